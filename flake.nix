@@ -91,6 +91,9 @@
           JAVA_HOME = "${pkgs.jdk17}";
           CARGO_TARGET_AARCH64_LINUX_ANDROID_LINKER = "${ANDROID_HOME}/ndk-bundle/toolchains/llvm/prebuilt/linux-x86_64/bin/aarch64-linux-android24-clang";
           CARGO_TARGET_AARCH64_LINUX_ANDROID_AR = "${ANDROID_HOME}/ndk-bundle/toolchains/llvm/prebuilt/linux-x86_64/bin/llvm-ar";
+          # C sources of ring (TLS) and zstd are built with the cc crate.
+          CC_aarch64_linux_android = "${ANDROID_HOME}/ndk-bundle/toolchains/llvm/prebuilt/linux-x86_64/bin/aarch64-linux-android24-clang";
+          AR_aarch64_linux_android = "${ANDROID_HOME}/ndk-bundle/toolchains/llvm/prebuilt/linux-x86_64/bin/llvm-ar";
           GODOT_ANDROID_KEYSTORE_DEBUG_USER = "android";
           GODOT_ANDROID_KEYSTORE_DEBUG_PASSWORD = "android";
           GODOT_ANDROID_KEYSTORE_DEBUG_PATH = "res://debug.keystore";
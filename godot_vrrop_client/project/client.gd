@@ -4,32 +4,100 @@ class_name Client
 var _client := VrropClient.new()
 
 signal images_received(images: ImagesMessage)
+signal map_delta_received(delta: MapDeltaMessage)
+signal pose_graph_received(graph: PoseGraphMessage)
 signal odometry_received(odometry: OdometryMessage)
 signal reset_command_sent()
+signal server_goodbye(reason: String, retry_after: float)
+signal connection_state_changed(state: String, message: String)
+signal map_reset(source: int, epoch: int)
+signal server_notice(text: String)
+signal camera_status(source: int, connected: bool, message: String)
+signal maps_listed(names: PackedStringArray, loaded: String)
+signal relocalized(map: String, source: int, epoch: int)
 signal stat_recording_changed()
 
 func _ready() -> void:
 	_client.images_received.connect(_on_images_received)
+	_client.map_delta_received.connect(_on_map_delta_received)
+	_client.pose_graph_received.connect(_on_pose_graph_received)
 	_client.odometry_received.connect(_on_odometry_received)
+	_client.server_goodbye.connect(_on_server_goodbye)
+	_client.connection_state_changed.connect(_on_connection_state_changed)
+	_client.map_reset.connect(_on_map_reset)
+	_client.server_notice.connect(_on_server_notice)
+	_client.camera_status.connect(_on_camera_status)
+	_client.maps_listed.connect(_on_maps_listed)
+	_client.relocalized.connect(_on_relocalized)
 	GlobalSettings.server_address.on_setting_changed.connect(_start)
 	GlobalSettings.server_port.on_setting_changed.connect(_start)
+	GlobalSettings.auth_token.on_setting_changed.connect(_start)
+	GlobalSettings.use_tls.on_setting_changed.connect(_start)
+	GlobalSettings.tls_pin.on_setting_changed.connect(_start)
+	GlobalSettings.sources.on_setting_changed.connect(_start)
 	_start()
 
 func _start() -> void:
 	var address = "%s:%d" % [GlobalSettings.server_address.get_value(), GlobalSettings.server_port.get_value()]
 	print(address)
-	_client.start(address)
+	_client.start(
+		address,
+		GlobalSettings.auth_token.get_value(),
+		GlobalSettings.use_tls.get_value(),
+		GlobalSettings.tls_pin.get_value(),
+		GlobalSettings.sources.get_value()
+	)
 
 func _on_images_received(images: ImagesMessage) -> void:
 	images_received.emit(images)
 
+func _on_map_delta_received(delta: MapDeltaMessage) -> void:
+	map_delta_received.emit(delta)
+
+func _on_pose_graph_received(graph: PoseGraphMessage) -> void:
+	pose_graph_received.emit(graph)
+
 func _on_odometry_received(odometry: OdometryMessage) -> void:
 	odometry_received.emit(odometry)
 
+func _on_server_goodbye(reason: String, retry_after: float) -> void:
+	server_goodbye.emit(reason, retry_after)
+
+func _on_connection_state_changed(state: String, message: String) -> void:
+	connection_state_changed.emit(state, message)
+
+func _on_map_reset(source: int, epoch: int) -> void:
+	map_reset.emit(source, epoch)
+
+func _on_server_notice(text: String) -> void:
+	server_notice.emit(text)
+
+func _on_camera_status(source: int, connected: bool, message: String) -> void:
+	camera_status.emit(source, connected, message)
+
+func _on_maps_listed(names: PackedStringArray, loaded: String) -> void:
+	maps_listed.emit(names, loaded)
+
+func _on_relocalized(map: String, source: int, epoch: int) -> void:
+	relocalized.emit(map, source, epoch)
+
+## Names of the server's cameras, indexed by source id.
+func sources() -> PackedStringArray:
+	return _client.sources()
+
 func send_reset_command() -> void:
 	_client.send_reset_command()
 	reset_command_sent.emit()
 
+func save_map(map_name: String) -> void:
+	_client.send_save_map_command(map_name)
+
+func load_map(map_name: String) -> void:
+	_client.send_load_map_command(map_name)
+
+func list_maps() -> void:
+	_client.send_list_maps_command()
+
 func start_stats_recording() -> void:
 	_client.start_recording()
 	stat_recording_changed.emit()
@@ -37,6 +105,9 @@ func start_stats_recording() -> void:
 func is_stat_recording() -> bool:
 	return _client.is_recording()
 
+func record_merge_time(images: ImagesMessage, secs: float) -> void:
+	_client.record_merge_time(images, secs)
+
 func end_stats_recording() -> void:
 	_client.end_recording()
 	stat_recording_changed.emit()
@@ -2,6 +2,8 @@ extends Node
 
 #@export var address := "127.0.0.1:23456"
 @export var address := "10.133.1.190:23456"
+## Pre-shared token of the control server, empty if it doesn't require one.
+@export var auth_token := ""
 
 var target_velocity := Vector2.ZERO
 var leg_length := 1.0
@@ -13,7 +15,7 @@ func _ready():
 
 func connect_to_server() -> void:
 	var client = VrropControlClient.new()
-	var err := client.connect_to_server(address)
+	var err := client.connect_to_server(address, auth_token)
 	if err == OK:
 		_client = client
 	else:
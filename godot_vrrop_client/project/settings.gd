@@ -3,9 +3,14 @@ class_name Settings
 
 var server_address := StringSetting.new("Server Address", "Client", "Address of the server to connect to", "127.0.0.1")
 var server_port := IntSetting.new("Server Port", "Client", "Port number of the server to connect to", 6677, 1, 65535)
+var auth_token := StringSetting.new("Auth Token", "Client", "Pre-shared token of the server, empty if it doesn't require one", "")
+var use_tls := BoolSetting.new("Use TLS", "Client", "Connect with wss:// instead of ws://", false)
+var tls_pin := StringSetting.new("TLS Certificate Pin", "Client", "SHA-256 fingerprint of the server certificate, empty to verify it against public CAs", "")
+var sources := StringSetting.new("Sources", "Client", "Comma-separated names of the cameras to receive, empty for all of them", "")
 
 var grid_size := FloatSetting.new("Grid Size", "Visualizer", "Grid size of the visualizer", 1.0)
 var show_grid := BoolSetting.new("Show Grid", "Visualizer", "Whether to display the grid or not", false)
+var follow_loop_closures := BoolSetting.new("Follow Loop Closures", "Visualizer", "Move the point cloud when SLAM corrects past poses, uses twice the memory", false)
 var view_type := MultiChoiceSetting.new("View Type", "Visualizer", "Type of the View", "Third Person", ["First Person", "Third Person"])
 
 const _PATH = "user://global_settings.gson"
@@ -13,8 +18,13 @@ const _PATH = "user://global_settings.gson"
 func _init():
 	add_setting(server_address)
 	add_setting(server_port)
+	add_setting(auth_token)
+	add_setting(use_tls)
+	add_setting(tls_pin)
+	add_setting(sources)
 	add_setting(grid_size)
 	add_setting(show_grid)
+	add_setting(follow_loop_closures)
 	add_setting(view_type)
 
 	if FileAccess.file_exists(_PATH):
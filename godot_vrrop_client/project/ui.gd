@@ -9,6 +9,8 @@ class_name VrropUi
 @onready var server_address_edit: LineEdit = %ServerAddressEdit
 @onready var server_port_edit: LineEdit = %ServerPortEdit
 @onready var record_stats_button: Button = %RecordStatsButton
+@onready var server_message_label: Label = %ServerMessageLabel
+@onready var connection_state_label: Label = %ConnectionStateLabel
 
 func _ready():
 	reset_button.pressed.connect(
@@ -52,6 +54,35 @@ func _ready():
 	)
 	GlobalClient.stat_recording_changed.connect(_on_stats_recording_changed)
 
+	GlobalClient.connection_state_changed.connect(
+		func(_state: String, message: String):
+			connection_state_label.text = message
+	)
+	GlobalClient.server_goodbye.connect(_on_server_goodbye)
+	GlobalClient.map_reset.connect(
+		func(source: int, epoch: int):
+			var message := "Map reset (epoch %d)" % epoch
+			var names := GlobalClient.sources()
+			if names.size() > 1 and source < names.size():
+				message = "%s: %s" % [names[source], message]
+			server_message_label.text = message
+	)
+	GlobalClient.server_notice.connect(
+		func(text: String):
+			server_message_label.text = text
+	)
+	GlobalClient.camera_status.connect(
+		func(source: int, _connected: bool, message: String):
+			var names := GlobalClient.sources()
+			if names.size() > 1 and source < names.size():
+				message = "%s: %s" % [names[source], message]
+			server_message_label.text = message
+	)
+	GlobalClient.relocalized.connect(
+		func(map: String, _source: int, _epoch: int):
+			server_message_label.text = "Relocalized in map %s" % map
+	)
+
 	_on_grid_size_changed()
 	_on_show_grid_changed()
 	_on_view_type_changed()
@@ -90,3 +121,9 @@ func _on_stats_recording_changed() -> void:
 		record_stats_button.text = "Stop Recording Stats"
 	else:
 		record_stats_button.text = "Start Recording Stats"
+
+func _on_server_goodbye(reason: String, retry_after: float) -> void:
+	if retry_after >= 0.0:
+		server_message_label.text = "Disconnected: %s (retrying in %d s)" % [reason, ceili(retry_after)]
+	else:
+		server_message_label.text = "Disconnected: %s" % reason
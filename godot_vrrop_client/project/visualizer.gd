@@ -5,54 +5,74 @@ class_name Visualizer
 @export var cloud_debug_material_modified: Material
 @export var show_grid := false:
 	set(value):
+		show_grid = value
 		_visualizer_lock.lock()
-		if _visualizer:
-			_visualizer.show_debug_mesh = value
+		for vis in _visualizers.values():
+			vis.show_debug_mesh = value
 		_visualizer_lock.unlock()
-	get:
-		var tmp: bool
-		_visualizer_lock.lock()
-		if _visualizer:
-			tmp = _visualizer.show_debug_mesh
-		else:
-			tmp = false
-		_visualizer_lock.unlock()
-		return tmp
 @export var grid_size := 1.0
+## Moves the point cloud along with loop closures, applied on the next reset.
+@export var follow_loop_closures := false
 @export var show_camera_marker := true:
 	set(value):
-		_camera_marker.visible = value
-	get:
-		return _camera_marker.visible
+		show_camera_marker = value
+		for marker in _camera_markers.values():
+			marker.visible = value
 
+## Marker of the first source, copied for the others.
 @onready var _camera_marker := $CameraMarker
-var _visualizer: PointCloudVisualizer
+## Point clouds by source id, each source has its own map.
+var _visualizers := {}
 var _visualizer_lock := Mutex.new()
+## Camera markers by source id.
+var _camera_markers := {}
 var _material := ShaderMaterial.new()
 const _shader := preload("res://point_cloud.gdshader")
 
-func _init_visualizer():
-	_visualizer_lock.lock()
-	if _visualizer != null:
-		_visualizer.queue_free()
-	_visualizer_lock.unlock()
-
+func _new_visualizer() -> PointCloudVisualizer:
 	var vis := PointCloudVisualizer.new()
 	vis.material = _material
 	vis.debug_mesh_material_normal = cloud_debug_material_normal
 	vis.debug_mesh_material_modified = cloud_debug_material_modified
 	vis.show_debug_mesh = show_grid
 	vis.grid_size = grid_size
+	vis.anchored = follow_loop_closures
 	vis.init()
-	add_child(vis)
+	add_child.call_deferred(vis)
+	return vis
+
+## Point cloud of `source`, created on its first message. Call with the lock held.
+func _visualizer(source: int) -> PointCloudVisualizer:
+	if not _visualizers.has(source):
+		_visualizers[source] = _new_visualizer()
+	return _visualizers[source]
 
+func _marker(source: int) -> Node3D:
+	if not _camera_markers.has(source):
+		var marker: Node3D = _camera_marker.duplicate()
+		marker.visible = show_camera_marker
+		add_child(marker)
+		_camera_markers[source] = marker
+	return _camera_markers[source]
+
+func _init_visualizers():
+	_visualizer_lock.lock()
+	for vis in _visualizers.values():
+		vis.queue_free()
+	_visualizers.clear()
+	_visualizer_lock.unlock()
+
+## Drops the point cloud of `source`, the other sources keep theirs.
+func _reset_visualizer(source: int) -> void:
 	_visualizer_lock.lock()
-	_visualizer = vis
+	if _visualizers.has(source):
+		_visualizers[source].queue_free()
+		_visualizers.erase(source)
 	_visualizer_lock.unlock()
 
 func _ready():
 	_material.shader = _shader
-	_init_visualizer()
+	_camera_markers[0] = _camera_marker
 
 func start(client: Client) -> void:
 	client.images_received.connect(
@@ -60,20 +80,34 @@ func start(client: Client) -> void:
 			WorkerThreadPool.add_task(
 				func():
 					_visualizer_lock.lock()
-					var time := _visualizer.add_image(image)
-					print(time)
+					var time := _visualizer(image.odometry().source()).add_image(image)
 					_visualizer_lock.unlock()
+					client.record_merge_time(image, time)
 			)
 	)
+	# Deltas only make sense in order, so they are not handed to the thread pool.
+	client.map_delta_received.connect(
+		func(delta: MapDeltaMessage):
+			_visualizer_lock.lock()
+			_visualizer(delta.source()).apply_map_delta(delta)
+			_visualizer_lock.unlock()
+	)
+	client.pose_graph_received.connect(
+		func(graph: PoseGraphMessage):
+			_visualizer_lock.lock()
+			_visualizer(graph.source()).apply_pose_graph(graph)
+			_visualizer_lock.unlock()
+	)
 	client.odometry_received.connect(
 		func(odom: OdometryMessage):
-			_camera_marker.position = odom.translation()
-			_camera_marker.quaternion = odom.rotation()
+			var marker := _marker(odom.source())
+			marker.position = odom.translation()
+			marker.quaternion = odom.rotation()
 	)
-	client.reset_command_sent.connect(
-		func():
-			self.reset()
+	client.map_reset.connect(
+		func(source: int, _epoch: int):
+			_reset_visualizer(source)
 	)
 
 func reset() -> void:
-	_init_visualizer()
+	_init_visualizers()
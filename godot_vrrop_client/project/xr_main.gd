@@ -20,9 +20,11 @@ func _ready():
 		print("OpenXR not initialized, please check if your headset is connected")
 
 	GlobalSettings.grid_size.on_setting_changed.connect(_on_grid_size_changed)
+	GlobalSettings.follow_loop_closures.on_setting_changed.connect(_on_follow_loop_closures_changed)
 	GlobalSettings.view_type.on_setting_changed.connect(_on_view_type_changed)
 	
 	_on_grid_size_changed()
+	_on_follow_loop_closures_changed()
 	_on_view_type_changed()
 
 	_enable_passthrough(true)
@@ -32,6 +34,9 @@ func _ready():
 func _on_grid_size_changed() -> void:
 	visualizer.grid_size = GlobalSettings.grid_size.get_value()
 
+func _on_follow_loop_closures_changed() -> void:
+	visualizer.follow_loop_closures = GlobalSettings.follow_loop_closures.get_value()
+
 func _on_view_type_changed() -> void:
 	match GlobalSettings.view_type.get_value():
 		"First Person":
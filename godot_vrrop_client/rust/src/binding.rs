@@ -22,15 +22,90 @@ impl VrropClient {
     #[signal]
     fn images_received(&self, images: Gd<ImagesMessage>);
 
+    #[signal]
+    fn map_delta_received(&self, delta: Gd<MapDeltaMessage>);
+
+    #[signal]
+    fn pose_graph_received(&self, graph: Gd<PoseGraphMessage>);
+
+    /// `retry_after` is in seconds, negative if the server didn't suggest one.
+    #[signal]
+    fn server_goodbye(&self, reason: GString, retry_after: f64);
+
+    #[signal]
+    fn map_reset(&self, source: i64, epoch: i64);
+
+    #[signal]
+    fn server_notice(&self, text: GString);
+
+    /// `state` is one of `connecting`, `connected`, `disconnected` and
+    /// `reconnecting`, `message` describes it for display.
+    #[signal]
+    fn connection_state_changed(&self, state: GString, message: GString);
+
+    #[signal]
+    fn camera_status(&self, source: i64, connected: bool, message: GString);
+
+    /// `loaded` is empty if SLAM isn't localizing in a saved map.
+    #[signal]
+    fn maps_listed(&self, names: PackedStringArray, loaded: GString);
+
+    #[signal]
+    fn relocalized(&self, map: GString, source: i64, epoch: i64);
+
+    /// `auth_token` is empty if the server doesn't require one. With `use_tls`
+    /// the server certificate must match `tls_pin`, or a public CA if it's empty.
+    /// `sources` are comma-separated camera names, empty to receive all.
     #[func(gd_self)]
-    fn start(mut this: Gd<Self>, address: String) {
+    fn start(
+        mut this: Gd<Self>,
+        address: String,
+        auth_token: String,
+        use_tls: bool,
+        tls_pin: String,
+        sources: String,
+    ) {
+        let pins = match tls_pin.split_whitespace().map(str::parse).collect() {
+            Ok(pins) => pins,
+            Err(e) => {
+                godot_print!("Invalid TLS certificate pin: {:?}", e);
+                return;
+            }
+        };
+        let auth = match auth_key(&auth_token) {
+            Ok(auth) => auth,
+            Err(e) => {
+                godot_print!("Invalid auth token: {:?}", e);
+                return;
+            }
+        };
+        let tls = use_tls.then(|| vrrop_client::TlsConfig {
+            server_name: None,
+            pins,
+        });
+        let options = vrrop_client::ClientOptions {
+            auth,
+            tls,
+            sources: sources
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            ..Default::default()
+        };
         let weak1: SharedGd<WeakRef> = SharedGd(weakref(this.to_variant()).to());
         let weak2 = SharedGd(weak1.clone());
+        let weak3 = SharedGd(weak1.clone());
+        let weak4 = SharedGd(weak1.clone());
+        let weak5 = SharedGd(weak1.clone());
+        let weak6 = SharedGd(weak1.clone());
 
         let _enter = TOKIO_RUNTIME.get().unwrap().enter();
         let server = tokio::runtime::Handle::current()
-            .block_on(vrrop_client::Client::new(
+            .block_on(vrrop_client::Client::with_options(
                 address,
+                options,
                 vrrop_client::Callbacks::new(
                     move |odometry| {
                         // godot_print!("Odometry: {:?}", odometry);
@@ -50,7 +125,96 @@ impl VrropClient {
                             &["images_received".to_variant(), images.to_variant()],
                         );
                     },
-                ),
+                )
+                .with_map_delta(move |delta| {
+                    let delta = MapDeltaMessage::new_gd(delta);
+                    let mut strong: Gd<VrropClient> = weak3.get_ref().to();
+                    strong.call_deferred(
+                        "emit_signal".into(),
+                        &["map_delta_received".to_variant(), delta.to_variant()],
+                    );
+                })
+                .with_pose_graph(move |graph| {
+                    let graph = PoseGraphMessage::new_gd(graph);
+                    let mut strong: Gd<VrropClient> = weak5.get_ref().to();
+                    strong.call_deferred(
+                        "emit_signal".into(),
+                        &["pose_graph_received".to_variant(), graph.to_variant()],
+                    );
+                })
+                .with_server_message(move |msg| {
+                    let args = match msg {
+                        vrrop_client::ServerMessage::Goodbye {
+                            reason,
+                            retry_after,
+                        } => vec![
+                            "server_goodbye".to_variant(),
+                            reason.to_variant(),
+                            retry_after.map_or(-1.0, |d| d.as_secs_f64()).to_variant(),
+                        ],
+                        vrrop_client::ServerMessage::MapReset { source, epoch } => vec![
+                            "map_reset".to_variant(),
+                            (source as i64).to_variant(),
+                            (epoch as i64).to_variant(),
+                        ],
+                        vrrop_client::ServerMessage::Notice { text } => {
+                            vec!["server_notice".to_variant(), text.to_variant()]
+                        }
+                        vrrop_client::ServerMessage::CameraStatus {
+                            source,
+                            connected,
+                            message,
+                        } => vec![
+                            "camera_status".to_variant(),
+                            (source as i64).to_variant(),
+                            connected.to_variant(),
+                            message.to_variant(),
+                        ],
+                        vrrop_client::ServerMessage::Maps { maps, loaded } => {
+                            let mut names = PackedStringArray::new();
+                            for map in &maps {
+                                names.push(GString::from(map.name.as_str()));
+                            }
+                            vec![
+                                "maps_listed".to_variant(),
+                                names.to_variant(),
+                                loaded.unwrap_or_default().to_variant(),
+                            ]
+                        }
+                        vrrop_client::ServerMessage::Relocalized { map, source, epoch } => vec![
+                            "relocalized".to_variant(),
+                            map.to_variant(),
+                            (source as i64).to_variant(),
+                            (epoch as i64).to_variant(),
+                        ],
+                    };
+                    let mut strong: Gd<VrropClient> = weak4.get_ref().to();
+                    strong.call_deferred("emit_signal".into(), &args);
+                })
+                .with_connection_state(move |state| {
+                    use vrrop_client::ConnectionState;
+                    let (state, message) = match state {
+                        ConnectionState::Connecting => ("connecting", "Connecting".to_owned()),
+                        ConnectionState::Connected(info) => (
+                            "connected",
+                            format!("Connected to {} (session {})", info.url, info.session_id),
+                        ),
+                        ConnectionState::Disconnected { reason } => ("disconnected", reason),
+                        ConnectionState::Reconnecting { reason, delay } => (
+                            "reconnecting",
+                            format!("{reason}, reconnecting in {:.0} s", delay.as_secs_f64()),
+                        ),
+                    };
+                    let mut strong: Gd<VrropClient> = weak6.get_ref().to();
+                    strong.call_deferred(
+                        "emit_signal".into(),
+                        &[
+                            "connection_state_changed".to_variant(),
+                            state.to_variant(),
+                            message.to_variant(),
+                        ],
+                    );
+                }),
             ))
             .unwrap();
         this.bind_mut().inner = Some(server);
@@ -62,6 +226,36 @@ impl VrropClient {
         client.send_command(vrrop_common::Command::Reset);
     }
 
+    #[func]
+    fn send_save_map_command(&self, name: String) {
+        let client = self.inner.as_ref().unwrap();
+        client.send_command(vrrop_common::Command::SaveMap(name));
+    }
+
+    #[func]
+    fn send_load_map_command(&self, name: String) {
+        let client = self.inner.as_ref().unwrap();
+        client.send_command(vrrop_common::Command::LoadMap(name));
+    }
+
+    #[func]
+    fn send_list_maps_command(&self) {
+        let client = self.inner.as_ref().unwrap();
+        client.send_command(vrrop_common::Command::ListMaps);
+    }
+
+    /// Names of the server's cameras, indexed by source id.
+    #[func]
+    fn sources(&self) -> PackedStringArray {
+        let mut names = PackedStringArray::new();
+        if let Some(client) = &self.inner {
+            for name in client.sources() {
+                names.push(GString::from(name.as_str()));
+            }
+        }
+        names
+    }
+
     #[func]
     fn start_recording(&self) {
         let client = self.inner.as_ref().unwrap();
@@ -78,6 +272,26 @@ impl VrropClient {
     fn is_recording(&self) -> bool {
         self.inner.as_ref().unwrap().is_recording()
     }
+
+    /// Adds the `secs` it took to merge `images` into the point cloud to the
+    /// statistics.
+    #[func]
+    fn record_merge_time(&self, images: Gd<ImagesMessage>, secs: f64) {
+        let Some(stamp) = images.bind().inner.as_ref().map(|i| i.odometry.stamp) else {
+            return;
+        };
+        self.inner
+            .as_ref()
+            .unwrap()
+            .record_merge(stamp, std::time::Duration::from_secs_f64(secs.max(0.0)));
+    }
+}
+
+fn auth_key(token: &str) -> anyhow::Result<Option<vrrop_common::auth::AuthKey>> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+    vrrop_common::auth::AuthKey::from_token(token).map(Some)
 }
 
 #[derive(GodotClass)]
@@ -109,6 +323,101 @@ impl ImagesMessage {
     }
 }
 
+#[derive(GodotClass)]
+#[class(init, base=RefCounted)]
+pub struct MapDeltaMessage {
+    base: Base<RefCounted>,
+    pub inner: Option<vrrop_client::MapDeltaMessage>,
+}
+
+impl MapDeltaMessage {
+    fn new_gd(inner: vrrop_client::MapDeltaMessage) -> Gd<Self> {
+        Gd::from_init_fn(|base| Self {
+            base,
+            inner: Some(inner),
+        })
+    }
+}
+
+#[godot_api]
+impl MapDeltaMessage {
+    #[func]
+    fn is_snapshot(&self) -> bool {
+        self.inner.as_ref().unwrap().snapshot
+    }
+
+    #[func]
+    fn epoch(&self) -> i64 {
+        self.inner.as_ref().unwrap().epoch as _
+    }
+
+    #[func]
+    fn source(&self) -> i64 {
+        self.inner.as_ref().unwrap().source as _
+    }
+}
+
+/// Loop closure corrections. Corrections are in the frame of odometry
+/// messages and move poses received around the node's stamp.
+#[derive(GodotClass)]
+#[class(init, base=RefCounted)]
+pub struct PoseGraphMessage {
+    base: Base<RefCounted>,
+    pub inner: Option<vrrop_client::PoseGraphMessage>,
+}
+
+impl PoseGraphMessage {
+    fn new_gd(inner: vrrop_client::PoseGraphMessage) -> Gd<Self> {
+        Gd::from_init_fn(|base| Self {
+            base,
+            inner: Some(inner),
+        })
+    }
+
+    fn node(&self, index: i64) -> &vrrop_client::GraphNode {
+        &self.inner.as_ref().unwrap().nodes[index as usize]
+    }
+}
+
+#[godot_api]
+impl PoseGraphMessage {
+    #[func]
+    fn epoch(&self) -> i64 {
+        self.inner.as_ref().unwrap().epoch as _
+    }
+
+    #[func]
+    fn source(&self) -> i64 {
+        self.inner.as_ref().unwrap().source as _
+    }
+
+    #[func]
+    fn node_count(&self) -> i64 {
+        self.inner.as_ref().unwrap().nodes.len() as _
+    }
+
+    #[func]
+    fn node_stamp(&self, index: i64) -> f64 {
+        self.node(index)
+            .stamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+    }
+
+    #[func]
+    fn correction_translation(&self, index: i64) -> Vector3 {
+        let t = &self.node(index).correction.translation;
+        Vector3::new(t.x, t.y, t.z)
+    }
+
+    #[func]
+    fn correction_rotation(&self, index: i64) -> Quaternion {
+        let v = self.node(index).correction.rotation.as_vector();
+        Quaternion::new(v.x, v.y, v.z, v.w)
+    }
+}
+
 #[derive(GodotClass)]
 #[class(init, base=RefCounted)]
 pub struct OdometryMessage {
@@ -152,6 +461,16 @@ impl OdometryMessage {
     fn original_size(&self) -> i64 {
         self.inner.as_ref().unwrap().original_size as _
     }
+
+    #[func]
+    fn epoch(&self) -> i64 {
+        self.inner.as_ref().unwrap().epoch as _
+    }
+
+    #[func]
+    fn source(&self) -> i64 {
+        self.inner.as_ref().unwrap().source as _
+    }
 }
 
 impl OdometryMessage {
@@ -172,11 +491,19 @@ pub struct VrropControlClient {
 
 #[godot_api]
 impl VrropControlClient {
+    /// `auth_token` is empty if the server doesn't require one.
     #[func]
-    fn connect_to_server(&mut self, address: String) -> godot::global::Error {
+    fn connect_to_server(&mut self, address: String, auth_token: String) -> godot::global::Error {
+        let auth = match auth_key(&auth_token) {
+            Ok(auth) => auth,
+            Err(e) => {
+                godot_print!("Invalid auth token: {:?}", e);
+                return godot::global::Error::ERR_INVALID_PARAMETER;
+            }
+        };
         let _enter = TOKIO_RUNTIME.get().unwrap().enter();
-        let client_result =
-            tokio::runtime::Handle::current().block_on(vrrop_control_client::Client::new(&address));
+        let client_result = tokio::runtime::Handle::current()
+            .block_on(vrrop_control_client::Client::new(&address, auth));
         match client_result {
             Ok(client) => {
                 self.inner = Some(Arc::new(client));
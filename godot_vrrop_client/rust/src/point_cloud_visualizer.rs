@@ -5,7 +5,7 @@ use godot::engine::Material;
 use godot::prelude::*;
 use vrrop_client::{GridIndex, PointCloud};
 
-use crate::binding::ImagesMessage;
+use crate::binding::{ImagesMessage, MapDeltaMessage, PoseGraphMessage};
 
 #[derive(GodotClass)]
 #[class(base=Node3D)]
@@ -19,6 +19,10 @@ struct PointCloudVisualizer {
     show_debug_mesh: bool,
     #[export]
     grid_size: f32,
+    /// Keeps points relative to their keyframe so loop closures can move them.
+    /// Takes effect on `init`.
+    #[export]
+    anchored: bool,
     #[export]
     material: Option<Gd<Material>>,
 
@@ -134,39 +138,48 @@ impl PointCloudVisualizer {
             return 0.0;
         };
         let (modified_grids, time) = self.cloud.merge_images_msg(image);
-        for grid_index in modified_grids.iter().copied() {
-            if let Some(mesh) = create_mesh(grid_index, &self.cloud, material.clone()) {
-                if let Some(mesh_inst) = self.meshes.get_mut(&grid_index) {
-                    mesh_inst.set_deferred("mesh".into(), mesh.to_variant());
-                } else {
-                    let mut mesh_inst = MeshInstance3D::new_alloc();
-                    mesh_inst.set_deferred("mesh".into(), mesh.to_variant());
-                    self.base_mut()
-                        .call_deferred("add_child".into(), &[mesh_inst.to_variant()]);
-                    self.meshes.insert(grid_index, mesh_inst.clone());
-                }
-            }
-        }
-
-        if self.show_debug_mesh {
-            if let (Some(normal), Some(modified)) = (
-                self.debug_mesh_material_normal.clone(),
-                self.debug_mesh_material_modified.clone(),
-            ) {
-                if let Some(mesh) =
-                    create_debug_mesh(&self.cloud, &modified_grids, normal, modified)
-                {
-                    self.debug_mesh_inst
-                        .set_deferred("mesh".into(), mesh.to_variant());
-                }
-            }
-        }
+        self.update_meshes(&modified_grids, material);
         time.as_secs_f64()
     }
 
+    /// Applies a map delta from a server running in fusion mode.
+    #[func]
+    fn apply_map_delta(&mut self, delta: Gd<MapDeltaMessage>) {
+        let Some(material) = self.material.clone() else {
+            return;
+        };
+        let delta = delta.bind();
+        let Some(delta) = delta.inner.as_ref() else {
+            return;
+        };
+        let modified_grids = self.cloud.apply_map_delta(delta);
+        self.update_meshes(&modified_grids, material);
+    }
+
+    /// Moves the points of anchored keyframes after a loop closure. Returns the
+    /// time it took in seconds.
+    #[func]
+    fn apply_pose_graph(&mut self, graph: Gd<PoseGraphMessage>) -> f64 {
+        let Some(material) = self.material.clone() else {
+            return 0.0;
+        };
+        let graph = graph.bind();
+        let Some(graph) = graph.inner.as_ref() else {
+            return 0.0;
+        };
+        let start = std::time::Instant::now();
+        let modified_grids = self.cloud.apply_pose_graph(graph).modified_grids();
+        self.update_meshes(&modified_grids, material);
+        start.elapsed().as_secs_f64()
+    }
+
     #[func]
     fn init(&mut self) {
-        self.cloud = PointCloud::new(self.grid_size);
+        self.cloud = if self.anchored {
+            PointCloud::anchored(self.grid_size)
+        } else {
+            PointCloud::new(self.grid_size)
+        };
         for child in self.base().get_children().iter_shared() {
             Gd::free(child);
         }
@@ -199,6 +212,40 @@ impl PointCloudVisualizer {
     }
 }
 
+impl PointCloudVisualizer {
+    fn update_meshes(&mut self, modified_grids: &FxHashSet<GridIndex>, material: Gd<Material>) {
+        for grid_index in modified_grids.iter().copied() {
+            if let Some(mesh) = create_mesh(grid_index, &self.cloud, material.clone()) {
+                if let Some(mesh_inst) = self.meshes.get_mut(&grid_index) {
+                    mesh_inst.set_deferred("mesh".into(), mesh.to_variant());
+                } else {
+                    let mut mesh_inst = MeshInstance3D::new_alloc();
+                    mesh_inst.set_deferred("mesh".into(), mesh.to_variant());
+                    self.base_mut()
+                        .call_deferred("add_child".into(), &[mesh_inst.to_variant()]);
+                    self.meshes.insert(grid_index, mesh_inst.clone());
+                }
+            } else if let Some(mut mesh_inst) = self.meshes.remove(&grid_index) {
+                // The grid lost all of its points.
+                mesh_inst.call_deferred("queue_free".into(), &[]);
+            }
+        }
+
+        if self.show_debug_mesh {
+            if let (Some(normal), Some(modified)) = (
+                self.debug_mesh_material_normal.clone(),
+                self.debug_mesh_material_modified.clone(),
+            ) {
+                if let Some(mesh) = create_debug_mesh(&self.cloud, modified_grids, normal, modified)
+                {
+                    self.debug_mesh_inst
+                        .set_deferred("mesh".into(), mesh.to_variant());
+                }
+            }
+        }
+    }
+}
+
 #[godot_api]
 impl INode3D for PointCloudVisualizer {
     fn init(base: Base<Node3D>) -> Self {
@@ -212,6 +259,7 @@ impl INode3D for PointCloudVisualizer {
             cloud: vrrop_client::PointCloud::new(DEFAULT_GRID_SIZE),
             meshes: FxHashMap::default(),
             grid_size: DEFAULT_GRID_SIZE,
+            anchored: false,
             material: None,
         }
     }
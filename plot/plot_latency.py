@@ -1,3 +1,5 @@
+import os
+import sys
 import pandas as pd
 import matplotlib.pyplot as plt
 import numpy as np
@@ -8,8 +10,10 @@ rcParams["font.family"] = "Noto Sans CJK JP"  # 環境に応じてフォント
 rcParams["axes.unicode_minus"] = False  # マイナス記号が文字化けしないように設定
 
 # CSVファイルの読み込み
-odometry_file = "stats/odometry.csv"
-images_file = "stats/images.csv"
+# サーバが保存したセッションのディレクトリ (例: stats/20240229-123456_session1)
+stats_dir = sys.argv[1]
+odometry_file = os.path.join(stats_dir, "odometry.csv")
+images_file = os.path.join(stats_dir, "images.csv")
 
 odometry_data = pd.read_csv(odometry_file)
 images_data = pd.read_csv(images_file)
@@ -1,20 +1,176 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use clap::Parser;
-use futures::pin_mut;
-use tokio::{select, time::sleep_until};
-use vrrop_common::bag;
+use futures::{pin_mut, StreamExt};
+use tokio::{
+    select,
+    time::{sleep, sleep_until},
+};
+use vrrop_client::PointCloud;
+use vrrop_common::{
+    bag,
+    codec::{self, ColorCodec, DepthCodec},
+    stats::image_stages,
+};
 
 #[derive(clap::Parser)]
 struct Args {
     #[clap(short, long, default_value = "bag")]
     bag: PathBuf,
+    /// Re-encode every image in the bag with each codec and report size and timing
+    #[clap(long)]
+    codecs: bool,
+    #[clap(long, default_value_t = 70)]
+    jpeg_quality: u8,
+    #[clap(long, default_value_t = 5)]
+    depth_max_error_mm: u16,
+    /// Receive images from a server instead and report where their latency comes from
+    #[clap(long)]
+    connect: Option<String>,
+    /// How long to receive images with --connect, in seconds
+    #[clap(long, default_value_t = 30)]
+    duration: u64,
+    /// Grid size of the point cloud images are merged into with --connect
+    #[clap(long, default_value_t = 1.0)]
+    grid_size: f32,
+}
+
+#[derive(Default)]
+struct CodecResult {
+    frames: usize,
+    bytes: usize,
+    encode_time: Duration,
+    decode_time: Duration,
+}
+
+impl CodecResult {
+    fn print(&self, name: &str) {
+        let n = self.frames.max(1) as f64;
+        println!(
+            "{:<16}{:>12.1}{:>12.2}{:>12.2}",
+            name,
+            self.bytes as f64 / n / 1e3,
+            self.encode_time.as_secs_f64() / n * 1e3,
+            self.decode_time.as_secs_f64() / n * 1e3,
+        );
+    }
+}
+
+fn bench_codecs(bag_dir: &Path, jpeg_quality: u8, depth_max_error_mm: u16) -> Result<()> {
+    let mut player = bag::Player::new(bag_dir)?;
+    let mut color_results: Vec<CodecResult> =
+        ColorCodec::ALL.iter().map(|_| Default::default()).collect();
+    let mut depth_results: Vec<CodecResult> =
+        DepthCodec::ALL.iter().map(|_| Default::default()).collect();
+    while let Some(event) = player.next_event()? {
+        let bag::Event::Images(msg) = event else {
+            continue;
+        };
+        let color = codec::decode_color(msg.color_codec, &msg.color_image)?;
+        let depth = codec::decode_depth(msg.depth_codec, &msg.depth_image)?;
+        for (&c, result) in ColorCodec::ALL.iter().zip(&mut color_results) {
+            let start = Instant::now();
+            let encoded =
+                codec::encode_color(c, &color, color.width(), color.height(), jpeg_quality)?;
+            result.encode_time += start.elapsed();
+            let start = Instant::now();
+            codec::decode_color(c, &encoded)?;
+            result.decode_time += start.elapsed();
+            result.bytes += encoded.len();
+            result.frames += 1;
+        }
+        for (&c, result) in DepthCodec::ALL.iter().zip(&mut depth_results) {
+            let start = Instant::now();
+            let encoded =
+                codec::encode_depth(c, &depth, depth.width(), depth.height(), depth_max_error_mm)?;
+            result.encode_time += start.elapsed();
+            let start = Instant::now();
+            codec::decode_depth(c, &encoded)?;
+            result.decode_time += start.elapsed();
+            result.bytes += encoded.len();
+            result.frames += 1;
+        }
+    }
+    println!(
+        "{:<16}{:>12}{:>12}{:>12}",
+        "codec", "size [kB]", "enc [ms]", "dec [ms]"
+    );
+    for (c, result) in ColorCodec::ALL.iter().zip(&color_results) {
+        result.print(&format!("color/{c}"));
+    }
+    for (c, result) in DepthCodec::ALL.iter().zip(&depth_results) {
+        result.print(&format!("depth/{c}"));
+    }
+    Ok(())
+}
+
+/// Merges the images a server sends into a point cloud like the client does,
+/// then reports how long they spent in each stage of the pipeline.
+async fn bench_pipeline(address: &str, duration: Duration, grid_size: f32) -> Result<()> {
+    let options = vrrop_client::ClientOptions {
+        auth: std::env::var("VRROP_AUTH_TOKEN")
+            .ok()
+            .map(|token| vrrop_client::AuthKey::from_token(&token))
+            .transpose()?,
+        ..Default::default()
+    };
+    let (client, mut events) = vrrop_client::Client::with_events(
+        address.to_owned(),
+        options,
+        vrrop_client::Buffering::Unbounded,
+    )
+    .await?;
+    let mut cloud = PointCloud::new(grid_size);
+    let mut stages = Vec::new();
+    let end = sleep(duration);
+    let ctrl_c = tokio::signal::ctrl_c();
+    pin_mut!(end, ctrl_c);
+    loop {
+        select! {
+            Some(event) = events.next() => {
+                let vrrop_client::ClientEvent::Images(images) = event else {
+                    continue;
+                };
+                let (_, merge) = cloud.merge_images_msg(&images);
+                let mut image_stages = images.stages;
+                image_stages.merge = Some(merge.as_nanos() as i64);
+                stages.push(image_stages);
+            }
+            _ = &mut end => break,
+            _ = &mut ctrl_c => break,
+        }
+    }
+    client.shutdown().await;
+    println!("{} images", stages.len());
+    println!(
+        "{:<12}{:>8}{:>12}{:>12}{:>12}{:>12}",
+        "stage", "count", "mean [ms]", "p50 [ms]", "p95 [ms]", "p99 [ms]"
+    );
+    for stage in image_stages(&stages) {
+        match stage.ms {
+            Some(ms) => println!(
+                "{:<12}{:>8}{:>12.2}{:>12.2}{:>12.2}{:>12.2}",
+                stage.stage, ms.count, ms.mean, ms.p50, ms.p95, ms.p99
+            ),
+            None => println!("{:<12}{:>8}", stage.stage, 0),
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    if args.codecs {
+        return bench_codecs(&args.bag, args.jpeg_quality, args.depth_max_error_mm);
+    }
+    if let Some(address) = &args.connect {
+        return bench_pipeline(address, Duration::from_secs(args.duration), args.grid_size).await;
+    }
     let mut player = bag::Player::new(&args.bag)?;
     let ctrl_c = tokio::signal::ctrl_c();
     pin_mut!(ctrl_c);
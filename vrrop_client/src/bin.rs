@@ -1,25 +1,57 @@
 use anyhow::Result;
+use futures::StreamExt;
+use vrrop_client::{Buffering, ClientEvent};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let _client = vrrop_client::Client::new(
-        "127.0.0.1:6677",
-        vrrop_client::Callbacks::new(
-            |msg| {
-                println!("odometry received");
+    let options = vrrop_client::ClientOptions {
+        auth: std::env::var("VRROP_AUTH_TOKEN")
+            .ok()
+            .map(|token| vrrop_client::AuthKey::from_token(&token))
+            .transpose()?,
+        tls: std::env::var("VRROP_TLS_PIN")
+            .ok()
+            .map(|pin| anyhow::Ok(vrrop_client::TlsConfig::pinned(pin.parse()?)))
+            .transpose()?,
+        // Comma-separated camera names, all cameras if unset.
+        sources: std::env::var("VRROP_SOURCES")
+            .map(|sources| sources.split(',').map(str::to_owned).collect())
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+    // Only the latest images matter when printing falls behind.
+    let (client, mut events) =
+        vrrop_client::Client::with_events("127.0.0.1:6677", options, Buffering::Latest(1)).await?;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let event = tokio::select! {
+            Some(event) = events.next() => event,
+            res = &mut ctrl_c => break res?,
+        };
+        match event {
+            ClientEvent::Odometry(msg) => {
+                println!("odometry received from source {}", msg.source);
                 println!("translation: {}", msg.translation);
                 println!("rotation: {}", msg.rotation);
-            },
-            |images| {
+            }
+            ClientEvent::Images(images) => {
                 println!("images received");
                 println!("color image size: {}", images.color.len());
                 println!("depth image size: {}", images.depth.len());
                 println!("color intrinsics: {:?}", images.color_intrinsics);
                 println!("depth intrinsics: {:?}", images.depth_intrinsics);
-            },
-        ),
-    )
-    .await?;
-    tokio::signal::ctrl_c().await?;
+            }
+            ClientEvent::MapDelta(delta) => {
+                println!("map delta: {} cells", delta.cells.len());
+            }
+            ClientEvent::PoseGraph(graph) => {
+                println!("pose graph updated: {} nodes", graph.nodes.len());
+            }
+            ClientEvent::Server(msg) => println!("server message: {msg:?}"),
+            ClientEvent::ConnectionState(state) => println!("connection: {state:?}"),
+        }
+    }
+    client.shutdown().await;
     Ok(())
 }
@@ -0,0 +1,214 @@
+//! Estimates the server clock from ping round trips, like NTP does.
+//!
+//! A pong that was delayed on its way says little about the offset, so of the
+//! last [`FILTER_LEN`] pings only the one with the smallest round trip is kept,
+//! at most one per [`PICK_INTERVAL`]. A line fitted through the kept ones gives
+//! the offset and how fast it drifts.
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Pings the one with the smallest round trip is picked from.
+const FILTER_LEN: usize = 8;
+/// Picked pings closer than this replace each other.
+const PICK_INTERVAL: Duration = Duration::from_secs(1);
+/// Picked pings the drift is fitted over, about a minute.
+const DRIFT_WINDOW: usize = 64;
+/// Shorter spans don't say much about drift.
+const MIN_DRIFT_SPAN: Duration = Duration::from_secs(10);
+/// Real clocks drift much less, more means the clock was stepped.
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+/// The server clock as seen from the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// Client time the estimate is for.
+    pub reference: SystemTime,
+    /// Server clock minus client clock at `reference`, in nanoseconds.
+    pub offset_ns: i64,
+    /// How fast the offset grows, in microseconds per second.
+    pub drift_ppm: f64,
+    /// The offset is likely off by no more than this, in nanoseconds.
+    pub uncertainty_ns: i64,
+    /// Round trip time of the best recent ping, in nanoseconds.
+    pub rtt_ns: i64,
+}
+
+impl ClockEstimate {
+    /// Server clock minus client clock at client time `local`.
+    pub fn offset_at(&self, local: SystemTime) -> i64 {
+        let elapsed_ns = nanos(local) - nanos(self.reference);
+        self.offset_ns + (elapsed_ns as f64 * self.drift_ppm * 1e-6) as i64
+    }
+
+    pub fn to_server_time(&self, local: SystemTime) -> SystemTime {
+        shift(local, self.offset_at(local))
+    }
+
+    pub fn to_local_time(&self, server: SystemTime) -> SystemTime {
+        // The offset depends on the local time sought, one refinement of the
+        // guess leaves an error of the drift squared.
+        let guess = shift(server, -self.offset_at(server));
+        shift(server, -self.offset_at(guess))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    /// Client time halfway through the round trip, in nanoseconds.
+    local_ns: i64,
+    offset_ns: i64,
+    rtt_ns: i64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ClockSync {
+    recent: VecDeque<Sample>,
+    picked: VecDeque<Sample>,
+    estimate: Option<ClockEstimate>,
+}
+
+impl ClockSync {
+    /// Adds the round trip of a ping sent at `sent`, answered at `server` and
+    /// received at `received`, and returns the new estimate.
+    pub fn add(
+        &mut self,
+        sent: SystemTime,
+        server: SystemTime,
+        received: SystemTime,
+    ) -> Option<ClockEstimate> {
+        let (sent, received) = (nanos(sent), nanos(received));
+        let rtt_ns = received - sent;
+        if rtt_ns < 0 {
+            return self.estimate;
+        }
+        let local_ns = sent + rtt_ns / 2;
+        self.recent.push_back(Sample {
+            local_ns,
+            offset_ns: nanos(server) - local_ns,
+            rtt_ns,
+        });
+        if self.recent.len() > FILTER_LEN {
+            self.recent.pop_front();
+        }
+        let best = *self.recent.iter().min_by_key(|s| s.rtt_ns).unwrap();
+        match self.picked.back_mut() {
+            Some(last) if best.local_ns - last.local_ns < PICK_INTERVAL.as_nanos() as i64 => {
+                if best.rtt_ns < last.rtt_ns {
+                    *last = best;
+                }
+            }
+            _ => {
+                self.picked.push_back(best);
+                if self.picked.len() > DRIFT_WINDOW {
+                    self.picked.pop_front();
+                }
+            }
+        }
+        self.estimate = Some(self.fit(best));
+        self.estimate
+    }
+
+    /// Least squares line through the picked samples, or just the best recent
+    /// one while they span too little time.
+    fn fit(&self, best: Sample) -> ClockEstimate {
+        let reference = best.local_ns;
+        let first = self.picked.front().unwrap();
+        let mut drift = 0.0;
+        let mut offset = best.offset_ns as f64;
+        let mut residual_ns = 0.0;
+        if Duration::from_nanos((reference - first.local_ns).max(0) as u64) >= MIN_DRIFT_SPAN {
+            let n = self.picked.len() as f64;
+            let xs = || self.picked.iter().map(|s| (s.local_ns - reference) as f64);
+            let ys = || self.picked.iter().map(|s| s.offset_ns as f64);
+            let mean_x = xs().sum::<f64>() / n;
+            let mean_y = ys().sum::<f64>() / n;
+            let sxx: f64 = xs().map(|x| (x - mean_x).powi(2)).sum();
+            let sxy: f64 = xs()
+                .zip(ys())
+                .map(|(x, y)| (x - mean_x) * (y - mean_y))
+                .sum();
+            let slope = sxy / sxx;
+            if slope.abs() * 1e6 <= MAX_DRIFT_PPM {
+                drift = slope;
+                offset = mean_y - slope * mean_x;
+                residual_ns = (xs()
+                    .zip(ys())
+                    .map(|(x, y)| (y - (offset + slope * x)).powi(2))
+                    .sum::<f64>()
+                    / n)
+                    .sqrt();
+            }
+        }
+        ClockEstimate {
+            reference: UNIX_EPOCH + Duration::from_nanos(reference.max(0) as u64),
+            offset_ns: offset as i64,
+            drift_ppm: drift * 1e6,
+            // The pong may have taken anywhere from none to all of the round trip.
+            uncertainty_ns: best.rtt_ns / 2 + residual_ns as i64,
+            rtt_ns: best.rtt_ns,
+        }
+    }
+
+    /// Starts over with the next ping, e.g. with a new connection where the
+    /// server may have restarted on another machine. Until then the last
+    /// estimate is kept.
+    pub fn restart(&mut self) {
+        self.recent.clear();
+        self.picked.clear();
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.estimate
+    }
+}
+
+fn nanos(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn shift(time: SystemTime, ns: i64) -> SystemTime {
+    if ns >= 0 {
+        time + Duration::from_nanos(ns as u64)
+    } else {
+        time - Duration::from_nanos(ns.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delayed_pongs_and_drift() {
+        let at = |ms: u64| UNIX_EPOCH + Duration::from_millis(1_000_000 + ms);
+        // The server is 50 ms ahead and gains 100 µs per second. Every fourth
+        // pong is delayed by 30 ms on its way back.
+        let mut clock = ClockSync::default();
+        let mut estimate = None;
+        for i in 0..300 {
+            let sent = i * 100;
+            let offset_ms = 50.0 + sent as f64 * 1e-4;
+            let server = at(sent + 2) + Duration::from_secs_f64(offset_ms / 1e3);
+            let received = at(sent + 4 + if i % 4 == 1 { 30 } else { 0 });
+            estimate = clock.add(at(sent), server, received);
+        }
+        let estimate = estimate.unwrap();
+        let now = at(30_000);
+        let offset_ms = estimate.offset_at(now) as f64 / 1e6;
+        assert!((offset_ms - 53.0).abs() < 0.1, "{offset_ms}");
+        assert!(
+            (estimate.drift_ppm - 100.0).abs() < 5.0,
+            "{}",
+            estimate.drift_ppm
+        );
+        assert_eq!(estimate.rtt_ns, 4_000_000);
+        assert!(estimate.uncertainty_ns < 3_000_000);
+        let server = estimate.to_server_time(now);
+        let back = estimate.to_local_time(server);
+        assert!(nanos(back).abs_diff(nanos(now)) < 1000);
+    }
+}
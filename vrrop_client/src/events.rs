@@ -0,0 +1,233 @@
+//! Everything a [`Client`](crate::Client) receives as one async stream, which
+//! [`Callbacks`](crate::Callbacks) are called from.
+
+use std::collections::VecDeque;
+use std::mem::{discriminant, Discriminant};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use tokio::sync::Notify;
+use vrrop_common::{MapDeltaMessage, SourceId};
+
+use crate::{ConnectionState, ImagesMessage, OdometryMessage, PoseGraphMessage, ServerMessage};
+
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Odometry(OdometryMessage),
+    Images(Box<ImagesMessage>),
+    /// Point cloud deltas when the server runs in fusion mode.
+    MapDelta(MapDeltaMessage),
+    /// See [`Callbacks::with_pose_graph`](crate::Callbacks::with_pose_graph).
+    PoseGraph(PoseGraphMessage),
+    Server(ServerMessage),
+    ConnectionState(ConnectionState),
+}
+
+impl ClientEvent {
+    /// Newer events with the same key make this one useless. Events without
+    /// one build on each other.
+    fn latest_key(&self) -> Option<(Discriminant<Self>, SourceId)> {
+        let source = match self {
+            ClientEvent::Odometry(odometry) => odometry.source,
+            ClientEvent::Images(images) => images.odometry.source,
+            _ => return None,
+        };
+        Some((discriminant(self), source))
+    }
+}
+
+/// What happens to events that arrive faster than they are taken from an
+/// [`EventStream`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Buffering {
+    /// Keeps every event.
+    #[default]
+    Unbounded,
+    /// Keeps only the newest this many images of each source, and as many
+    /// odometry messages. Other events build on each other and are always
+    /// kept.
+    Latest(usize),
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    events: VecDeque<ClientEvent>,
+    /// Set once the client stopped, the stream ends when it's empty.
+    closed: bool,
+    /// Set once the stream was dropped, nothing is queued anymore.
+    abandoned: bool,
+}
+
+impl Queue {
+    async fn pop(&self) -> Option<ClientEvent> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(event) = state.events.pop_front() {
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // Only the stream waits, so a notification can't go to anyone else.
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Events of a client, ending once the client was shut down.
+pub struct EventStream {
+    stream: BoxStream<'static, ClientEvent>,
+    queue: Arc<Queue>,
+}
+
+impl Stream for EventStream {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ClientEvent>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.abandoned = true;
+        state.events.clear();
+    }
+}
+
+/// Hands events of the connection tasks to the [`EventStream`], which ends
+/// when the sender is dropped.
+#[derive(Debug)]
+pub(crate) struct EventSender {
+    queue: Arc<Queue>,
+    buffering: Buffering,
+}
+
+impl EventSender {
+    pub fn send(&self, event: ClientEvent) {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.abandoned {
+            return;
+        }
+        if let (Buffering::Latest(limit), Some(key)) = (self.buffering, event.latest_key()) {
+            let queued = state.events.iter().filter(|e| e.latest_key() == Some(key));
+            if queued.count() >= limit {
+                match state
+                    .events
+                    .iter()
+                    .position(|e| e.latest_key() == Some(key))
+                {
+                    Some(oldest) => {
+                        state.events.remove(oldest);
+                    }
+                    // A limit of zero keeps none at all.
+                    None => return,
+                }
+            }
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.queue.notify.notify_one();
+    }
+
+    pub fn server_message(&self, msg: ServerMessage) {
+        self.send(ClientEvent::Server(msg));
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.notify.notify_one();
+    }
+}
+
+pub(crate) fn channel(buffering: Buffering) -> (EventSender, EventStream) {
+    let queue = Arc::new(Queue::default());
+    let stream = futures::stream::unfold(Arc::clone(&queue), |queue| async move {
+        let event = queue.pop().await?;
+        Some((event, queue))
+    });
+    (
+        EventSender {
+            queue: Arc::clone(&queue),
+            buffering,
+        },
+        EventStream {
+            stream: stream.boxed(),
+            queue,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn latest_drops_old_images_only() {
+        let (sender, stream) = channel(Buffering::Latest(1));
+        let reason = |i: usize| i.to_string();
+        for i in 0..3 {
+            sender.send(ClientEvent::ConnectionState(
+                ConnectionState::Disconnected { reason: reason(i) },
+            ));
+            sender.send(ClientEvent::Server(ServerMessage::Notice {
+                text: reason(i),
+            }));
+        }
+        drop(sender);
+        let events: Vec<_> = stream.collect().await;
+        assert_eq!(events.len(), 6);
+
+        let (sender, stream) = channel(Buffering::Latest(1));
+        let odometry = |secs| OdometryMessage {
+            original_size: 0,
+            epoch: 0,
+            source: 0,
+            stamp: std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs),
+            translation: Default::default(),
+            rotation: Default::default(),
+        };
+        sender.send(ClientEvent::Odometry(odometry(1)));
+        sender.send(ClientEvent::Server(ServerMessage::Notice {
+            text: String::new(),
+        }));
+        sender.send(ClientEvent::Odometry(odometry(2)));
+        sender.send(ClientEvent::Odometry(OdometryMessage {
+            source: 1,
+            ..odometry(3)
+        }));
+        drop(sender);
+        let events: Vec<_> = stream.collect().await;
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], ClientEvent::Server(_)));
+        assert!(matches!(&events[1], ClientEvent::Odometry(o) if o.stamp == odometry(2).stamp));
+        assert!(matches!(&events[2], ClientEvent::Odometry(o) if o.source == 1));
+    }
+
+    #[test]
+    fn nothing_is_queued_for_a_dropped_stream() {
+        let (sender, stream) = channel(Buffering::Unbounded);
+        sender.server_message(ServerMessage::Notice {
+            text: String::new(),
+        });
+        drop(stream);
+        sender.server_message(ServerMessage::Notice {
+            text: String::new(),
+        });
+        assert!(sender.queue.state.lock().unwrap().events.is_empty());
+    }
+}
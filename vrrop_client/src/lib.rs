@@ -1,47 +1,135 @@
 use anyhow::{anyhow, bail, Context, Result};
+use clock::ClockSync;
+use events::EventSender;
 use futures::SinkExt;
 use futures::{never::Never, StreamExt, TryStreamExt};
-use image::{ImageBuffer, Luma, Rgb};
-use nalgebra::{Quaternion, UnitQuaternion, Vector3, Vector4};
-use std::sync::atomic::AtomicI64;
+use std::collections::HashMap;
 use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::SystemTime;
 use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::net::{lookup_host, ToSocketAddrs};
-use tokio::sync::mpsc;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, watch};
 use tokio::{net::UdpSocket, select, task::JoinHandle, time::sleep};
+use tokio_tungstenite::Connector;
 use tokio_util::sync::CancellationToken;
-use vrrop_common::{CameraIntrinsics, Command, Stats};
+pub use vrrop_common::auth::AuthKey;
+use vrrop_common::auth::{PacketOpener, PacketSealer, RejectLog, CONTEXT_CLIENT, CONTEXT_SERVER};
+use vrrop_common::decoded::{self, decode_pose_graph_message};
+pub use vrrop_common::decoded::{
+    decode_odometry_message, GraphNode, ImagesMessage, OdometryMessage, PoseGraphMessage,
+};
+pub use vrrop_common::pointcloud::{CloudDelta, GridIndex, PointCloud};
+use vrrop_common::{
+    codec, Command, FeedbackMessage, HelloMessage, LeaveMessage, PingMessage, Stats,
+    SubscribeMessage, UdpClientMessage, WebSocketClientMessage, WebSocketServerMessage,
+};
+pub use vrrop_common::{ImageStages, ImageTimings, MapDeltaMessage, MapInfo, SourceId};
 
-mod pointcloud;
-pub use pointcloud::GridIndex;
-pub use pointcloud::PointCloud;
+/// The UDP subscription is renewed every this many pings.
+const PINGS_PER_SUBSCRIBE: u32 = 10;
 
+mod clock;
+mod events;
+mod reconnect;
+mod tls;
+pub use clock::ClockEstimate;
+pub use events::{Buffering, ClientEvent, EventStream};
+pub use reconnect::ReconnectPolicy;
+pub use tls::TlsConfig;
+pub use vrrop_common::cert::Fingerprint;
+
+/// Control messages from the server.
 #[derive(Debug, Clone)]
-pub enum ServerMessage {}
-
-#[derive(Debug, Copy, Clone)]
-pub struct OdometryMessage {
-    pub original_size: usize,
-    pub stamp: std::time::SystemTime,
-    pub translation: Vector3<f32>,
-    pub rotation: UnitQuaternion<f32>,
+pub enum ServerMessage {
+    /// The server closed the connection. The client reconnects after
+    /// `retry_after`, or after the usual delay if the server gave none.
+    Goodbye {
+        reason: String,
+        retry_after: Option<Duration>,
+    },
+    /// The map of `source` was restarted; anything accumulated of it so far
+    /// is stale.
+    MapReset {
+        source: SourceId,
+        epoch: u64,
+    },
+    Notice {
+        text: String,
+    },
+    /// A camera of the server stopped or came back. The server keeps retrying
+    /// while it's gone.
+    CameraStatus {
+        source: SourceId,
+        connected: bool,
+        message: String,
+    },
+    /// Saved maps, and the one SLAM localizes in.
+    Maps {
+        maps: Vec<MapInfo>,
+        loaded: Option<String>,
+    },
+    /// Poses of `source` in `epoch` are in the frame of the saved map `map`,
+    /// so a point cloud kept for that map can be shown again.
+    Relocalized {
+        map: String,
+        source: SourceId,
+        epoch: u64,
+    },
 }
 
-#[derive(Debug, Clone)]
-pub struct ImagesMessage {
-    pub original_size: usize,
-    pub odometry: OdometryMessage,
-    pub color: ImageBuffer<Rgb<u8>, Vec<u8>>,
-    pub color_intrinsics: CameraIntrinsics,
-    pub depth: ImageBuffer<Luma<u16>, Vec<u16>>,
-    pub depth_intrinsics: CameraIntrinsics,
-    pub depth_unit: f32,
+/// Where the client is in connecting to the server, see
+/// [`Client::connection_state`] and [`Callbacks::with_connection_state`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected(ServerInfo),
+    /// The client was shut down or ran out of attempts, see
+    /// [`ReconnectPolicy::max_attempts`]. It doesn't reconnect.
+    Disconnected {
+        reason: String,
+    },
+    /// The connection ended or couldn't be made, waiting before connecting
+    /// again.
+    Reconnecting {
+        reason: String,
+        delay: Duration,
+    },
+}
+
+/// The server the client is connected to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub url: String,
+    pub session_id: u64,
+    /// Map epoch of each source, indexed by id.
+    pub map_epochs: Vec<u64>,
+    /// See [`Client::sources`].
+    pub sources: Vec<String>,
+}
+
+/// Ends a connection the server said goodbye to.
+#[derive(Debug)]
+struct ServerGoodbye {
+    reason: String,
+    retry_after: Option<Duration>,
 }
 
+impl std::fmt::Display for ServerGoodbye {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server closed the connection: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ServerGoodbye {}
+
 pub struct Callbacks {
     on_odometry: Box<dyn Fn(OdometryMessage) + Send + Sync>,
     on_images: Box<dyn Fn(ImagesMessage) + Send + Sync>,
+    on_map_delta: Option<Box<dyn Fn(MapDeltaMessage) + Send + Sync>>,
+    on_pose_graph: Option<Box<dyn Fn(PoseGraphMessage) + Send + Sync>>,
+    on_server_message: Option<Box<dyn Fn(ServerMessage) + Send + Sync>>,
+    on_connection_state: Option<Box<dyn Fn(ConnectionState) + Send + Sync>>,
 }
 
 impl Callbacks {
@@ -52,16 +140,176 @@ impl Callbacks {
         Self {
             on_odometry: Box::new(on_odometry),
             on_images: Box::new(on_images),
+            on_map_delta: None,
+            on_pose_graph: None,
+            on_server_message: None,
+            on_connection_state: None,
+        }
+    }
+
+    /// Called with point cloud deltas when the server runs in fusion mode.
+    pub fn with_map_delta(
+        mut self,
+        on_map_delta: impl Fn(MapDeltaMessage) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_map_delta = Some(Box::new(on_map_delta));
+        self
+    }
+
+    /// Called when a loop closure corrected the poses of the current map, so
+    /// whatever was placed with them can be moved.
+    pub fn with_pose_graph(
+        mut self,
+        on_pose_graph: impl Fn(PoseGraphMessage) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_pose_graph = Some(Box::new(on_pose_graph));
+        self
+    }
+
+    pub fn with_server_message(
+        mut self,
+        on_server_message: impl Fn(ServerMessage) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_server_message = Some(Box::new(on_server_message));
+        self
+    }
+
+    /// Called whenever the client connects, is disconnected or waits to
+    /// reconnect.
+    pub fn with_connection_state(
+        mut self,
+        on_connection_state: impl Fn(ConnectionState) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_connection_state = Some(Box::new(on_connection_state));
+        self
+    }
+
+    /// Calls the callback of `event`, if there is one.
+    fn call(&self, event: ClientEvent) {
+        match event {
+            ClientEvent::Odometry(msg) => (self.on_odometry)(msg),
+            ClientEvent::Images(msg) => (self.on_images)(*msg),
+            ClientEvent::MapDelta(delta) => {
+                if let Some(on_map_delta) = &self.on_map_delta {
+                    on_map_delta(delta);
+                }
+            }
+            ClientEvent::PoseGraph(graph) => {
+                if let Some(on_pose_graph) = &self.on_pose_graph {
+                    on_pose_graph(graph);
+                }
+            }
+            ClientEvent::Server(msg) => {
+                if let Some(on_server_message) = &self.on_server_message {
+                    on_server_message(msg);
+                }
+            }
+            ClientEvent::ConnectionState(state) => {
+                if let Some(on_connection_state) = &self.on_connection_state {
+                    on_connection_state(state);
+                }
+            }
         }
     }
 }
 
+/// How to connect and authenticate to the server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Pre-shared token, required if the server has one.
+    pub auth: Option<AuthKey>,
+    pub tls: Option<TlsConfig>,
+    /// Names of the cameras to receive, all of them if empty.
+    pub sources: Vec<String>,
+    pub reconnect: ReconnectPolicy,
+    /// Limit on resolving the host and opening the WebSocket connection.
+    pub connect_timeout: Duration,
+    /// How long to wait for each handshake message of the server after
+    /// connecting.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            auth: None,
+            tls: None,
+            sources: Vec::new(),
+            reconnect: ReconnectPolicy::default(),
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn with_auth(mut self, auth: AuthKey) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_sources(mut self, sources: Vec<String>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    pub fn with_reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+}
+
 pub struct Client {
     connect_loop: JoinHandle<()>,
+    /// Calls the callbacks of a client made with them.
+    callbacks_loop: Option<JoinHandle<()>>,
     cancel: CancellationToken,
     command_sender: mpsc::UnboundedSender<Command>,
-    stats: Arc<Mutex<StatsState>>,
-    server_time_offset_ns: Arc<AtomicI64>,
+    shared: Arc<Shared>,
+}
+
+/// State shared between the [`Client`] and its connection tasks.
+#[derive(Debug, Default)]
+struct Shared {
+    stats: Mutex<StatsState>,
+    clock: Mutex<ClockSync>,
+    map_epoch: MapEpoch,
+    /// Whole pose graph of each source, the server only sends changes.
+    pose_graphs: Mutex<HashMap<SourceId, vrrop_common::PoseGraphMessage>>,
+    /// The server's cameras, from the last welcome.
+    sources: Mutex<Vec<String>>,
+    connection_state: watch::Sender<ConnectionState>,
+}
+
+impl Shared {
+    /// The client clock until the first pong.
+    fn server_now(&self) -> SystemTime {
+        let now = SystemTime::now();
+        match self.clock.lock().unwrap().estimate() {
+            Some(estimate) => estimate.to_server_time(now),
+            None => now,
+        }
+    }
+
+    fn set_connection_state(&self, state: ConnectionState, events: &EventSender) {
+        self.connection_state.send_replace(state.clone());
+        events.send(ClientEvent::ConnectionState(state));
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -70,110 +318,225 @@ struct StatsState {
     recording: bool,
 }
 
+/// The server's current map epoch of each source. Messages of older epochs
+/// are stale and dropped; a newer epoch is announced as
+/// [`ServerMessage::MapReset`].
+#[derive(Debug, Default)]
+struct MapEpoch(Mutex<HashMap<SourceId, u64>>);
+
+impl MapEpoch {
+    fn accept(&self, source: SourceId, epoch: u64, events: &EventSender) -> bool {
+        let mut current = self.0.lock().unwrap();
+        match current.get(&source) {
+            Some(&current) if epoch < current => return false,
+            Some(&current) if epoch == current => return true,
+            _ => {}
+        }
+        let first = current.insert(source, epoch).is_none();
+        drop(current);
+        if !first {
+            events.server_message(ServerMessage::MapReset { source, epoch });
+        }
+        true
+    }
+
+    /// A restarted server may start over at lower epochs.
+    fn welcome(&self, epochs: &[u64], events: &EventSender) {
+        let mut current = self.0.lock().unwrap();
+        let prev = std::mem::take(&mut *current);
+        current.extend((0..).zip(epochs.iter().copied()));
+        drop(current);
+        for (source, &epoch) in (0..).zip(epochs) {
+            if prev.get(&source).is_some_and(|&prev| prev != epoch) {
+                events.server_message(ServerMessage::MapReset { source, epoch });
+            }
+        }
+    }
+
+    fn get(&self, source: SourceId) -> Option<u64> {
+        self.0.lock().unwrap().get(&source).copied()
+    }
+}
+
+/// Decodes on a blocking thread, see [`decoded::decode_images_message`].
 pub async fn decode_images_message(
     compressed: vrrop_common::ImagesMessage,
     original_size: usize,
 ) -> Result<ImagesMessage> {
-    let color_image = compressed.color_image;
-    let depth_image = compressed.depth_image;
-    let color = tokio::task::spawn_blocking(move || image::load_from_memory(&color_image));
-    let depth = tokio::task::spawn_blocking(move || image::load_from_memory(&depth_image));
-    Ok(ImagesMessage {
-        original_size,
-        odometry: decode_odometry_message(compressed.odometry, 0),
-        color: color.await??.to_rgb8(),
-        color_intrinsics: compressed.color_intrinsics,
-        depth: depth.await??.to_luma16(),
-        depth_intrinsics: compressed.depth_intrinsics,
-        depth_unit: compressed.depth_unit,
-    })
+    tokio::task::spawn_blocking(move || decoded::decode_images_message(compressed, original_size))
+        .await?
 }
 
-pub fn decode_odometry_message(
-    raw: vrrop_common::OdometryMessage,
-    original_size: usize,
-) -> OdometryMessage {
-    OdometryMessage {
-        original_size,
-        stamp: raw.stamp,
-        translation: Vector3::from_row_slice(&raw.translation),
-        rotation: UnitQuaternion::new_normalize(Quaternion::from_vector(Vector4::from_row_slice(
-            &raw.rotation,
-        ))),
+async fn handle_websocket_message(
+    data: &[u8],
+    events: &EventSender,
+    shared: &Shared,
+    feedback_sender: &mpsc::UnboundedSender<FeedbackMessage>,
+) -> Result<()> {
+    match bincode::deserialize::<WebSocketServerMessage>(data)? {
+        WebSocketServerMessage::Images(compressed) => {
+            if !shared.map_epoch.accept(
+                compressed.odometry.source,
+                compressed.odometry.epoch,
+                events,
+            ) {
+                return Ok(());
+            }
+            handle_images_message(compressed, data.len(), events, shared, feedback_sender).await
+        }
+        WebSocketServerMessage::MapDelta(delta) => {
+            if !shared.map_epoch.accept(delta.source, delta.epoch, events) {
+                return Ok(());
+            }
+            events.send(ClientEvent::MapDelta(delta));
+            Ok(())
+        }
+        WebSocketServerMessage::Challenge(_) => bail!("Unexpected challenge after handshake"),
+        WebSocketServerMessage::Welcome(_) => bail!("Unexpected welcome after handshake"),
+        WebSocketServerMessage::Goodbye(goodbye) => {
+            events.server_message(ServerMessage::Goodbye {
+                reason: goodbye.reason.clone(),
+                retry_after: goodbye.retry_after,
+            });
+            Err(ServerGoodbye {
+                reason: goodbye.reason,
+                retry_after: goodbye.retry_after,
+            }
+            .into())
+        }
+        WebSocketServerMessage::MapReset(reset) => {
+            shared.map_epoch.accept(reset.source, reset.epoch, events);
+            Ok(())
+        }
+        WebSocketServerMessage::Notice(notice) => {
+            events.server_message(ServerMessage::Notice { text: notice.text });
+            Ok(())
+        }
+        WebSocketServerMessage::CameraStatus(status) => {
+            events.server_message(ServerMessage::CameraStatus {
+                source: status.source,
+                connected: status.connected,
+                message: status.message,
+            });
+            Ok(())
+        }
+        WebSocketServerMessage::Maps(maps) => {
+            events.server_message(ServerMessage::Maps {
+                maps: maps.maps,
+                loaded: maps.loaded,
+            });
+            Ok(())
+        }
+        WebSocketServerMessage::Relocalized(relocalized) => {
+            events.server_message(ServerMessage::Relocalized {
+                map: relocalized.map,
+                source: relocalized.source,
+                epoch: relocalized.epoch,
+            });
+            Ok(())
+        }
+        WebSocketServerMessage::PoseGraph(pose_graph) => {
+            if !shared
+                .map_epoch
+                .accept(pose_graph.source, pose_graph.epoch, events)
+            {
+                return Ok(());
+            }
+            let mut graphs = shared.pose_graphs.lock().unwrap();
+            let graph = graphs.entry(pose_graph.source).or_insert_with(|| {
+                vrrop_common::PoseGraphMessage::new(pose_graph.epoch, pose_graph.source)
+            });
+            graph.merge(pose_graph);
+            let graph = graph.clone();
+            drop(graphs);
+            events.send(ClientEvent::PoseGraph(decode_pose_graph_message(graph)));
+            Ok(())
+        }
+        WebSocketServerMessage::Dropped(dropped) => {
+            let mut stats = shared.stats.lock().unwrap();
+            if stats.recording {
+                stats.stats.messages_dropped += dropped.messages;
+            }
+            Ok(())
+        }
     }
 }
 
 async fn handle_images_message(
-    data: &[u8],
-    callbacks: &Callbacks,
-    stats: &Mutex<StatsState>,
-    server_time_offset_ns: &Arc<AtomicI64>,
+    compressed: vrrop_common::ImagesMessage,
+    original_size: usize,
+    events: &EventSender,
+    shared: &Shared,
+    feedback_sender: &mpsc::UnboundedSender<FeedbackMessage>,
 ) -> Result<()> {
-    let compressed = bincode::deserialize::<vrrop_common::ImagesMessage>(data)?;
-    let msg = decode_images_message(compressed, data.len()).await?;
+    let received = shared.server_now();
+    let decode_start = std::time::Instant::now();
+    let mut msg = decode_images_message(compressed, original_size).await?;
+    msg.stages = ImageStages::new(&msg.timings, received, decode_start.elapsed());
+    let latency_ns = latency(msg.odometry.stamp, shared.server_now());
     {
-        let mut stats = stats.lock().unwrap();
+        let mut stats = shared.stats.lock().unwrap();
         if stats.recording {
-            let stamp_ns = msg.odometry.stamp.duration_since(UNIX_EPOCH)?.as_nanos();
-            let server_time_offset_ns =
-                server_time_offset_ns.load(std::sync::atomic::Ordering::Relaxed);
-            let now_server_time_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
-                as i64
-                + server_time_offset_ns;
-            let latency_ns = now_server_time_ns - stamp_ns as i64;
             stats.stats.images_stamps.push(msg.odometry.stamp);
-            stats.stats.images_original_sizes.push(data.len());
+            stats.stats.images_original_sizes.push(original_size);
             stats.stats.images_latencies.push(latency_ns);
+            stats.stats.images_stages.push(msg.stages);
         }
     }
-    (callbacks.on_images)(msg);
+    let _ = feedback_sender.send(FeedbackMessage {
+        stamp: msg.odometry.stamp,
+        latency_ns,
+    });
+    events.send(ClientEvent::Images(Box::new(msg)));
     Ok(())
 }
 
-async fn handle_udp_message(
-    data: &[u8],
-    callbacks: &Callbacks,
-    stats: &Mutex<StatsState>,
-    server_time_offset_ns: &Arc<AtomicI64>,
-) -> Result<()> {
+/// From `stamp` to `now` in nanoseconds, negative if `now` is earlier.
+fn latency(stamp: SystemTime, now: SystemTime) -> i64 {
+    match now.duration_since(stamp) {
+        Ok(latency) => latency.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+async fn handle_udp_message(data: &[u8], events: &EventSender, shared: &Shared) -> Result<()> {
     let raw = bincode::deserialize::<vrrop_common::UdpServerMessage>(data)?;
     match raw {
         vrrop_common::UdpServerMessage::Pong(pong) => {
-            let Ok(rtt) = pong.client_time.elapsed() else {
+            let received = SystemTime::now();
+            let Ok(rtt) = received.duration_since(pong.client_time) else {
                 return Ok(());
             };
-            let server_time_ns = pong
-                .server_time
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_nanos() as i64;
-            let now_ns = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_nanos() as i64;
-            let rtt_ns = rtt.as_nanos() as i64;
-            let new_server_time_offset_ns = server_time_ns - (now_ns - rtt_ns / 2);
-            server_time_offset_ns.store(
-                new_server_time_offset_ns,
-                std::sync::atomic::Ordering::Relaxed,
-            );
+            let estimate =
+                shared
+                    .clock
+                    .lock()
+                    .unwrap()
+                    .add(pong.client_time, pong.server_time, received);
+            let mut stats = shared.stats.lock().unwrap();
+            if let (true, Some(estimate)) = (stats.recording, estimate) {
+                let stats = &mut stats.stats;
+                stats.time_sync_rtts.push(rtt.as_nanos() as i64);
+                stats.time_sync_offsets.push(estimate.offset_at(received));
+                stats.time_sync_uncertainties.push(estimate.uncertainty_ns);
+                stats.time_sync_drifts.push(estimate.drift_ppm);
+            }
         }
         vrrop_common::UdpServerMessage::Odometry(odom) => {
+            if !shared.map_epoch.accept(odom.source, odom.epoch, events) {
+                return Ok(());
+            }
             let msg = decode_odometry_message(odom, data.len());
             {
-                let mut stats = stats.lock().unwrap();
+                let mut stats = shared.stats.lock().unwrap();
                 if stats.recording {
-                    let stamp_ns = msg.stamp.duration_since(UNIX_EPOCH)?.as_nanos();
-                    let server_time_offset_ns =
-                        server_time_offset_ns.load(std::sync::atomic::Ordering::Relaxed);
-                    let now_server_time_ns =
-                        SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as i64
-                            + server_time_offset_ns;
-                    let latency_ns = now_server_time_ns - stamp_ns as i64;
+                    let latency_ns = latency(msg.stamp, shared.server_now());
                     stats.stats.odometry_stamps.push(msg.stamp);
                     stats.stats.odometry_original_sizes.push(data.len());
                     stats.stats.odometry_latencies.push(latency_ns);
                 }
             }
-            (callbacks.on_odometry)(msg);
+            events.send(ClientEvent::Odometry(msg));
         }
     }
     Ok(())
@@ -181,35 +544,110 @@ async fn handle_udp_message(
 
 async fn connect(
     target: SocketAddr,
-    callbacks: Arc<Callbacks>,
+    options: &ClientOptions,
+    events: Arc<EventSender>,
     cancel: CancellationToken,
     command_receiver: &mut mpsc::UnboundedReceiver<Command>,
-    stats: Arc<Mutex<StatsState>>,
-    server_time_offset_ns: Arc<AtomicI64>,
+    shared: Arc<Shared>,
 ) -> Result<()> {
     let udp_sock = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
     udp_sock.connect(target).await?;
+    shared.clock.lock().unwrap().restart();
 
-    let url = format!("ws://{}", target);
-    let ws_stream = tokio_tungstenite::connect_async(&url).await?.0;
-    println!("Connected to {}", url);
-    let (mut ws_writer, ws_reader) = ws_stream.split();
+    let (url, connector) = match &options.tls {
+        Some(tls) => {
+            let url = match &tls.server_name {
+                Some(name) => format!("wss://{}:{}", name, target.port()),
+                None => format!("wss://{}", target),
+            };
+            (url, Connector::Rustls(tls.client_config()?))
+        }
+        None => (format!("ws://{}", target), Connector::Plain),
+    };
+    let ws_stream = tokio::time::timeout(options.connect_timeout, async {
+        let tcp_stream = TcpStream::connect(target).await?;
+        anyhow::Ok(
+            tokio_tungstenite::client_async_tls_with_config(
+                url.as_str(),
+                tcp_stream,
+                None,
+                Some(connector),
+            )
+            .await?
+            .0,
+        )
+    })
+    .await
+    .with_context(|| format!("Timed out connecting to {target}"))??;
+    let (mut ws_writer, mut ws_reader) = ws_stream.split();
+    let challenge = tokio::time::timeout(options.handshake_timeout, ws_reader.next())
+        .await
+        .context("Timed out waiting for the server's challenge")?
+        .context("WebSocket connection closed during handshake")??;
+    let WebSocketServerMessage::Challenge(challenge) =
+        bincode::deserialize(&challenge.into_data())?
+    else {
+        bail!("Expected a challenge from the server");
+    };
+    let hello = WebSocketClientMessage::Hello(HelloMessage {
+        color_codecs: codec::supported_color_codecs(),
+        depth_codecs: codec::supported_depth_codecs(),
+        auth_response: options
+            .auth
+            .as_ref()
+            .map(|key| key.respond(&challenge.nonce)),
+        sources: options.sources.clone(),
+    });
+    ws_writer
+        .send(tokio_tungstenite::tungstenite::Message::binary(
+            bincode::serialize(&hello)?,
+        ))
+        .await?;
+    let welcome = tokio::time::timeout(options.handshake_timeout, ws_reader.next())
+        .await
+        .context("Timed out waiting for the server to accept the hello")?
+        .context("WebSocket connection closed during handshake")??;
+    let welcome = match bincode::deserialize(&welcome.into_data())? {
+        WebSocketServerMessage::Welcome(welcome) => welcome,
+        // E.g. the hello named sources the server doesn't have.
+        WebSocketServerMessage::Goodbye(goodbye) => {
+            events.server_message(ServerMessage::Goodbye {
+                reason: goodbye.reason.clone(),
+                retry_after: goodbye.retry_after,
+            });
+            return Err(ServerGoodbye {
+                reason: goodbye.reason,
+                retry_after: goodbye.retry_after,
+            }
+            .into());
+        }
+        _ => bail!("Expected a welcome from the server"),
+    };
+    *shared.sources.lock().unwrap() = welcome.sources.clone();
+    shared.map_epoch.welcome(&welcome.map_epochs, &events);
+    // The server sends the whole graphs again.
+    shared.pose_graphs.lock().unwrap().clear();
+    shared.set_connection_state(
+        ConnectionState::Connected(ServerInfo {
+            url,
+            session_id: welcome.session_id,
+            map_epochs: welcome.map_epochs,
+            sources: welcome.sources,
+        }),
+        &events,
+    );
+    let session_token = welcome.session_token;
+    let (feedback_sender, mut feedback_receiver) = mpsc::unbounded_channel();
 
     let mut ws_read_loop = tokio::spawn({
-        let callbacks = Arc::clone(&callbacks);
-        let stats = Arc::clone(&stats);
-        let server_time_offset_ns = Arc::clone(&server_time_offset_ns);
+        let events = Arc::clone(&events);
+        let shared = Arc::clone(&shared);
         async move {
             ws_reader
                 .map_err(|e| anyhow!(e))
                 .and_then(|msg| async {
-                    handle_images_message(
-                        &msg.into_data(),
-                        &callbacks,
-                        &stats,
-                        &server_time_offset_ns,
-                    )
-                    .await?;
+                    handle_websocket_message(&msg.into_data(), &events, &shared, &feedback_sender)
+                        .await?;
                     anyhow::Ok(())
                 })
                 .try_for_each(|_| async { Ok(()) })
@@ -220,155 +658,365 @@ async fn connect(
 
     let mut udp_recv_loop: JoinHandle<Result<Never>> = tokio::spawn({
         let udp_sock = Arc::clone(&udp_sock);
-        let callbacks = Arc::clone(&callbacks);
-        let stats = Arc::clone(&stats);
-        let server_time_offset_ns = Arc::clone(&server_time_offset_ns);
+        let events = Arc::clone(&events);
+        let shared = Arc::clone(&shared);
+        let mut opener = PacketOpener::new(options.auth.clone(), CONTEXT_SERVER);
+        let mut rejects = RejectLog::new("udp");
         async move {
             loop {
                 let mut data = [0u8; 1024];
                 let n = udp_sock.recv(&mut data).await?;
-                handle_udp_message(&data[..n], &callbacks, &stats, &server_time_offset_ns).await?;
+                let data = match opener.open(&data[..n]) {
+                    Ok(data) => data,
+                    Err(reason) => {
+                        rejects.record(target, reason);
+                        continue;
+                    }
+                };
+                handle_udp_message(data, &events, &shared).await?;
             }
         }
     });
     let udp_recv_abort_handle = udp_recv_loop.abort_handle();
 
+    let sealer = Arc::new(PacketSealer::new(options.auth.clone(), CONTEXT_CLIENT));
     let mut udp_send_loop: JoinHandle<Result<Never>> = tokio::spawn({
         let udp_sock = Arc::clone(&udp_sock);
+        let sealer = Arc::clone(&sealer);
         async move {
+            let mut pings = 0u32;
             loop {
-                let msg = bincode::serialize(&vrrop_common::UdpClientMessage::Ping(
-                    vrrop_common::PingMessage {
-                        client_time: std::time::SystemTime::now(),
-                    },
-                ))?;
+                if pings.is_multiple_of(PINGS_PER_SUBSCRIBE) {
+                    let msg = sealer.seal(bincode::serialize(&UdpClientMessage::Subscribe(
+                        SubscribeMessage { session_token },
+                    ))?);
+                    udp_sock.send(&msg).await?;
+                }
+                let msg = sealer.seal(bincode::serialize(&UdpClientMessage::Ping(PingMessage {
+                    session_token,
+                    client_time: std::time::SystemTime::now(),
+                }))?);
                 udp_sock.send(&msg).await?;
+                pings = pings.wrapping_add(1);
                 sleep(Duration::from_millis(100)).await;
             }
         }
     });
     let udp_send_abort_handle = udp_send_loop.abort_handle();
 
-    loop {
-        select! {
-            res = &mut ws_read_loop => {
-                match res.unwrap() {
-                    Ok(()) => {
-                        bail!("WebSocket connection closed");
-                    }
-                    Err(e) => {
-                        return Err(anyhow!(e).context("Reading Websocket failed"))
+    // The tasks hold the event sender, which has to go for the event stream to end.
+    let res: Result<()> = async {
+        loop {
+            select! {
+                res = &mut ws_read_loop => {
+                    match res.unwrap() {
+                        Ok(()) => {
+                            bail!("WebSocket connection closed");
+                        }
+                        Err(e) => {
+                            return Err(anyhow!(e).context("Reading Websocket failed"))
+                        }
                     }
                 }
-            }
-            res = &mut udp_recv_loop => {
-                match res.unwrap() {
-                    Ok(a) => match a {},
-                    Err(e) => {
-                        return Err(e.context("Reading UDP failed"))
+                res = &mut udp_recv_loop => {
+                    match res.unwrap() {
+                        Ok(a) => match a {},
+                        Err(e) => {
+                            return Err(e.context("Reading UDP failed"))
+                        }
                     }
                 }
-            }
-            res = &mut udp_send_loop => {
-                match res.unwrap() {
-                    Ok(a) => match a {},
-                    Err(e) => {
-                        return Err(e.context("Sending UDP failed"))
+                res = &mut udp_send_loop => {
+                    match res.unwrap() {
+                        Ok(a) => match a {},
+                        Err(e) => {
+                            return Err(e.context("Sending UDP failed"))
+                        }
                     }
                 }
-            }
-            res = command_receiver.recv() => {
-                match res {
-                    Some(command) => {
-                        ws_writer.send(tokio_tungstenite::tungstenite::Message::binary(bincode::serialize(&command)?)).await?;
-                    }
-                    None => {
-                        break;
+                res = command_receiver.recv() => {
+                    match res {
+                        Some(command) => {
+                            let msg = WebSocketClientMessage::Command(command);
+                            ws_writer.send(tokio_tungstenite::tungstenite::Message::binary(bincode::serialize(&msg)?)).await?;
+                        }
+                        None => {
+                            break;
+                        }
                     }
                 }
-            }
-            _ = cancel.cancelled() => {
-                break;
+                Some(feedback) = feedback_receiver.recv() => {
+                    let msg = WebSocketClientMessage::Feedback(feedback);
+                    ws_writer.send(tokio_tungstenite::tungstenite::Message::binary(bincode::serialize(&msg)?)).await?;
+                }
+                _ = cancel.cancelled() => {
+                    break;
+                }
             }
         }
+        Ok(())
     }
+    .await;
     ws_read_abort_handle.abort();
     udp_recv_abort_handle.abort();
     udp_send_abort_handle.abort();
+    res?;
+    // Best effort, the subscription times out anyway.
+    let leave = sealer.seal(bincode::serialize(&UdpClientMessage::Leave(
+        LeaveMessage { session_token },
+    ))?);
+    let _ = udp_sock.send(&leave).await;
     Ok(())
 }
 
+/// Resolves the host for each attempt, its address may have changed since the
+/// last one.
+async fn resolve(target: impl ToSocketAddrs, timeout: Duration) -> Result<SocketAddr> {
+    tokio::time::timeout(timeout, lookup_host(target))
+        .await
+        .context("Timed out resolving the host")??
+        .next()
+        .context("Failed to resolve host")
+}
+
+/// How events wait for [`Callbacks`]. Images that pile up behind a slow
+/// callback would only show ever older frames, while the server keeps sending
+/// at full rate since the client receives them in time.
+pub const CALLBACK_BUFFERING: Buffering = Buffering::Latest(2);
+
 impl Client {
-    pub async fn new(target: impl ToSocketAddrs, callbacks: Callbacks) -> Result<Self> {
-        let target = lookup_host(target)
-            .await?
-            .next()
-            .context("Failed to resolve host")?;
-        let callbacks = Arc::new(callbacks);
+    pub async fn new(
+        target: impl ToSocketAddrs + Clone + Send + Sync + 'static,
+        callbacks: Callbacks,
+    ) -> Result<Self> {
+        Self::with_options(target, ClientOptions::default(), callbacks).await
+    }
+
+    /// Like [`Client::with_events`], with `callbacks` called for every event.
+    /// Only the latest few images and odometry messages wait for slow
+    /// callbacks, see [`CALLBACK_BUFFERING`].
+    pub async fn with_options(
+        target: impl ToSocketAddrs + Clone + Send + Sync + 'static,
+        options: ClientOptions,
+        callbacks: Callbacks,
+    ) -> Result<Self> {
+        let (mut client, mut events) =
+            Self::with_events(target, options, CALLBACK_BUFFERING).await?;
+        client.callbacks_loop = Some(tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                callbacks.call(event);
+            }
+        }));
+        Ok(client)
+    }
+
+    /// Keeps connecting to `target` in the background as `options.reconnect`
+    /// allows, see [`Client::connection_state`]. Everything received comes
+    /// from the returned stream, buffered as `buffering` says.
+    pub async fn with_events(
+        target: impl ToSocketAddrs + Clone + Send + Sync + 'static,
+        options: ClientOptions,
+        buffering: Buffering,
+    ) -> Result<(Self, EventStream)> {
+        let (events, stream) = events::channel(buffering);
+        let events = Arc::new(events);
         let cancel = CancellationToken::new();
         let (command_sender, mut command_receiver) = mpsc::unbounded_channel();
-        let stats = Arc::new(Mutex::new(StatsState::default()));
-        let server_time_offset_ns = Arc::new(AtomicI64::new(0));
+        let shared = Arc::new(Shared::default());
 
         let connect_loop = tokio::spawn({
             let cancel = cancel.clone();
-            let stats = Arc::clone(&stats);
-            let server_time_offset_ns = Arc::clone(&server_time_offset_ns);
+            let shared = Arc::clone(&shared);
             async move {
+                let mut failures = 0;
                 loop {
-                    match connect(
-                        target,
-                        Arc::clone(&callbacks),
-                        cancel.clone(),
-                        &mut command_receiver,
-                        Arc::clone(&stats.clone()),
-                        Arc::clone(&server_time_offset_ns),
-                    )
-                    .await
-                    {
-                        Ok(_) => return,
-                        Err(e) => {
-                            eprintln!("Error: {:?}", e);
+                    shared.set_connection_state(ConnectionState::Connecting, &events);
+                    let res = match resolve(target.clone(), options.connect_timeout).await {
+                        Ok(addr) => {
+                            connect(
+                                addr,
+                                &options,
+                                Arc::clone(&events),
+                                cancel.clone(),
+                                &mut command_receiver,
+                                Arc::clone(&shared),
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    if matches!(
+                        *shared.connection_state.borrow(),
+                        ConnectionState::Connected(_)
+                    ) {
+                        failures = 0;
+                    }
+                    let (reason, retry_after) = match res {
+                        Ok(_) => {
+                            let reason = "Client shut down".to_owned();
+                            let state = ConnectionState::Disconnected { reason };
+                            shared.set_connection_state(state, &events);
+                            return;
+                        }
+                        Err(e) => match e.downcast_ref::<ServerGoodbye>() {
+                            Some(goodbye) => (goodbye.to_string(), goodbye.retry_after),
+                            None => (format!("{e:#}"), None),
+                        },
+                    };
+                    failures += 1;
+                    if !options.reconnect.should_retry(failures) {
+                        let reason = format!("{reason}, giving up after {failures} attempts");
+                        let state = ConnectionState::Disconnected { reason };
+                        shared.set_connection_state(state, &events);
+                        return;
+                    }
+                    let delay =
+                        retry_after.unwrap_or_else(|| options.reconnect.next_delay(failures));
+                    let state = ConnectionState::Reconnecting { reason, delay };
+                    shared.set_connection_state(state, &events);
+                    select! {
+                        _ = sleep(delay) => {}
+                        _ = cancel.cancelled() => {
+                            let reason = "Client shut down".to_owned();
+                            let state = ConnectionState::Disconnected { reason };
+                            shared.set_connection_state(state, &events);
+                            return;
                         }
                     }
-                    sleep(Duration::from_secs(1)).await;
                 }
             }
         });
 
-        Ok(Self {
+        let client = Self {
             connect_loop,
+            callbacks_loop: None,
             cancel,
             command_sender,
-            stats,
-            server_time_offset_ns,
-        })
+            shared,
+        };
+        Ok((client, stream))
     }
 
+    /// Sent once connected. Dropped if the client gave up reconnecting, see
+    /// [`ConnectionState::Disconnected`].
     pub fn send_command(&self, command: Command) {
-        self.command_sender.send(command).unwrap();
+        let _ = self.command_sender.send(command);
+    }
+
+    /// Changes whenever the client connects, is disconnected or waits to
+    /// reconnect.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.shared.connection_state.subscribe()
+    }
+
+    /// The current time on the server's clock, which stamps and latencies are
+    /// measured in. The client's clock until the first pong.
+    pub fn server_now(&self) -> SystemTime {
+        self.shared.server_now()
+    }
+
+    /// When the server's clock showed `server_time`, on the client's clock.
+    pub fn to_local_time(&self, server_time: SystemTime) -> SystemTime {
+        match self.clock() {
+            Some(estimate) => estimate.to_local_time(server_time),
+            None => server_time,
+        }
+    }
+
+    /// How the server's clock relates to the client's, once a pong arrived.
+    pub fn clock(&self) -> Option<ClockEstimate> {
+        self.shared.clock.lock().unwrap().estimate()
+    }
+
+    /// The server's map epoch of `source`, once connected.
+    pub fn map_epoch(&self, source: SourceId) -> Option<u64> {
+        self.shared.map_epoch.get(source)
+    }
+
+    /// Names of the server's cameras by source id, once connected.
+    pub fn sources(&self) -> Vec<String> {
+        self.shared.sources.lock().unwrap().clone()
     }
 
     pub fn start_recording(&self) {
-        let mut stats = self.stats.lock().unwrap();
+        let mut stats = self.shared.stats.lock().unwrap();
         stats.recording = true;
     }
 
     pub fn is_recording(&self) -> bool {
-        self.stats.lock().unwrap().recording
+        self.shared.stats.lock().unwrap().recording
+    }
+
+    /// Adds the time the application took to merge the images stamped
+    /// `stamp` into its point cloud to the statistics being recorded.
+    pub fn record_merge(&self, stamp: SystemTime, time: Duration) {
+        let mut stats = self.shared.stats.lock().unwrap();
+        let stats = &mut stats.stats;
+        // Usually the last images received.
+        if let Some(i) = stats.images_stamps.iter().rposition(|&s| s == stamp) {
+            if let Some(stages) = stats.images_stages.get_mut(i) {
+                stages.merge = Some(time.as_nanos() as i64);
+            }
+        }
     }
 
     pub fn end_recording(&self) {
-        let mut stats = self.stats.lock().unwrap();
+        let mut stats = self.shared.stats.lock().unwrap();
         stats.recording = false;
-        self.command_sender
-            .send(Command::SaveStats(stats.stats.clone()))
-            .unwrap();
+        self.send_command(Command::SaveStats(Box::new(stats.stats.clone())));
         stats.stats = Default::default();
     }
 
     pub async fn shutdown(self) {
         self.cancel.cancel();
         self.connect_loop.await.unwrap();
+        if let Some(callbacks_loop) = self.callbacks_loop {
+            callbacks_loop.await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn pose_graph_corrections() {
+        let pose = |x: f32| vrrop_common::Pose {
+            translation: [x, 0.0, 0.0],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        };
+        let node = |id, secs, odometry, optimized| vrrop_common::GraphNode {
+            id,
+            stamp: UNIX_EPOCH + Duration::from_secs(secs),
+            odometry: pose(odometry),
+            optimized: pose(optimized),
+        };
+        let graph = decode_pose_graph_message(vrrop_common::PoseGraphMessage {
+            epoch: 1,
+            source: 0,
+            nodes: vec![node(1, 10, 1.0, 1.0), node(2, 20, 2.0, 2.5)],
+            removed: Vec::new(),
+        });
+        let shift = |secs| {
+            graph
+                .correction_at(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap()
+                .translation
+                .x
+        };
+        assert_eq!(shift(0), 0.0);
+        assert_eq!(shift(14), 0.0);
+        assert_eq!(shift(16), 0.5);
+        assert_eq!(shift(30), 0.5);
+        assert!(PoseGraphMessage {
+            epoch: 1,
+            source: 0,
+            nodes: Vec::new()
+        }
+        .correction_at(UNIX_EPOCH)
+        .is_none());
     }
 }
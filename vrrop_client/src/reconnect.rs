@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+/// When to reconnect after a connection can't be made or ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Wait before the first reconnect.
+    pub initial_delay: Duration,
+    /// The wait grows by `multiplier` per failed attempt up to this.
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Up to this fraction of each wait is randomly cut off, so clients that
+    /// lost the same server don't all come back at once. Between 0 and 1.
+    pub jitter: f64,
+    /// Give up after this many failed attempts in a row, retry forever if
+    /// `None`. Attempts count again from zero once connected.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Retries every `delay` forever.
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            initial_delay: delay,
+            max_delay: delay,
+            multiplier: 1.0,
+            jitter: 0.0,
+            max_attempts: None,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Whether to try again after `failures` failed attempts in a row.
+    pub fn should_retry(&self, failures: u32) -> bool {
+        self.max_attempts.is_none_or(|max| failures < max)
+    }
+
+    /// Wait after `failures` failed attempts in a row, at least one.
+    /// `random` in `[0, 1)` picks the jitter. Never more than `max_delay`,
+    /// even with a negative or NaN `multiplier` or `jitter`.
+    pub fn delay(&self, failures: u32, random: f64) -> Duration {
+        let max_delay = self.max_delay.as_secs_f64();
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay =
+            (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent)).min(max_delay);
+        let delay = delay * (1.0 - self.jitter.clamp(0.0, 1.0) * random);
+        if delay.is_nan() {
+            return self.max_delay;
+        }
+        // Near `Duration::MAX` the seconds round up past what fits.
+        Duration::try_from_secs_f64(delay.clamp(0.0, max_delay))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// Like [`delay`](Self::delay) with a random jitter.
+    pub(crate) fn next_delay(&self, failures: u32) -> Duration {
+        let mut bytes = [0; 8];
+        getrandom::getrandom(&mut bytes).expect("Failed to get random bytes");
+        let random = (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64;
+        self.delay(failures, random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: Some(3),
+        };
+        let secs = |failures, random| policy.delay(failures, random).as_secs_f64();
+        assert_eq!(secs(1, 0.0), 1.0);
+        assert_eq!(secs(2, 0.0), 2.0);
+        assert_eq!(secs(3, 0.0), 4.0);
+        assert_eq!(secs(4, 0.0), 5.0);
+        assert_eq!(secs(100, 0.0), 5.0);
+        assert_eq!(secs(2, 0.5), 1.5);
+        assert!(policy.should_retry(2));
+        assert!(!policy.should_retry(3));
+        assert!(ReconnectPolicy::default().should_retry(u32::MAX));
+    }
+
+    #[test]
+    fn bad_policies_dont_panic() {
+        let policy = |multiplier, jitter| ReconnectPolicy {
+            multiplier,
+            jitter,
+            ..ReconnectPolicy::default()
+        };
+        let max = ReconnectPolicy::default().max_delay;
+        assert_eq!(policy(-2.0, 0.0).delay(2, 0.5), Duration::ZERO);
+        assert_eq!(policy(f64::NAN, 0.0).delay(2, 0.5), max);
+        assert_eq!(policy(2.0, f64::NAN).delay(2, 0.5), max);
+        assert_eq!(policy(f64::INFINITY, 0.0).delay(2, 0.5), max);
+        assert_eq!(policy(2.0, 0.2).delay(u32::MAX, 0.5), max.mul_f64(0.9));
+        let forever = ReconnectPolicy {
+            max_delay: Duration::MAX,
+            ..policy(2.0, 0.0)
+        };
+        assert_eq!(forever.delay(u32::MAX, 0.5), Duration::MAX);
+    }
+}
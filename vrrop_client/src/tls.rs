@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use vrrop_common::cert::Fingerprint;
+
+/// Connects with `wss://` instead of `ws://`.
+///
+/// Without pins the server certificate is verified against the bundled web PKI
+/// roots, so it works the same on Android where there is no system store to read.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Name the certificate is issued for. Defaults to the server's IP address.
+    pub server_name: Option<String>,
+    /// Accept only certificates with one of these fingerprints, e.g. self-signed
+    /// ones. The certificate's names and issuer are not checked.
+    pub pins: Vec<Fingerprint>,
+}
+
+impl TlsConfig {
+    pub fn pinned(pin: Fingerprint) -> Self {
+        Self {
+            server_name: None,
+            pins: vec![pin],
+        }
+    }
+
+    pub(crate) fn client_config(&self) -> anyhow::Result<Arc<ClientConfig>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let config = if self.pins.is_empty() {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pins: self.pins.clone(),
+                    provider,
+                }))
+                .with_no_client_auth()
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<Fingerprint>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = Fingerprint::of(end_entity);
+        if self.pins.contains(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server certificate {fingerprint} doesn't match the pinned fingerprint"
+            )))
+        }
+    }
+
+    // The handshake still has to be signed by the pinned certificate's key.
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
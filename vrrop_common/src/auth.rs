@@ -0,0 +1,359 @@
+//! Pre-shared token authentication.
+//!
+//! Websocket clients answer a random challenge from the server with an HMAC of
+//! it. UDP datagrams carry a sender id, a sequence number and a truncated HMAC;
+//! receivers drop datagrams that fail the MAC or don't advance the sender's
+//! sequence number.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const TRAILER_LEN: usize = 8 + 8 + TAG_LEN;
+
+/// Replay state of senders idle for this long is forgotten once there are
+/// [`MAX_SENDERS`]. Their old datagrams would be accepted again, so this is
+/// much longer than senders, which pick a new id per connection, stay idle.
+const SENDER_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const MAX_SENDERS: usize = 1024;
+
+/// Domain separation for the HMACs, so a datagram from one channel or direction
+/// is never accepted on another.
+pub const CONTEXT_WEBSOCKET: &[u8] = b"vrrop websocket";
+pub const CONTEXT_CLIENT: &[u8] = b"vrrop udp client";
+pub const CONTEXT_SERVER: &[u8] = b"vrrop udp server";
+pub const CONTEXT_CONTROL: &[u8] = b"vrrop control";
+
+#[derive(Clone)]
+pub struct AuthKey([u8; 32]);
+
+impl AuthKey {
+    pub fn from_token(token: &str) -> Result<Self> {
+        ensure!(!token.is_empty(), "Auth token must not be empty");
+        Ok(Self(Sha256::digest(token.as_bytes()).into()))
+    }
+
+    fn mac(&self, context: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).unwrap();
+        mac.update(context);
+        mac
+    }
+
+    /// Proof of the key for a websocket challenge.
+    pub fn respond(&self, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+        let mut mac = self.mac(CONTEXT_WEBSOCKET);
+        mac.update(nonce);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    pub fn verify_response(&self, nonce: &[u8; NONCE_LEN], response: &[u8]) -> bool {
+        let mut mac = self.mac(CONTEXT_WEBSOCKET);
+        mac.update(nonce);
+        mac.verify_slice(response).is_ok()
+    }
+}
+
+impl fmt::Debug for AuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthKey(..)")
+    }
+}
+
+pub fn new_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).expect("Failed to get random bytes");
+    nonce
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// Too short to carry a MAC or failed to deserialize.
+    Malformed,
+    /// A websocket client didn't answer the challenge.
+    Unauthenticated,
+    BadMac,
+    /// Already seen, out of order or too old.
+    Replayed,
+}
+
+impl RejectReason {
+    pub const ALL: &'static [RejectReason] = &[
+        RejectReason::Malformed,
+        RejectReason::Unauthenticated,
+        RejectReason::BadMac,
+        RejectReason::Replayed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RejectReason::Malformed => "malformed",
+            RejectReason::Unauthenticated => "unauthenticated",
+            RejectReason::BadMac => "bad_mac",
+            RejectReason::Replayed => "replayed",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Appends `sender id || sequence || tag` to outgoing datagrams. Without a key
+/// datagrams are sent as-is.
+#[derive(Debug)]
+pub struct PacketSealer {
+    key: Option<AuthKey>,
+    context: &'static [u8],
+    sender_id: u64,
+    last_seq: AtomicU64,
+}
+
+impl PacketSealer {
+    pub fn new(key: Option<AuthKey>, context: &'static [u8]) -> Self {
+        let mut sender_id = [0; 8];
+        getrandom::getrandom(&mut sender_id).expect("Failed to get random bytes");
+        Self {
+            key,
+            context,
+            sender_id: u64::from_le_bytes(sender_id),
+            last_seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn seal(&self, mut payload: Vec<u8>) -> Vec<u8> {
+        let Some(key) = &self.key else {
+            return payload;
+        };
+        // Strictly increasing even if the clock goes backwards. Starting from the
+        // clock keeps a sealer that reuses an id ahead of its old datagrams.
+        let now = now_micros();
+        let prev = self
+            .last_seq
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        let seq = now.max(prev + 1);
+        payload.extend_from_slice(&self.sender_id.to_le_bytes());
+        payload.extend_from_slice(&seq.to_le_bytes());
+        let mut mac = key.mac(self.context);
+        mac.update(&payload);
+        payload.extend_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+        payload
+    }
+}
+
+/// Who sealed an authentic datagram and its sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketId {
+    pub sender_id: u64,
+    pub seq: u64,
+}
+
+#[derive(Debug)]
+struct SenderState {
+    last_seq: u64,
+    last_seen: Instant,
+}
+
+/// Verifies datagrams sealed by [`PacketSealer`]. Late datagrams are dropped like
+/// replays; odometry and control commands are useless once a newer one arrived.
+/// Sequence numbers are only compared with the sender's last accepted one, the
+/// sender's clock may be off.
+#[derive(Debug)]
+pub struct PacketOpener {
+    key: Option<AuthKey>,
+    context: &'static [u8],
+    senders: HashMap<u64, SenderState>,
+}
+
+impl PacketOpener {
+    pub fn new(key: Option<AuthKey>, context: &'static [u8]) -> Self {
+        Self {
+            key,
+            context,
+            senders: HashMap::new(),
+        }
+    }
+
+    /// Returns the payload of an authentic datagram.
+    pub fn open<'a>(&mut self, packet: &'a [u8]) -> Result<&'a [u8], RejectReason> {
+        let (payload, id) = self.authenticate(packet)?;
+        let Some(PacketId { sender_id, seq }) = id else {
+            return Ok(payload);
+        };
+        let now = Instant::now();
+        if let Some(sender) = self.senders.get(&sender_id) {
+            if seq <= sender.last_seq {
+                return Err(RejectReason::Replayed);
+            }
+        } else if self.senders.len() >= MAX_SENDERS {
+            self.senders
+                .retain(|_, s| now.duration_since(s.last_seen) < SENDER_IDLE_TIMEOUT);
+            if self.senders.len() >= MAX_SENDERS {
+                // Forgetting an active sender would let its datagrams be replayed.
+                return Err(RejectReason::Replayed);
+            }
+        }
+        self.senders.insert(
+            sender_id,
+            SenderState {
+                last_seq: seq,
+                last_seen: now,
+            },
+        );
+        Ok(payload)
+    }
+
+    /// Returns the payload of an authentic datagram and its id, without
+    /// checking for replays. For receivers that keep the replay state along
+    /// with other state of the sender, so it goes away with it. Without a key
+    /// there is no id.
+    pub fn authenticate<'a>(
+        &self,
+        packet: &'a [u8],
+    ) -> Result<(&'a [u8], Option<PacketId>), RejectReason> {
+        let Some(key) = &self.key else {
+            return Ok((packet, None));
+        };
+        if packet.len() < TRAILER_LEN {
+            return Err(RejectReason::Malformed);
+        }
+        let (signed, tag) = packet.split_at(packet.len() - TAG_LEN);
+        let mut mac = key.mac(self.context);
+        mac.update(signed);
+        mac.verify_truncated_left(tag)
+            .map_err(|_| RejectReason::BadMac)?;
+        let (payload, trailer) = signed.split_at(signed.len() - 16);
+        let sender_id = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let seq = u64::from_le_bytes(trailer[8..].try_into().unwrap());
+        Ok((payload, Some(PacketId { sender_id, seq })))
+    }
+}
+
+/// Counts rejected datagrams and logs them at most once per second, so a flood
+/// doesn't drown the log.
+#[derive(Debug)]
+pub struct RejectLog {
+    name: &'static str,
+    counts: HashMap<RejectReason, u64>,
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RejectLog {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            counts: HashMap::new(),
+            last_logged: None,
+            suppressed: 0,
+        }
+    }
+
+    pub fn record(&mut self, peer: SocketAddr, reason: RejectReason) {
+        *self.counts.entry(reason).or_default() += 1;
+        let now = Instant::now();
+        if self
+            .last_logged
+            .is_some_and(|t| now.duration_since(t) < Duration::from_secs(1))
+        {
+            self.suppressed += 1;
+            return;
+        }
+        if self.suppressed > 0 {
+            eprintln!(
+                "{}: rejected packet from {peer} ({reason}), {} more suppressed",
+                self.name, self.suppressed
+            );
+        } else {
+            eprintln!("{}: rejected packet from {peer} ({reason})", self.name);
+        }
+        self.last_logged = Some(now);
+        self.suppressed = 0;
+    }
+
+    pub fn count(&self, reason: RejectReason) -> u64 {
+        self.counts.get(&reason).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_are_authenticated_and_not_replayable() {
+        let key = AuthKey::from_token("secret").unwrap();
+        let sealer = PacketSealer::new(Some(key.clone()), CONTEXT_CLIENT);
+        let mut opener = PacketOpener::new(Some(key.clone()), CONTEXT_CLIENT);
+
+        let first = sealer.seal(b"first".to_vec());
+        let second = sealer.seal(b"second".to_vec());
+        assert_eq!(opener.open(&first), Ok(&b"first"[..]));
+        assert_eq!(opener.open(&first), Err(RejectReason::Replayed));
+        assert_eq!(opener.open(&second), Ok(&b"second"[..]));
+
+        let mut tampered = sealer.seal(b"third".to_vec());
+        tampered[0] ^= 1;
+        assert_eq!(opener.open(&tampered), Err(RejectReason::BadMac));
+        assert_eq!(opener.open(b"short"), Err(RejectReason::Malformed));
+
+        let wrong_key =
+            PacketSealer::new(Some(AuthKey::from_token("guess").unwrap()), CONTEXT_CLIENT);
+        let forged = wrong_key.seal(b"forged".to_vec());
+        assert_eq!(opener.open(&forged), Err(RejectReason::BadMac));
+
+        // Server datagrams can't be reflected back as client datagrams.
+        let server = PacketSealer::new(Some(key), CONTEXT_SERVER);
+        let reflected = server.seal(b"odometry".to_vec());
+        assert_eq!(opener.open(&reflected), Err(RejectReason::BadMac));
+    }
+
+    #[test]
+    fn sender_clock_may_be_off() {
+        let key = AuthKey::from_token("secret").unwrap();
+        let sealer = PacketSealer::new(Some(key.clone()), CONTEXT_CLIENT);
+        let mut opener = PacketOpener::new(Some(key), CONTEXT_CLIENT);
+        // A day ahead of the receiver.
+        sealer
+            .last_seq
+            .store(now_micros() + 24 * 3600 * 1_000_000, Ordering::Relaxed);
+        let first = sealer.seal(b"first".to_vec());
+        assert_eq!(opener.open(&first), Ok(&b"first"[..]));
+        assert_eq!(opener.open(&first), Err(RejectReason::Replayed));
+    }
+
+    #[test]
+    fn challenge_response() {
+        let key = AuthKey::from_token("secret").unwrap();
+        let nonce = new_nonce();
+        let response = key.respond(&nonce);
+        assert!(key.verify_response(&nonce, &response));
+        assert!(!key.verify_response(&new_nonce(), &response));
+        assert!(!AuthKey::from_token("guess")
+            .unwrap()
+            .verify_response(&nonce, &response));
+    }
+}
@@ -8,7 +8,10 @@ use std::{
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::{CameraIntrinsics, ImagesMessage, OdometryMessage};
+use crate::{
+    codec::{ColorCodec, DepthCodec},
+    CameraIntrinsics, ImagesMessage, OdometryMessage,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 enum Entry {
@@ -35,8 +38,12 @@ struct ImagesEntry {
     odometry: OdometryMessage,
     color_intrinsics: CameraIntrinsics,
     color_image_path: PathBuf,
+    #[serde(default)]
+    color_codec: ColorCodec,
     depth_intrinsics: CameraIntrinsics,
     depth_image_path: PathBuf,
+    #[serde(default)]
+    depth_codec: DepthCodec,
     depth_unit: f32,
 }
 
@@ -46,12 +53,17 @@ pub struct Recorder {
     entries_file: File,
 }
 
+/// Names of the sources, indexed by [`crate::SourceId`].
+const SOURCES_FILE: &str = "sources.json";
+
 impl Recorder {
-    pub fn new(dest_dir: impl AsRef<Path>) -> Result<Self> {
+    /// Records messages of all `sources`, named in the order of their ids.
+    pub fn new(dest_dir: impl AsRef<Path>, sources: &[String]) -> Result<Self> {
         let dest_dir = dest_dir.as_ref().to_path_buf();
         fs::create_dir_all(&dest_dir)?;
         let entries_file = fs::File::create(dest_dir.join("entries.jsonl"))?;
         fs::create_dir(dest_dir.join("images"))?;
+        fs::write(dest_dir.join(SOURCES_FILE), serde_json::to_string(sources)?)?;
         Ok(Self {
             dest_dir,
             entries_file,
@@ -65,16 +77,25 @@ impl Recorder {
             .stamp
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis();
-        let color_image_path = img_dir.join(format!("color_{stamp}.jpg"));
-        let depth_image_path = img_dir.join(format!("depth_{stamp}.png"));
+        let source = msg.odometry.source;
+        let color_image_path = img_dir.join(format!(
+            "color_{source}_{stamp}.{}",
+            msg.color_codec.extension()
+        ));
+        let depth_image_path = img_dir.join(format!(
+            "depth_{source}_{stamp}.{}",
+            msg.depth_codec.extension()
+        ));
         fs::File::create(self.dest_dir.join(&color_image_path))?.write_all(&msg.color_image)?;
         fs::File::create(self.dest_dir.join(&depth_image_path))?.write_all(&msg.depth_image)?;
         let entry = ImagesEntry {
             odometry: msg.odometry.clone(),
             color_intrinsics: msg.color_intrinsics,
             color_image_path,
+            color_codec: msg.color_codec,
             depth_intrinsics: msg.depth_intrinsics,
             depth_image_path,
+            depth_codec: msg.depth_codec,
             depth_unit: msg.depth_unit,
         };
         let mut serialized = serde_json::to_string(&Entry::Images(entry))?;
@@ -106,6 +127,7 @@ pub struct Player {
     start_instant: Instant,
     start_time: SystemTime,
     bag_dir: PathBuf,
+    sources: Vec<String>,
 }
 
 impl Player {
@@ -120,6 +142,12 @@ impl Player {
             .first()
             .ok_or_else(|| anyhow::anyhow!("No entry found"))?
             .stamp();
+        // Bags of a single camera have no source names.
+        let sources = match fs::read_to_string(bag_dir.join(SOURCES_FILE)) {
+            Ok(sources) => serde_json::from_str(&sources)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
         Ok(Self {
             entries,
             idx: 0,
@@ -127,9 +155,15 @@ impl Player {
             start_instant: Instant::now(),
             start_time: SystemTime::now(),
             bag_dir,
+            sources,
         })
     }
 
+    /// Names of the recorded sources, empty for bags of a single camera.
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
     pub fn poll_next_event_time(&self) -> Option<Instant> {
         let event = self.next_entry()?;
         Some(self.start_instant + (event.stamp().duration_since(self.first_stamp).unwrap()))
@@ -160,10 +194,14 @@ impl Player {
                 let msg = ImagesMessage {
                     odometry: entry.odometry.clone(),
                     color_image: fs::read(self.bag_dir.join(entry.color_image_path))?,
+                    color_codec: entry.color_codec,
                     color_intrinsics: entry.color_intrinsics,
                     depth_image: fs::read(self.bag_dir.join(entry.depth_image_path))?,
+                    depth_codec: entry.depth_codec,
                     depth_intrinsics: entry.depth_intrinsics,
                     depth_unit: entry.depth_unit,
+                    // Those of the recording don't apply to the replay.
+                    timings: Default::default(),
                 };
                 Ok(Some(Event::Images(msg)))
             }
@@ -0,0 +1,69 @@
+//! Certificate fingerprints used to pin the server's TLS certificate.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, ensure, Result};
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of a DER encoded certificate. Written like
+/// `openssl x509 -noout -fingerprint -sha256` prints it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl Fingerprint {
+    pub fn of(der: &[u8]) -> Self {
+        Self(Sha256::digest(der).into())
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts hex digits with or without colons, in either case.
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix("sha256:").unwrap_or(s);
+        let digits: Vec<u8> = s.bytes().filter(|&b| b != b':').collect();
+        ensure!(
+            digits.len() == 64,
+            "Expected 32 hex encoded bytes, got {} digits",
+            digits.len()
+        );
+        let mut fingerprint = [0; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            let Ok(hex) = std::str::from_utf8(pair) else {
+                bail!("Invalid hex digit in fingerprint");
+            };
+            *byte = u8::from_str_radix(hex, 16)?;
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_roundtrip() {
+        let fingerprint = Fingerprint::of(b"certificate");
+        let text = fingerprint.to_string();
+        assert_eq!(text.len(), 32 * 3 - 1);
+        assert_eq!(text.parse::<Fingerprint>().unwrap(), fingerprint);
+        let plain = text.replace(':', "").to_lowercase();
+        assert_eq!(plain.parse::<Fingerprint>().unwrap(), fingerprint);
+        assert!("AB:CD".parse::<Fingerprint>().is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, qoi::QoiEncoder, webp::WebPEncoder},
+    EncodableLayout, ExtendedColorType, ImageBuffer, ImageEncoder, ImageFormat, Luma, Rgb,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::rvl;
+
+pub type ColorImage = ImageBuffer<Rgb<u8>, Vec<u8>>;
+pub type DepthImage = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+/// Largest depth image decoded, so a bad header can't make us allocate
+/// gigabytes.
+pub const MAX_DEPTH_PIXELS: usize = 4096 * 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ColorCodec {
+    #[default]
+    Jpeg,
+    /// Lossless WebP.
+    WebP,
+    Qoi,
+    Png,
+}
+
+impl ColorCodec {
+    pub const ALL: &'static [ColorCodec] = &[
+        ColorCodec::Jpeg,
+        ColorCodec::WebP,
+        ColorCodec::Qoi,
+        ColorCodec::Png,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ColorCodec::Jpeg => "jpg",
+            ColorCodec::WebP => "webp",
+            ColorCodec::Qoi => "qoi",
+            ColorCodec::Png => "png",
+        }
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        !matches!(self, ColorCodec::Jpeg)
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            ColorCodec::Jpeg => ImageFormat::Jpeg,
+            ColorCodec::WebP => ImageFormat::WebP,
+            ColorCodec::Qoi => ImageFormat::Qoi,
+            ColorCodec::Png => ImageFormat::Png,
+        }
+    }
+}
+
+impl std::fmt::Display for ColorCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ColorCodec::Jpeg => "jpeg",
+            ColorCodec::WebP => "webp",
+            ColorCodec::Qoi => "qoi",
+            ColorCodec::Png => "png",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for ColorCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ColorCodec::ALL
+            .iter()
+            .find(|codec| codec.to_string() == s)
+            .copied()
+            .with_context(|| format!("Unknown color codec: {s}"))
+    }
+}
+
+/// Color codecs this build can decode, advertised to the server in the handshake.
+pub fn supported_color_codecs() -> Vec<ColorCodec> {
+    ColorCodec::ALL.to_vec()
+}
+
+/// Encodes an RGB8 image. `jpeg_quality` is ignored by lossless codecs.
+pub fn encode_color(
+    codec: ColorCodec,
+    rgb: &[u8],
+    width: u32,
+    height: u32,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>> {
+    let len = width as usize * height as usize * 3;
+    ensure!(rgb.len() >= len, "Color buffer is smaller than the image");
+    let rgb = &rgb[..len];
+    let mut dst = Vec::new();
+    match codec {
+        ColorCodec::Jpeg => JpegEncoder::new_with_quality(&mut dst, jpeg_quality).write_image(
+            rgb,
+            width,
+            height,
+            ExtendedColorType::Rgb8,
+        )?,
+        ColorCodec::WebP => WebPEncoder::new_lossless(&mut dst).write_image(
+            rgb,
+            width,
+            height,
+            ExtendedColorType::Rgb8,
+        )?,
+        ColorCodec::Qoi => {
+            QoiEncoder::new(&mut dst).write_image(rgb, width, height, ExtendedColorType::Rgb8)?
+        }
+        ColorCodec::Png => {
+            PngEncoder::new(&mut dst).write_image(rgb, width, height, ExtendedColorType::Rgb8)?
+        }
+    }
+    Ok(dst)
+}
+
+pub fn decode_color(codec: ColorCodec, data: &[u8]) -> Result<ColorImage> {
+    Ok(image::load_from_memory_with_format(data, codec.image_format())?.to_rgb8())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum DepthCodec {
+    #[default]
+    Png,
+    Rvl,
+    /// zstd of the row-wise delta of the depth image.
+    ZstdDelta,
+    /// Like [`DepthCodec::ZstdDelta`], but the depth is quantized first so the
+    /// reconstruction error stays within a configured number of millimetres.
+    ZstdQuantized,
+}
+
+impl DepthCodec {
+    pub const ALL: &'static [DepthCodec] = &[
+        DepthCodec::Png,
+        DepthCodec::Rvl,
+        DepthCodec::ZstdDelta,
+        DepthCodec::ZstdQuantized,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DepthCodec::Png => "png",
+            DepthCodec::Rvl => "rvl",
+            DepthCodec::ZstdDelta | DepthCodec::ZstdQuantized => "zst",
+        }
+    }
+}
+
+impl std::fmt::Display for DepthCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DepthCodec::Png => "png",
+            DepthCodec::Rvl => "rvl",
+            DepthCodec::ZstdDelta => "zstd-delta",
+            DepthCodec::ZstdQuantized => "zstd-quantized",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for DepthCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        DepthCodec::ALL
+            .iter()
+            .find(|codec| codec.to_string() == s)
+            .copied()
+            .with_context(|| format!("Unknown depth codec: {s}"))
+    }
+}
+
+/// Depth codecs this build can decode, advertised to the server in the handshake.
+pub fn supported_depth_codecs() -> Vec<DepthCodec> {
+    DepthCodec::ALL.to_vec()
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Encodes a depth image in millimetres. `max_error_mm` is only used by lossy codecs.
+pub fn encode_depth(
+    codec: DepthCodec,
+    depth: &[u16],
+    width: u32,
+    height: u32,
+    max_error_mm: u16,
+) -> Result<Vec<u8>> {
+    let len = width as usize * height as usize;
+    ensure!(depth.len() >= len, "Depth buffer is smaller than the image");
+    let depth = &depth[..len];
+    match codec {
+        DepthCodec::Png => {
+            let mut dst = Vec::new();
+            PngEncoder::new(&mut dst).write_image(
+                depth.as_bytes(),
+                width,
+                height,
+                ExtendedColorType::L16,
+            )?;
+            Ok(dst)
+        }
+        DepthCodec::Rvl => {
+            let mut dst = header(width, height, 0);
+            dst.extend(rvl::compress(depth));
+            Ok(dst)
+        }
+        DepthCodec::ZstdDelta => {
+            let mut dst = header(width, height, 0);
+            dst.extend(zstd::bulk::compress(&row_delta(depth, width), ZSTD_LEVEL)?);
+            Ok(dst)
+        }
+        DepthCodec::ZstdQuantized => {
+            let step = 2 * max_error_mm as u32 + 1;
+            let quantized: Vec<u16> = depth
+                .iter()
+                .map(|&d| {
+                    if d == 0 {
+                        0
+                    } else {
+                        ((d as u32 - 1) / step + 1) as u16
+                    }
+                })
+                .collect();
+            let mut dst = header(width, height, max_error_mm);
+            dst.extend(zstd::bulk::compress(
+                &row_delta(&quantized, width),
+                ZSTD_LEVEL,
+            )?);
+            Ok(dst)
+        }
+    }
+}
+
+pub fn decode_depth(codec: DepthCodec, data: &[u8]) -> Result<DepthImage> {
+    if codec == DepthCodec::Png {
+        return Ok(image::load_from_memory(data)?.to_luma16());
+    }
+    let (width, height, max_error_mm, payload) = parse_header(data)?;
+    let len = width as usize * height as usize;
+    let depth = match codec {
+        DepthCodec::Png => unreachable!(),
+        DepthCodec::Rvl => rvl::decompress(payload, len)?,
+        DepthCodec::ZstdDelta => undo_row_delta(&decompress_zstd(payload, len * 2)?, width)?,
+        DepthCodec::ZstdQuantized => {
+            let step = 2 * max_error_mm as u32 + 1;
+            undo_row_delta(&decompress_zstd(payload, len * 2)?, width)?
+                .into_iter()
+                .map(|q| {
+                    if q == 0 {
+                        0
+                    } else {
+                        ((q as u32 - 1) * step + 1 + max_error_mm as u32).min(u16::MAX as u32)
+                            as u16
+                    }
+                })
+                .collect()
+        }
+    };
+    ensure!(depth.len() == len, "Decoded depth has the wrong size");
+    DepthImage::from_raw(width, height, depth).context("Invalid depth image")
+}
+
+fn header(width: u32, height: u32, max_error_mm: u16) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(10);
+    dst.extend_from_slice(&width.to_le_bytes());
+    dst.extend_from_slice(&height.to_le_bytes());
+    dst.extend_from_slice(&max_error_mm.to_le_bytes());
+    dst
+}
+
+fn parse_header(data: &[u8]) -> Result<(u32, u32, u16, &[u8])> {
+    if data.len() < 10 {
+        bail!("Depth data is too short");
+    }
+    let width = u32::from_le_bytes(data[0..4].try_into()?);
+    let height = u32::from_le_bytes(data[4..8].try_into()?);
+    let max_error_mm = u16::from_le_bytes(data[8..10].try_into()?);
+    ensure!(width > 0 && height > 0, "Depth image is {width}x{height}");
+    ensure!(
+        width as u64 * height as u64 <= MAX_DEPTH_PIXELS as u64,
+        "Depth image of {width}x{height} is too large"
+    );
+    Ok((width, height, max_error_mm, &data[10..]))
+}
+
+/// Checks the size the frame claims before allocating for it.
+fn decompress_zstd(payload: &[u8], size: usize) -> Result<Vec<u8>> {
+    let content_size = zstd::zstd_safe::get_frame_content_size(payload)
+        .map_err(|_| anyhow!("Invalid zstd frame"))?;
+    ensure!(
+        content_size == Some(size as u64),
+        "Depth data has {content_size:?} bytes, expected {size}"
+    );
+    Ok(zstd::bulk::decompress(payload, size)?)
+}
+
+fn row_delta(depth: &[u16], width: u32) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(depth.len() * 2);
+    for row in depth.chunks(width as usize) {
+        let mut prev = 0u16;
+        for &d in row {
+            dst.extend_from_slice(&d.wrapping_sub(prev).to_le_bytes());
+            prev = d;
+        }
+    }
+    dst
+}
+
+fn undo_row_delta(data: &[u8], width: u32) -> Result<Vec<u16>> {
+    ensure!(
+        data.len().is_multiple_of(2),
+        "Odd number of bytes in depth data"
+    );
+    let mut depth = Vec::with_capacity(data.len() / 2);
+    for row in data.chunks(width as usize * 2) {
+        let mut prev = 0u16;
+        for delta in row.chunks_exact(2) {
+            prev = prev.wrapping_add(u16::from_le_bytes([delta[0], delta[1]]));
+            depth.push(prev);
+        }
+    }
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_depth() -> (Vec<u16>, u32, u32) {
+        let (width, height) = (64, 48);
+        let depth = (0..width * height)
+            .map(|i| {
+                if i % 11 == 0 {
+                    0
+                } else {
+                    (500 + i % 300) as u16
+                }
+            })
+            .collect();
+        (depth, width, height)
+    }
+
+    #[test]
+    fn lossless_color_codecs_roundtrip() {
+        let (width, height) = (32, 24);
+        let rgb: Vec<u8> = (0..width * height * 3)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        for &codec in ColorCodec::ALL.iter().filter(|c| c.is_lossless()) {
+            let encoded = encode_color(codec, &rgb, width, height, 0).unwrap();
+            let decoded = decode_color(codec, &encoded).unwrap();
+            assert_eq!(decoded.into_raw(), rgb, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn lossless_codecs_roundtrip() {
+        let (depth, width, height) = test_depth();
+        for codec in [DepthCodec::Png, DepthCodec::Rvl, DepthCodec::ZstdDelta] {
+            let encoded = encode_depth(codec, &depth, width, height, 0).unwrap();
+            let decoded = decode_depth(codec, &encoded).unwrap();
+            assert_eq!(decoded.dimensions(), (width, height));
+            assert_eq!(decoded.into_raw(), depth, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn quantized_error_is_bounded() {
+        let (depth, width, height) = test_depth();
+        let max_error_mm = 5;
+        let encoded = encode_depth(
+            DepthCodec::ZstdQuantized,
+            &depth,
+            width,
+            height,
+            max_error_mm,
+        )
+        .unwrap();
+        let decoded = decode_depth(DepthCodec::ZstdQuantized, &encoded).unwrap();
+        for (&orig, &dec) in depth.iter().zip(decoded.as_raw()) {
+            assert_eq!(orig == 0, dec == 0);
+            assert!(orig.abs_diff(dec) <= max_error_mm);
+        }
+    }
+
+    #[test]
+    fn bad_sizes_are_rejected() {
+        let (depth, width, height) = test_depth();
+        for codec in [DepthCodec::Rvl, DepthCodec::ZstdDelta] {
+            let encoded = encode_depth(codec, &depth, width, height, 0).unwrap();
+            let with_size = |width: u32, height: u32| {
+                let mut data = encoded.clone();
+                data[0..4].copy_from_slice(&width.to_le_bytes());
+                data[4..8].copy_from_slice(&height.to_le_bytes());
+                data
+            };
+            for (w, h) in [
+                (0, height),
+                (width, 0),
+                (u32::MAX, u32::MAX),
+                (width, height * 2),
+            ] {
+                assert!(
+                    decode_depth(codec, &with_size(w, h)).is_err(),
+                    "{codec:?} {w}x{h}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Messages as the clients and the server's fusion use them: images decoded,
+//! poses in nalgebra types.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+use image::{ImageBuffer, Luma, Rgb};
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion, Vector3, Vector4};
+
+use crate::{codec, CameraIntrinsics, ImageStages, ImageTimings, SourceId};
+
+#[derive(Debug, Copy, Clone)]
+pub struct OdometryMessage {
+    pub original_size: usize,
+    pub epoch: u64,
+    /// Index of the camera in [`WelcomeMessage::sources`](crate::WelcomeMessage::sources).
+    pub source: SourceId,
+    pub stamp: std::time::SystemTime,
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+}
+
+/// A node of the server's SLAM map after a loop closure.
+#[derive(Debug, Copy, Clone)]
+pub struct GraphNode {
+    pub id: i32,
+    /// Stamp of the odometry message of the node's frame.
+    pub stamp: std::time::SystemTime,
+    /// Moves poses received around `stamp` to where the optimized map has them.
+    pub correction: Isometry3<f32>,
+}
+
+/// Loop closure corrections of poses already received.
+#[derive(Debug, Clone)]
+pub struct PoseGraphMessage {
+    pub epoch: u64,
+    pub source: SourceId,
+    /// Sorted by stamp.
+    pub nodes: Vec<GraphNode>,
+}
+
+impl PoseGraphMessage {
+    /// Correction of the node closest in time to `stamp`.
+    pub fn correction_at(&self, stamp: SystemTime) -> Option<Isometry3<f32>> {
+        let i = self.nodes.partition_point(|node| node.stamp < stamp);
+        let distance = |node: &GraphNode| {
+            node.stamp
+                .duration_since(stamp)
+                .unwrap_or_else(|e| e.duration())
+        };
+        let after = self.nodes.get(i);
+        let before = i.checked_sub(1).map(|i| &self.nodes[i]);
+        match (before, after) {
+            (Some(before), Some(after)) if distance(before) <= distance(after) => Some(before),
+            (_, Some(after)) => Some(after),
+            (before, None) => before,
+        }
+        .map(|node| node.correction)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImagesMessage {
+    pub original_size: usize,
+    pub odometry: OdometryMessage,
+    pub color: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    pub color_intrinsics: CameraIntrinsics,
+    pub depth: ImageBuffer<Luma<u16>, Vec<u16>>,
+    pub depth_intrinsics: CameraIntrinsics,
+    pub depth_unit: f32,
+    pub timings: ImageTimings,
+    /// Stages up to decoding, clients add merging.
+    pub stages: ImageStages,
+}
+
+/// Decodes color and depth in parallel. Blocks for a while.
+pub fn decode_images_message(
+    compressed: crate::ImagesMessage,
+    original_size: usize,
+) -> Result<ImagesMessage> {
+    let (color, depth) = std::thread::scope(|scope| {
+        let depth =
+            scope.spawn(|| codec::decode_depth(compressed.depth_codec, &compressed.depth_image));
+        let color = codec::decode_color(compressed.color_codec, &compressed.color_image);
+        (color, depth.join().expect("Depth decoding panicked"))
+    });
+    Ok(ImagesMessage {
+        original_size,
+        odometry: decode_odometry_message(compressed.odometry, 0),
+        color: color?,
+        color_intrinsics: compressed.color_intrinsics,
+        depth: depth?,
+        depth_intrinsics: compressed.depth_intrinsics,
+        depth_unit: compressed.depth_unit,
+        timings: compressed.timings,
+        stages: ImageStages::default(),
+    })
+}
+
+pub fn decode_odometry_message(
+    raw: crate::OdometryMessage,
+    original_size: usize,
+) -> OdometryMessage {
+    OdometryMessage {
+        original_size,
+        epoch: raw.epoch,
+        source: raw.source,
+        stamp: raw.stamp,
+        translation: Vector3::from_row_slice(&raw.translation),
+        rotation: UnitQuaternion::new_normalize(Quaternion::from_vector(Vector4::from_row_slice(
+            &raw.rotation,
+        ))),
+    }
+}
+
+fn decode_pose(pose: &crate::Pose) -> Isometry3<f32> {
+    Isometry3::from_parts(
+        Translation3::from(Vector3::from_row_slice(&pose.translation)),
+        UnitQuaternion::new_normalize(Quaternion::from_vector(Vector4::from_row_slice(
+            &pose.rotation,
+        ))),
+    )
+}
+
+pub fn decode_pose_graph_message(raw: crate::PoseGraphMessage) -> PoseGraphMessage {
+    PoseGraphMessage {
+        epoch: raw.epoch,
+        source: raw.source,
+        nodes: raw
+            .nodes
+            .iter()
+            .map(|node| GraphNode {
+                id: node.id,
+                stamp: node.stamp,
+                correction: decode_pose(&node.optimized) * decode_pose(&node.odometry).inverse(),
+            })
+            .collect(),
+    }
+}
@@ -1,10 +1,23 @@
 use serde::{Deserialize, Serialize};
 
+pub mod auth;
 pub mod bag;
+pub mod cert;
+pub mod codec;
+pub mod decoded;
+pub mod pointcloud;
+mod rvl;
+pub mod stats;
+
+use codec::{ColorCodec, DepthCodec};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UdpClientMessage {
     Ping(PingMessage),
+    /// Asks for odometry on the sending address. Repeated as a keepalive; the
+    /// server drops subscriptions it hasn't heard from for a few seconds.
+    Subscribe(SubscribeMessage),
+    Leave(LeaveMessage),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,17 +28,40 @@ pub enum UdpServerMessage {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PingMessage {
+    pub session_token: SessionToken,
     pub client_time: std::time::SystemTime,
 }
 
+/// Ties UDP datagrams to the websocket session the token was issued to.
+pub type SessionToken = [u8; 16];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeMessage {
+    pub session_token: SessionToken,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaveMessage {
+    pub session_token: SessionToken,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PongMessage {
     pub client_time: std::time::SystemTime,
     pub server_time: std::time::SystemTime,
 }
 
+/// Index of a camera in [`WelcomeMessage::sources`].
+pub type SourceId = u16;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OdometryMessage {
+    /// Map epoch the pose belongs to, see [`MapResetMessage`].
+    #[serde(default)]
+    pub epoch: u64,
+    /// Camera the pose is of. Images carry it in their odometry.
+    #[serde(default)]
+    pub source: SourceId,
     pub stamp: std::time::SystemTime,
     pub translation: [f32; 3],
     pub rotation: [f32; 4],
@@ -35,10 +71,130 @@ pub struct OdometryMessage {
 pub struct ImagesMessage {
     pub odometry: OdometryMessage,
     pub color_image: Vec<u8>,
+    pub color_codec: ColorCodec,
     pub color_intrinsics: CameraIntrinsics,
     pub depth_image: Vec<u8>,
+    pub depth_codec: DepthCodec,
     pub depth_intrinsics: CameraIntrinsics,
     pub depth_unit: f32,
+    /// Must stay the last field, see [`ImageTimings::send`].
+    pub timings: ImageTimings,
+}
+
+/// When an images message passed each stage of the server's pipeline, in
+/// server time. Stages that weren't measured are `None`, e.g. those of frames
+/// replayed from a bag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageTimings {
+    /// The camera's stamp of the frame.
+    pub capture: Option<std::time::SystemTime>,
+    /// When SLAM published the pose of the frame.
+    pub odometry: Option<std::time::SystemTime>,
+    pub encode_start: Option<std::time::SystemTime>,
+    pub encode_end: Option<std::time::SystemTime>,
+    /// When the message was written to the client's websocket. Messages are
+    /// encoded once for all clients, so this is patched into the serialized
+    /// message, which is why it must be last.
+    pub send: Option<std::time::SystemTime>,
+}
+
+/// Time an images message spent in each stage of the pipeline, in
+/// nanoseconds. `None` where either end of the stage wasn't measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageStages {
+    /// Capture to odometry.
+    pub slam: Option<i64>,
+    /// Odometry to encode start, waiting in the broadcast channel and for
+    /// congestion control.
+    pub queue: Option<i64>,
+    pub encode: Option<i64>,
+    /// Encode end to send, waiting in the client's send queue.
+    pub send_queue: Option<i64>,
+    /// Send to receive by the client, depends on the clock sync.
+    pub network: Option<i64>,
+    pub decode: Option<i64>,
+    /// Merging into the client's point cloud, reported by the application.
+    pub merge: Option<i64>,
+}
+
+impl ImageStages {
+    pub const NAMES: [&'static str; 7] = [
+        "slam",
+        "queue",
+        "encode",
+        "send_queue",
+        "network",
+        "decode",
+        "merge",
+    ];
+
+    /// `received` is in server time.
+    pub fn new(
+        timings: &ImageTimings,
+        received: std::time::SystemTime,
+        decode: std::time::Duration,
+    ) -> Self {
+        let between = |from: Option<std::time::SystemTime>, to: Option<std::time::SystemTime>| {
+            let (from, to) = (from?, to?);
+            Some(match to.duration_since(from) {
+                Ok(d) => d.as_nanos() as i64,
+                Err(e) => -(e.duration().as_nanos() as i64),
+            })
+        };
+        Self {
+            slam: between(timings.capture, timings.odometry),
+            queue: between(timings.odometry, timings.encode_start),
+            encode: between(timings.encode_start, timings.encode_end),
+            send_queue: between(timings.encode_end, timings.send),
+            network: between(timings.send, Some(received)),
+            decode: Some(decode.as_nanos() as i64),
+            merge: None,
+        }
+    }
+
+    /// In the order of [`Self::NAMES`].
+    pub fn values(&self) -> [Option<i64>; 7] {
+        [
+            self.slam,
+            self.queue,
+            self.encode,
+            self.send_queue,
+            self.network,
+            self.decode,
+            self.merge,
+        ]
+    }
+}
+
+/// Changes to the point cloud fused on the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapDeltaMessage {
+    /// See [`MapResetMessage`].
+    pub epoch: u64,
+    /// Each source has a map of its own.
+    pub source: SourceId,
+    pub grid_size: f32,
+    /// The cells make up the whole map of the source and replace whatever the
+    /// client had.
+    pub snapshot: bool,
+    pub cells: Vec<GridCellDelta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GridCellDelta {
+    pub index: [i32; 3],
+    pub removed: Vec<QuantizedPoint>,
+    pub added: Vec<QuantizedPoint>,
+}
+
+/// A point relative to the corner of its grid cell.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QuantizedPoint {
+    /// Offset within the cell in units of `grid_size / 65535`.
+    pub position: [u16; 3],
+    pub color: [u8; 3],
+    /// Point size in units of 10 µm.
+    pub size: u16,
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -51,10 +207,222 @@ pub struct CameraIntrinsics {
     pub cy: f32,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WebSocketServerMessage {
+    /// First message sent by the server after accepting a connection.
+    Challenge(ChallengeMessage),
+    /// Accepts the client's hello.
+    Welcome(WelcomeMessage),
+    Images(ImagesMessage),
+    MapDelta(MapDeltaMessage),
+    Dropped(DroppedMessage),
+    /// The server is about to close the connection.
+    Goodbye(GoodbyeMessage),
+    /// The map was restarted, e.g. after a SLAM reset. Clients should drop what
+    /// they accumulated so far.
+    MapReset(MapResetMessage),
+    /// Text to show to the user.
+    Notice(NoticeMessage),
+    /// The server's camera stopped delivering frames or came back.
+    CameraStatus(CameraStatusMessage),
+    /// Saved maps, after [`Command::ListMaps`] and whenever they change.
+    Maps(MapsMessage),
+    /// SLAM of the first source found its place in the loaded map.
+    Relocalized(RelocalizedMessage),
+    /// SLAM corrected past poses after a loop closure.
+    PoseGraph(PoseGraphMessage),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WebSocketClientMessage {
+    Hello(HelloMessage),
+    Command(Command),
+    Feedback(FeedbackMessage),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeMessage {
+    pub nonce: [u8; auth::NONCE_LEN],
+}
+
+/// Sent before the next message when the server dropped messages because the
+/// client couldn't keep up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DroppedMessage {
+    /// Dropped since the previous notice.
+    pub messages: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoodbyeMessage {
+    pub reason: String,
+    /// When to reconnect, if the server expects to come back.
+    pub retry_after: Option<std::time::Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraStatusMessage {
+    pub source: SourceId,
+    pub connected: bool,
+    /// What happened, for the user.
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapsMessage {
+    pub maps: Vec<MapInfo>,
+    /// The map SLAM is localizing in, if any.
+    pub loaded: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapInfo {
+    pub name: String,
+    /// Size of the database in bytes.
+    pub size: u64,
+    pub modified: std::time::SystemTime,
+}
+
+/// Poses of `epoch` are in the frame of the saved map `map`, so a point cloud
+/// stored for that map lines up with them again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelocalizedMessage {
+    pub map: String,
+    pub source: SourceId,
+    pub epoch: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pose {
+    pub translation: [f32; 3],
+    /// Quaternion as `[x, y, z, w]`, like [`OdometryMessage::rotation`].
+    pub rotation: [f32; 4],
+}
+
+/// A keyframe of the SLAM map, tied to the odometry message of its frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GraphNode {
+    pub id: i32,
+    /// Stamp of the odometry message.
+    pub stamp: std::time::SystemTime,
+    /// Pose sent in the odometry message.
+    pub odometry: Pose,
+    /// Pose after the graph optimization.
+    pub optimized: Pose,
+}
+
+/// Sent when a loop closure moved the nodes of the map. Everything placed with
+/// odometry near a node moves by the same correction, `optimized * odometry⁻¹`.
+///
+/// Only has what changed since the previous message of the epoch, see
+/// [`PoseGraphMessage::merge`]. The first one a client gets has the whole graph.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoseGraphMessage {
+    pub epoch: u64,
+    pub source: SourceId,
+    /// Nodes that are new or moved, oldest first.
+    pub nodes: Vec<GraphNode>,
+    /// Nodes RTAB-Map dropped from the map.
+    pub removed: Vec<i32>,
+}
+
+impl PoseGraphMessage {
+    pub fn new(epoch: u64, source: SourceId) -> Self {
+        Self {
+            epoch,
+            source,
+            nodes: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Applies a later message to the whole graph. One of a newer epoch
+    /// starts over.
+    pub fn merge(&mut self, update: PoseGraphMessage) {
+        if update.epoch != self.epoch {
+            *self = Self::new(update.epoch, update.source);
+        }
+        self.nodes.retain(|node| {
+            !update.removed.contains(&node.id) && !update.nodes.iter().any(|n| n.id == node.id)
+        });
+        self.nodes.extend(update.nodes);
+        self.nodes.sort_by_key(|node| node.stamp);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapResetMessage {
+    /// Each source has a map of its own, which is reset on its own.
+    pub source: SourceId,
+    /// Incremented on every reset of the source's map. Odometry and images
+    /// carry the epoch they belong to.
+    pub epoch: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoticeMessage {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WelcomeMessage {
+    pub session_id: u64,
+    /// Subscribes to odometry over UDP.
+    pub session_token: SessionToken,
+    /// Current epoch of each source's map, see [`MapResetMessage`].
+    pub map_epochs: Vec<u64>,
+    /// Names of the server's cameras, indexed by [`SourceId`].
+    pub sources: Vec<String>,
+}
+
+/// Answer to the server's challenge, before any other message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloMessage {
+    pub color_codecs: Vec<ColorCodec>,
+    pub depth_codecs: Vec<DepthCodec>,
+    /// See [`auth::AuthKey::respond`]. Servers without a token ignore it.
+    pub auth_response: Option<Vec<u8>>,
+    /// Names of the sources to receive, all of them if empty. The server says
+    /// goodbye to hellos naming sources it doesn't have.
+    pub sources: Vec<String>,
+}
+
+/// Sent by the client for every received images message so the server can adapt the image quality.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedbackMessage {
+    pub stamp: std::time::SystemTime,
+    pub latency_ns: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Command {
+    /// Starts a new, empty map. Also leaves a loaded map.
     Reset,
-    SaveStats(Stats),
+    SaveStats(Box<Stats>),
+    SetKeyframePolicy(KeyframePolicy),
+    /// Saves the current map of the first source under a name.
+    SaveMap(String),
+    /// Restarts SLAM in a saved map, localizing instead of extending it. Only
+    /// the first source uses the map, the others start empty ones.
+    LoadMap(String),
+    /// Asks for [`WebSocketServerMessage::Maps`].
+    ListMaps,
+}
+
+/// Decides which odometry frames are sent to the clients together with images.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeyframePolicy {
+    /// Send images at a fixed interval regardless of movement.
+    Interval { interval: std::time::Duration },
+    /// Send images when the camera moved or turned more than the thresholds, or when less than
+    /// `min_overlap` of the last keyframe is still in view. Falls back to `max_interval`.
+    Motion {
+        min_translation: f32,
+        min_rotation: f32,
+        min_overlap: f32,
+        min_interval: std::time::Duration,
+        max_interval: std::time::Duration,
+    },
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -65,4 +433,18 @@ pub struct Stats {
     pub odometry_stamps: Vec<std::time::SystemTime>,
     pub odometry_original_sizes: Vec<usize>,
     pub odometry_latencies: Vec<i64>,
+    /// Per image, like the other `images_` fields.
+    pub images_stages: Vec<ImageStages>,
+    /// Websocket messages the server dropped for this client.
+    pub messages_dropped: u64,
+    /// Round trip times of the pings the clock offset was estimated from, in
+    /// nanoseconds.
+    pub time_sync_rtts: Vec<i64>,
+    /// Estimated offset of the server clock from the client clock, in
+    /// nanoseconds, one per ping.
+    pub time_sync_offsets: Vec<i64>,
+    /// How far off each offset estimate likely is, in nanoseconds.
+    pub time_sync_uncertainties: Vec<i64>,
+    /// Estimated drift of the server clock, in microseconds per second.
+    pub time_sync_drifts: Vec<f64>,
 }
@@ -0,0 +1,689 @@
+use std::time::SystemTime;
+
+use crate::decoded::{ImagesMessage, OdometryMessage, PoseGraphMessage};
+use crate::{CameraIntrinsics, GridCellDelta, MapDeltaMessage, QuantizedPoint};
+use fxhash::{FxHashMap, FxHashSet};
+use nalgebra::{Isometry3, Point3, Vector2, Vector3};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    pub position: Point3<f32>,
+    pub color: Vector3<u8>,
+    pub size: f32,
+    /// Keyframe the point came from, in anchored point clouds.
+    pub anchor: Option<Anchor>,
+}
+
+/// The `index`th point of keyframe `keyframe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Anchor {
+    pub keyframe: u32,
+    pub index: u32,
+}
+
+/// Keyframe poses closer than this to the current one aren't worth moving the
+/// points for.
+const MIN_POSE_CHANGE: f32 = 1e-3;
+
+/// Points of one images message, relative to the camera.
+#[derive(Debug)]
+struct Keyframe {
+    stamp: SystemTime,
+    /// Pose from the odometry message, which pose graph corrections apply to.
+    odometry: Isometry3<f32>,
+    pose: Isometry3<f32>,
+    /// `None` once carved away by a later frame.
+    points: Vec<Option<Point>>,
+    /// Grids holding the points, possibly some that don't anymore.
+    grids: FxHashSet<GridIndex>,
+}
+
+pub type GridIndex = Vector3<i32>;
+
+#[derive(Debug)]
+pub struct SpacialGridMap {
+    grid_size: f32,
+    grids: FxHashMap<GridIndex, Vec<Point>>,
+}
+
+impl SpacialGridMap {
+    pub fn new(grid_size: f32) -> Self {
+        Self {
+            grid_size,
+            grids: FxHashMap::default(),
+        }
+    }
+
+    pub fn grids(&self) -> &FxHashMap<GridIndex, Vec<Point>> {
+        &self.grids
+    }
+
+    pub fn grid_size(&self) -> f32 {
+        self.grid_size
+    }
+
+    pub fn grid_index(&self, point: &Point3<f32>) -> GridIndex {
+        point
+            .coords
+            .map(|x| x / self.grid_size)
+            .map(|x| x.floor() as i32)
+    }
+
+    pub fn add_point(&mut self, point: &Point) {
+        let idx = self.grid_index(&point.position);
+        self.grids.entry(idx).or_default().push(*point);
+    }
+
+    pub fn points_in_grid_mut(&mut self, grid_index: GridIndex) -> Option<&mut Vec<Point>> {
+        self.grids.get_mut(&grid_index)
+    }
+
+    pub fn points_in_grid(&self, grid_index: GridIndex) -> Option<&Vec<Point>> {
+        self.grids.get(&grid_index)
+    }
+
+    pub fn grids_touching_aabb(
+        &self,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+    ) -> impl Iterator<Item = GridIndex> + '_ {
+        let min_idx = min.map(|x| (x / self.grid_size).floor() as i32);
+        let max_idx = max.map(|x| (x / self.grid_size).floor() as i32);
+        (min_idx.x..=max_idx.x)
+            .flat_map(move |x| {
+                (min_idx.y..=max_idx.y)
+                    .flat_map(move |y| (min_idx.z..=max_idx.z).map(move |z| Vector3::new(x, y, z)))
+            })
+            .filter(|grid_index| self.grids.contains_key(grid_index))
+    }
+
+    pub fn grid_corners(&self, grid_index: GridIndex) -> [Point3<f32>; 8] {
+        let min = grid_index.map(|x| x as f32 * self.grid_size);
+        let max = min + Vector3::new(self.grid_size, self.grid_size, self.grid_size);
+        [
+            Point3::new(min.x, min.y, min.z),
+            Point3::new(min.x, min.y, max.z),
+            Point3::new(min.x, max.y, min.z),
+            Point3::new(min.x, max.y, max.z),
+            Point3::new(max.x, min.y, min.z),
+            Point3::new(max.x, min.y, max.z),
+            Point3::new(max.x, max.y, min.z),
+            Point3::new(max.x, max.y, max.z),
+        ]
+    }
+
+    pub fn all_points(&self) -> impl Iterator<Item = &Point> {
+        self.grids.values().flat_map(|cell| cell.iter())
+    }
+
+    pub fn remove_grid(&mut self, grid_index: GridIndex) {
+        self.grids.remove(&grid_index);
+    }
+}
+
+/// Points added to and removed from each grid by a merge.
+#[derive(Debug, Default)]
+pub struct CloudDelta {
+    pub added: FxHashMap<GridIndex, Vec<Point>>,
+    pub removed: FxHashMap<GridIndex, Vec<Point>>,
+}
+
+impl CloudDelta {
+    pub fn modified_grids(&self) -> FxHashSet<GridIndex> {
+        self.added
+            .keys()
+            .chain(self.removed.keys())
+            .copied()
+            .collect()
+    }
+}
+
+/// Size of a point is quantized in units of 10 µm.
+const POINT_SIZE_UNIT: f32 = 1e-5;
+
+fn quantize_point(grid_size: f32, grid_index: GridIndex, point: &Point) -> QuantizedPoint {
+    let origin = grid_index.map(|x| x as f32 * grid_size);
+    let offset = (point.position.coords - origin) / grid_size;
+    QuantizedPoint {
+        position: offset
+            .map(|x| (x * u16::MAX as f32 + 0.5).clamp(0.0, u16::MAX as f32) as u16)
+            .into(),
+        color: point.color.into(),
+        size: (point.size / POINT_SIZE_UNIT).round().min(u16::MAX as f32) as u16,
+    }
+}
+
+fn dequantize_point(grid_size: f32, grid_index: GridIndex, point: &QuantizedPoint) -> Point {
+    let origin = grid_index.map(|x| x as f32 * grid_size);
+    let offset = Vector3::from(point.position).map(|x| x as f32 / u16::MAX as f32);
+    Point {
+        position: Point3::from(origin + offset * grid_size),
+        color: point.color.into(),
+        size: point.size as f32 * POINT_SIZE_UNIT,
+        anchor: None,
+    }
+}
+
+pub struct PointCloud {
+    grid_map: SpacialGridMap,
+    /// Set in anchored point clouds.
+    keyframes: Option<FxHashMap<u32, Keyframe>>,
+    next_keyframe: u32,
+}
+
+impl PointCloud {
+    pub fn new(grid_size: f32) -> Self {
+        Self {
+            grid_map: SpacialGridMap::new(grid_size),
+            keyframes: None,
+            next_keyframe: 0,
+        }
+    }
+
+    /// A point cloud that also keeps every point relative to its keyframe, so
+    /// the points move along when the keyframe pose is corrected. Takes about
+    /// twice the memory.
+    pub fn anchored(grid_size: f32) -> Self {
+        Self {
+            keyframes: Some(FxHashMap::default()),
+            ..Self::new(grid_size)
+        }
+    }
+
+    /// Stamp and current pose of each keyframe still holding points.
+    pub fn keyframes(&self) -> impl Iterator<Item = (SystemTime, Isometry3<f32>)> + '_ {
+        self.keyframes
+            .iter()
+            .flat_map(|keyframes| keyframes.values())
+            .map(|keyframe| (keyframe.stamp, keyframe.pose))
+    }
+
+    /// Moves the points of the keyframe of the images message stamped `stamp`
+    /// to `pose`. Returns the points that moved between grids, or nothing if
+    /// there is no such keyframe.
+    pub fn set_keyframe_pose(&mut self, stamp: SystemTime, pose: Isometry3<f32>) -> CloudDelta {
+        let mut delta = CloudDelta::default();
+        let id = self
+            .keyframes
+            .iter()
+            .flatten()
+            .find_map(|(id, keyframe)| (keyframe.stamp == stamp).then_some(*id));
+        if let Some(id) = id {
+            self.move_keyframe(id, pose, &mut delta);
+        }
+        delta
+    }
+
+    /// Moves every keyframe by the correction of the graph node closest in
+    /// time. Corrections replace earlier ones, they don't add up.
+    pub fn apply_pose_graph(&mut self, graph: &PoseGraphMessage) -> CloudDelta {
+        let mut delta = CloudDelta::default();
+        let poses: Vec<_> = self
+            .keyframes
+            .iter()
+            .flatten()
+            .filter_map(|(id, keyframe)| {
+                let correction = graph.correction_at(keyframe.stamp)?;
+                Some((*id, correction * keyframe.odometry))
+            })
+            .collect();
+        for (id, pose) in poses {
+            self.move_keyframe(id, pose, &mut delta);
+        }
+        delta
+    }
+
+    fn move_keyframe(&mut self, id: u32, pose: Isometry3<f32>, delta: &mut CloudDelta) {
+        let Some(keyframe) = self.keyframes.as_mut().and_then(|k| k.get_mut(&id)) else {
+            return;
+        };
+        let change = keyframe.pose.inverse() * pose;
+        if change.translation.vector.norm() < MIN_POSE_CHANGE
+            && change.rotation.angle() < MIN_POSE_CHANGE
+        {
+            return;
+        }
+        keyframe.pose = pose;
+        for grid_index in keyframe.grids.drain() {
+            let Some(points) = self.grid_map.points_in_grid_mut(grid_index) else {
+                continue;
+            };
+            let mut removed = Vec::new();
+            let mut i = 0;
+            while let Some(point) = points.get(i) {
+                if point.anchor.is_some_and(|anchor| anchor.keyframe == id) {
+                    removed.push(points.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            if points.is_empty() {
+                self.grid_map.remove_grid(grid_index);
+            }
+            if !removed.is_empty() {
+                delta.removed.entry(grid_index).or_default().extend(removed);
+            }
+        }
+        for point in keyframe.points.iter().flatten() {
+            let point = Point {
+                position: pose * point.position,
+                ..*point
+            };
+            let grid_index = self.grid_map.grid_index(&point.position);
+            self.grid_map.add_point(&point);
+            keyframe.grids.insert(grid_index);
+            delta.added.entry(grid_index).or_default().push(point);
+        }
+    }
+
+    pub fn merge_images_msg(
+        &mut self,
+        image_msg: &ImagesMessage,
+    ) -> (FxHashSet<GridIndex>, std::time::Duration) {
+        let (delta, elapsed) = self.merge_images_msg_with_delta(image_msg);
+        (delta.modified_grids(), elapsed)
+    }
+
+    /// Like [`PointCloud::merge_images_msg`], but reports exactly which points changed.
+    pub fn merge_images_msg_with_delta(
+        &mut self,
+        image_msg: &ImagesMessage,
+    ) -> (CloudDelta, std::time::Duration) {
+        let start = std::time::Instant::now();
+
+        let max_depth = 5.0;
+
+        let extrinsics = odometry_to_extrinsics(image_msg.odometry);
+        let keyframe_id = self.next_keyframe;
+        let mut keyframe = self.keyframes.is_some().then(|| Keyframe {
+            stamp: image_msg.odometry.stamp,
+            odometry: extrinsics,
+            pose: extrinsics,
+            points: Vec::new(),
+            grids: FxHashSet::default(),
+        });
+        let color_projector = Projector::new(image_msg.color_intrinsics, extrinsics);
+        let depth_projector = Projector::new(image_msg.depth_intrinsics, extrinsics);
+
+        let (min, max) = color_projector.aabb(max_depth);
+        let target_grids: Vec<GridIndex> = self
+            .grid_map
+            .grids_touching_aabb(min, max)
+            .filter(|grid_index| {
+                self.grid_map.grid_corners(*grid_index).iter().any(|point| {
+                    color_projector.point_to_pixel(*point).is_some()
+                        && color_projector.point_depth(*point) < max_depth
+                })
+            })
+            .collect();
+
+        let mut delta = CloudDelta::default();
+        let mut carved_keyframes = FxHashSet::default();
+        for grid_index in target_grids {
+            let mut removed = Vec::new();
+            let mut i = 0;
+            let Some(points) = self.grid_map.points_in_grid_mut(grid_index) else {
+                continue;
+            };
+            while let Some(point) = points.get(i) {
+                if let (Some(_), Some(depth_pixel)) = (
+                    color_projector.point_to_pixel(point.position),
+                    depth_projector.point_to_pixel(point.position),
+                ) {
+                    let orig_depth = depth_projector.point_depth(point.position);
+                    let depth = image_msg.depth.get_pixel(depth_pixel.x, depth_pixel.y)[0] as f32
+                        * image_msg.depth_unit;
+                    let remove = depth > orig_depth - 0.5;
+                    if remove {
+                        let point = points.swap_remove(i);
+                        if let Some(anchor) = point.anchor {
+                            carved_keyframes.insert(anchor.keyframe);
+                            if let Some(keyframe) = self
+                                .keyframes
+                                .as_mut()
+                                .and_then(|k| k.get_mut(&anchor.keyframe))
+                            {
+                                keyframe.points[anchor.index as usize] = None;
+                            }
+                        }
+                        removed.push(point);
+                        continue;
+                    }
+                }
+                i += 1;
+            }
+            if points.is_empty() {
+                self.grid_map.remove_grid(grid_index);
+            }
+            if !removed.is_empty() {
+                delta.removed.insert(grid_index, removed);
+            }
+        }
+
+        for y in 0..image_msg.depth.height() {
+            for x in 0..image_msg.depth.width() {
+                let depth_pixel = Vector2::new(x, y);
+                let depth = image_msg.depth.get_pixel(x, y)[0] as f32 * image_msg.depth_unit;
+                if depth == 0.0 || depth > max_depth {
+                    continue;
+                }
+                let point = depth_projector.pixel_to_point(depth_pixel, depth);
+                if let Some(color_pixel) = color_projector.point_to_pixel(point) {
+                    let color = image_msg.color.get_pixel(color_pixel.x, color_pixel.y).0;
+                    let size = color_projector.point_size(depth);
+                    let mut point = Point {
+                        position: point,
+                        color: Vector3::new(color[0], color[1], color[2]),
+                        size,
+                        anchor: None,
+                    };
+                    let grid_index = self.grid_map.grid_index(&point.position);
+                    if let Some(keyframe) = &mut keyframe {
+                        point.anchor = Some(Anchor {
+                            keyframe: keyframe_id,
+                            index: keyframe.points.len() as u32,
+                        });
+                        keyframe.points.push(Some(Point {
+                            position: extrinsics.inverse_transform_point(&point.position),
+                            ..point
+                        }));
+                        keyframe.grids.insert(grid_index);
+                    }
+                    self.grid_map.add_point(&point);
+                    delta.added.entry(grid_index).or_default().push(point);
+                }
+            }
+        }
+
+        if let Some(keyframes) = &mut self.keyframes {
+            // Keyframes whose points were all carved away are gone for good.
+            for id in carved_keyframes {
+                if keyframes
+                    .get(&id)
+                    .is_some_and(|k| k.points.iter().all(Option::is_none))
+                {
+                    keyframes.remove(&id);
+                }
+            }
+            if let Some(keyframe) = keyframe.filter(|k| !k.points.is_empty()) {
+                keyframes.insert(keyframe_id, keyframe);
+                self.next_keyframe += 1;
+            }
+        }
+
+        (delta, start.elapsed())
+    }
+
+    /// Encodes a merge result for clients that only apply deltas.
+    pub fn encode_delta(&self, delta: &CloudDelta) -> MapDeltaMessage {
+        let cells = delta
+            .modified_grids()
+            .into_iter()
+            .map(|grid_index| {
+                let quantize = |points: Option<&Vec<Point>>| {
+                    points
+                        .into_iter()
+                        .flatten()
+                        .map(|p| quantize_point(self.grid_map.grid_size(), grid_index, p))
+                        .collect()
+                };
+                GridCellDelta {
+                    index: grid_index.into(),
+                    removed: quantize(delta.removed.get(&grid_index)),
+                    added: quantize(delta.added.get(&grid_index)),
+                }
+            })
+            .collect();
+        MapDeltaMessage {
+            epoch: 0,
+            source: 0,
+            grid_size: self.grid_map.grid_size(),
+            snapshot: false,
+            cells,
+        }
+    }
+
+    /// Encodes the whole map, used for clients that join late.
+    pub fn snapshot(&self) -> MapDeltaMessage {
+        let cells = self
+            .grid_map
+            .grids()
+            .iter()
+            .map(|(grid_index, points)| GridCellDelta {
+                index: (*grid_index).into(),
+                removed: Vec::new(),
+                added: points
+                    .iter()
+                    .map(|p| quantize_point(self.grid_map.grid_size(), *grid_index, p))
+                    .collect(),
+            })
+            .collect();
+        MapDeltaMessage {
+            epoch: 0,
+            source: 0,
+            grid_size: self.grid_map.grid_size(),
+            snapshot: true,
+            cells,
+        }
+    }
+
+    /// Applies a delta streamed by the server and returns the modified grids.
+    /// A snapshot replaces the whole map.
+    pub fn apply_map_delta(&mut self, msg: &MapDeltaMessage) -> FxHashSet<GridIndex> {
+        let mut modified_grids = FxHashSet::default();
+        if msg.snapshot || msg.grid_size != self.grid_map.grid_size() {
+            modified_grids.extend(self.grid_map.grids().keys().copied());
+            self.grid_map = SpacialGridMap::new(msg.grid_size);
+        }
+        for cell in &msg.cells {
+            let grid_index = GridIndex::from(cell.index);
+            if !cell.removed.is_empty() {
+                let removed: FxHashSet<QuantizedPoint> = cell.removed.iter().copied().collect();
+                if let Some(points) = self.grid_map.grids.get_mut(&grid_index) {
+                    points.retain(|p| {
+                        !removed.contains(&quantize_point(msg.grid_size, grid_index, p))
+                    });
+                    if points.is_empty() {
+                        self.grid_map.remove_grid(grid_index);
+                    }
+                }
+            }
+            for point in &cell.added {
+                let point = dequantize_point(msg.grid_size, grid_index, point);
+                self.grid_map
+                    .grids
+                    .entry(grid_index)
+                    .or_default()
+                    .push(point);
+            }
+            modified_grids.insert(grid_index);
+        }
+        modified_grids
+    }
+
+    pub fn grid_map(&self) -> &SpacialGridMap {
+        &self.grid_map
+    }
+}
+
+fn odometry_to_extrinsics(odometry: OdometryMessage) -> nalgebra::Isometry3<f32> {
+    let translation = nalgebra::Translation3::new(
+        odometry.translation.x,
+        odometry.translation.y,
+        odometry.translation.z,
+    );
+    nalgebra::Isometry3::from_parts(translation, odometry.rotation)
+}
+
+struct Projector {
+    intrinsics: CameraIntrinsics,
+    extrinsics: nalgebra::Isometry3<f32>,
+    inv_extrinsics: nalgebra::Isometry3<f32>,
+}
+
+impl Projector {
+    fn new(intrinsics: CameraIntrinsics, extrinsics: nalgebra::Isometry3<f32>) -> Self {
+        let inv_extrinsics = extrinsics.inverse();
+        Self {
+            intrinsics,
+            extrinsics,
+            inv_extrinsics,
+        }
+    }
+
+    fn point_to_pixel(&self, point: Point3<f32>) -> Option<Vector2<u32>> {
+        let point = self.inv_extrinsics * point;
+        if point.x < 0.0 {
+            return None;
+        }
+        let x = (self.intrinsics.fx * -point.y / point.x + self.intrinsics.cx) as i64;
+        let y = (self.intrinsics.fy * -point.z / point.x + self.intrinsics.cy) as i64;
+        if 0 <= x && x < self.intrinsics.width as i64 && 0 <= y && y < self.intrinsics.height as i64
+        {
+            Some(Vector2::new(x as u32, y as u32))
+        } else {
+            None
+        }
+    }
+
+    fn point_depth(&self, point: Point3<f32>) -> f32 {
+        let point = self.inv_extrinsics * point;
+        point.x
+    }
+
+    fn pixel_to_point(&self, pixel: Vector2<u32>, depth: f32) -> Point3<f32> {
+        let y = -(pixel.x as f32 - self.intrinsics.cx) / self.intrinsics.fx;
+        let z = -(pixel.y as f32 - self.intrinsics.cy) / self.intrinsics.fy;
+        self.extrinsics * Point3::new(depth, y * depth, z * depth)
+    }
+
+    fn point_size(&self, depth: f32) -> f32 {
+        depth / self.intrinsics.fx
+    }
+
+    fn aabb(&self, depth: f32) -> (Vector3<f32>, Vector3<f32>) {
+        let origin = self.extrinsics.translation.vector;
+        let c1 = self.pixel_to_point(Vector2::new(0, 0), depth);
+        let c2 = self.pixel_to_point(Vector2::new(self.intrinsics.width - 1, 0), depth);
+        let c3 = self.pixel_to_point(Vector2::new(0, self.intrinsics.height - 1), depth);
+        let c4 = self.pixel_to_point(
+            Vector2::new(self.intrinsics.width - 1, self.intrinsics.height - 1),
+            depth,
+        );
+        let min = Vector3::new(
+            origin.x.min(c1.x).min(c2.x).min(c3.x).min(c4.x),
+            origin.y.min(c1.y).min(c2.y).min(c3.y).min(c4.y),
+            origin.z.min(c1.z).min(c2.z).min(c3.z).min(c4.z),
+        );
+        let max = Vector3::new(
+            origin.x.max(c1.x).max(c2.x).max(c3.x).max(c4.x),
+            origin.y.max(c1.y).max(c2.y).max(c3.y).max(c4.y),
+            origin.z.max(c1.z).max(c2.z).max(c3.z).max(c4.z),
+        );
+        (min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point {
+            position: Point3::new(x, y, z),
+            color: Vector3::new(10, 20, 30),
+            size: 0.004,
+            anchor: None,
+        }
+    }
+
+    #[test]
+    fn deltas_reproduce_the_server_map() {
+        let mut server = PointCloud::new(1.0);
+        let mut client = PointCloud::new(0.5);
+        let a = point(0.25, -1.5, 3.75);
+        let b = point(2.1, 0.3, -0.7);
+        server.grid_map.add_point(&a);
+        server.grid_map.add_point(&b);
+        client.apply_map_delta(&server.snapshot());
+        assert_eq!(client.grid_map().grid_size(), 1.0);
+        assert_eq!(client.grid_map().all_points().count(), 2);
+
+        let grid_index = server.grid_map.grid_index(&a.position);
+        let c = point(0.5, -1.25, 3.5);
+        let mut delta = CloudDelta::default();
+        delta.removed.insert(grid_index, vec![a]);
+        delta.added.insert(grid_index, vec![c]);
+        let modified = client.apply_map_delta(&server.encode_delta(&delta));
+        assert_eq!(modified.into_iter().collect::<Vec<_>>(), vec![grid_index]);
+
+        let points = client.grid_map().points_in_grid(grid_index).unwrap();
+        assert_eq!(points.len(), 1);
+        assert!((points[0].position - c.position).norm() < 1e-4);
+        assert_eq!(points[0].color, c.color);
+        assert!((points[0].size - c.size).abs() < POINT_SIZE_UNIT);
+    }
+
+    fn images(stamp: SystemTime) -> ImagesMessage {
+        let intrinsics = CameraIntrinsics {
+            width: 4,
+            height: 4,
+            fx: 4.0,
+            fy: 4.0,
+            cx: 2.0,
+            cy: 2.0,
+        };
+        ImagesMessage {
+            original_size: 0,
+            odometry: OdometryMessage {
+                original_size: 0,
+                epoch: 0,
+                source: 0,
+                stamp,
+                translation: Vector3::new(0.0, 0.0, 0.0),
+                rotation: nalgebra::UnitQuaternion::identity(),
+            },
+            color: image::ImageBuffer::from_pixel(4, 4, image::Rgb([10, 20, 30])),
+            color_intrinsics: intrinsics,
+            depth: image::ImageBuffer::from_pixel(4, 4, image::Luma([1500])),
+            depth_intrinsics: intrinsics,
+            depth_unit: 0.001,
+            timings: Default::default(),
+            stages: Default::default(),
+        }
+    }
+
+    #[test]
+    fn anchored_points_follow_their_keyframe() {
+        let stamp = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(10);
+        let mut cloud = PointCloud::anchored(1.0);
+        cloud.merge_images_msg(&images(stamp));
+        assert_eq!(cloud.keyframes().count(), 1);
+        let before: FxHashSet<_> = cloud.grid_map().grids().keys().copied().collect();
+
+        let up = Isometry3::translation(0.0, 0.0, 10.0);
+        let delta = cloud.set_keyframe_pose(stamp, up);
+        assert_eq!(delta.removed.values().flatten().count(), 16);
+        assert_eq!(delta.added.values().flatten().count(), 16);
+        assert!(delta.modified_grids().is_superset(&before));
+        assert!(cloud.grid_map().all_points().all(|p| p.position.z > 9.0));
+        assert!(cloud.grid_map().grids().keys().all(|g| !before.contains(g)));
+        // Already there.
+        assert!(cloud.set_keyframe_pose(stamp, up).added.is_empty());
+
+        let graph = PoseGraphMessage {
+            epoch: 0,
+            source: 0,
+            nodes: vec![crate::decoded::GraphNode {
+                id: 1,
+                stamp,
+                correction: Isometry3::translation(1.0, 0.0, 0.0),
+            }],
+        };
+        cloud.apply_pose_graph(&graph);
+        assert!(cloud
+            .grid_map()
+            .all_points()
+            .all(|p| (p.position.x - 2.5).abs() < 1e-4 && p.position.z.abs() < 1.0));
+    }
+}
@@ -0,0 +1,165 @@
+//! Run length / variable length (RVL) coding of depth images.
+//!
+//! See A. D. Wilson, "Fast Lossless Depth Image Compression", ISS 2017.
+
+use anyhow::{bail, ensure, Result};
+
+use crate::codec::MAX_DEPTH_PIXELS;
+
+struct Writer {
+    out: Vec<u8>,
+    word: u32,
+    nibbles: u32,
+}
+
+impl Writer {
+    fn encode_vle(&mut self, mut value: u32) {
+        loop {
+            let mut nibble = value & 0x7;
+            value >>= 3;
+            if value != 0 {
+                nibble |= 0x8;
+            }
+            self.word = (self.word << 4) | nibble;
+            self.nibbles += 1;
+            if self.nibbles == 8 {
+                self.out.extend_from_slice(&self.word.to_le_bytes());
+                self.word = 0;
+                self.nibbles = 0;
+            }
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nibbles != 0 {
+            self.word <<= 4 * (8 - self.nibbles);
+            self.out.extend_from_slice(&self.word.to_le_bytes());
+        }
+        self.out
+    }
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    word: u32,
+    nibbles: u32,
+}
+
+impl Reader<'_> {
+    fn decode_vle(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        let mut shift = 0;
+        loop {
+            if self.nibbles == 0 {
+                let Some((word, rest)) = self.input.split_first_chunk::<4>() else {
+                    bail!("Unexpected end of RVL data");
+                };
+                self.word = u32::from_le_bytes(*word);
+                self.input = rest;
+                self.nibbles = 8;
+            }
+            let nibble = self.word >> 28;
+            self.word <<= 4;
+            self.nibbles -= 1;
+            if shift > 28 {
+                bail!("RVL value overflow");
+            }
+            value |= (nibble & 0x7) << shift;
+            shift += 3;
+            if nibble & 0x8 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+pub fn compress(depth: &[u16]) -> Vec<u8> {
+    let mut writer = Writer {
+        out: Vec::with_capacity(depth.len()),
+        word: 0,
+        nibbles: 0,
+    };
+    let mut prev = 0i32;
+    let mut i = 0;
+    while i < depth.len() {
+        let zeros = depth[i..].iter().take_while(|&&d| d == 0).count();
+        writer.encode_vle(zeros as u32);
+        i += zeros;
+        let nonzeros = depth[i..].iter().take_while(|&&d| d != 0).count();
+        writer.encode_vle(nonzeros as u32);
+        for &d in &depth[i..i + nonzeros] {
+            let delta = d as i32 - prev;
+            writer.encode_vle(((delta << 1) ^ (delta >> 31)) as u32);
+            prev = d as i32;
+        }
+        i += nonzeros;
+    }
+    writer.finish()
+}
+
+pub fn decompress(data: &[u8], len: usize) -> Result<Vec<u16>> {
+    ensure!(
+        len > 0 && len <= MAX_DEPTH_PIXELS,
+        "Invalid RVL image size {len}"
+    );
+    let mut reader = Reader {
+        input: data,
+        word: 0,
+        nibbles: 0,
+    };
+    // Runs of zeros take next to no data, only the other pixels are bounded
+    // by it.
+    let mut depth = Vec::with_capacity(len.min(data.len() * 2));
+    let mut prev = 0i32;
+    while depth.len() < len {
+        let zeros = reader.decode_vle()? as usize;
+        let nonzeros = reader.decode_vle()? as usize;
+        if depth.len() + zeros + nonzeros > len {
+            bail!("RVL data exceeds image size");
+        }
+        depth.resize(depth.len() + zeros, 0);
+        for _ in 0..nonzeros {
+            let positive = reader.decode_vle()? as i32;
+            let delta = (positive >> 1) ^ -(positive & 1);
+            prev += delta;
+            depth.push(prev as u16);
+        }
+    }
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let depth: Vec<u16> = (0..640u32 * 3)
+            .map(|i| match i % 97 {
+                0..=20 => 0,
+                n => (1000 + n * 13 + i / 7) as u16,
+            })
+            .chain([u16::MAX, 1, 0, 0, u16::MAX])
+            .collect();
+        let compressed = compress(&depth);
+        assert!(compressed.len() < depth.len() * 2);
+        assert_eq!(decompress(&compressed, depth.len()).unwrap(), depth);
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let depth = vec![1234u16; 100];
+        let compressed = compress(&depth);
+        assert!(decompress(&compressed[..compressed.len() - 4], depth.len()).is_err());
+    }
+
+    #[test]
+    fn bad_length_is_an_error() {
+        let compressed = compress(&[1234u16; 100]);
+        assert!(decompress(&compressed, 0).is_err());
+        assert!(decompress(&compressed, MAX_DEPTH_PIXELS + 1).is_err());
+    }
+}
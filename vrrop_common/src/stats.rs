@@ -0,0 +1,105 @@
+//! Summaries of recorded statistics.
+
+use serde::{Deserialize, Serialize};
+
+use crate::ImageStages;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    pub count: usize,
+    pub mean: f64,
+    /// Sample standard deviation.
+    pub stdev: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl Distribution {
+    /// `None` without values.
+    pub fn of(values: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let mut values: Vec<_> = values.into_iter().collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let count = values.len();
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let variance =
+            values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count.max(2) - 1) as f64;
+        Some(Self {
+            count,
+            mean,
+            stdev: variance.sqrt(),
+            p50: percentile(&values, 0.5),
+            p95: percentile(&values, 0.95),
+            p99: percentile(&values, 0.99),
+        })
+    }
+}
+
+/// Linear interpolation between the closest ranks of `sorted`.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (rank - low as f64)
+}
+
+/// How long images spent in one stage of the pipeline.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageSummary {
+    pub stage: &'static str,
+    /// Of the images the stage was measured for, in milliseconds.
+    pub ms: Option<Distribution>,
+}
+
+/// In the order of [`ImageStages::NAMES`].
+pub fn image_stages(stages: &[ImageStages]) -> Vec<StageSummary> {
+    ImageStages::NAMES
+        .iter()
+        .enumerate()
+        .map(|(i, &stage)| StageSummary {
+            stage,
+            ms: Distribution::of(
+                stages
+                    .iter()
+                    .filter_map(|stages| stages.values()[i])
+                    .map(|ns| ns as f64 / 1e6),
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distribution() {
+        let latencies = Distribution::of((1..=100).map(f64::from)).unwrap();
+        assert_eq!(latencies.count, 100);
+        assert_eq!(latencies.mean, 50.5);
+        assert!((latencies.stdev - 29.011).abs() < 1e-3);
+        assert_eq!(latencies.p50, 50.5);
+        assert!((latencies.p95 - 95.05).abs() < 1e-9);
+        assert!(Distribution::of([]).is_none());
+        assert_eq!(Distribution::of([2.0]).unwrap().stdev, 0.0);
+
+        let stages = image_stages(&[
+            ImageStages {
+                encode: Some(2_000_000),
+                ..Default::default()
+            },
+            ImageStages {
+                encode: Some(4_000_000),
+                merge: Some(1_000_000),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(stages.len(), ImageStages::NAMES.len());
+        assert_eq!(stages[2].stage, "encode");
+        assert_eq!(stages[2].ms.as_ref().unwrap().mean, 3.0);
+        assert_eq!(stages[6].ms.as_ref().unwrap().count, 1);
+        assert!(stages[0].ms.is_none());
+    }
+}
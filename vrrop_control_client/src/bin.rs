@@ -2,12 +2,16 @@ use std::time::Duration;
 
 use anyhow::Result;
 use tokio::{select, time::sleep};
-use vrrop_control_client::Client;
+use vrrop_control_client::{AuthKey, Client};
 use vrrop_control_common::SetTargetVelocity;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let client = Client::new("127.0.0.1:23456").await?;
+    let auth = std::env::var("VRROP_AUTH_TOKEN")
+        .ok()
+        .map(|token| AuthKey::from_token(&token))
+        .transpose()?;
+    let client = Client::new("127.0.0.1:23456", auth).await?;
     let task = tokio::spawn(async move {
         loop {
             client
@@ -1,20 +1,27 @@
 use anyhow::Result;
 use tokio::net::{ToSocketAddrs, UdpSocket};
+pub use vrrop_common::auth::AuthKey;
+use vrrop_common::auth::{PacketSealer, CONTEXT_CONTROL};
 pub use vrrop_control_common::{ControlMessage, SetTargetVelocity};
 
 pub struct Client {
     socket: UdpSocket,
+    sealer: PacketSealer,
 }
 
 impl Client {
-    pub async fn new(addr: impl ToSocketAddrs) -> Result<Self> {
+    /// `auth` must match the key of the server, if it has one.
+    pub async fn new(addr: impl ToSocketAddrs, auth: Option<AuthKey>) -> Result<Self> {
         let socket = UdpSocket::bind("127.0.0.1:0").await?;
         socket.connect(addr).await?;
-        Ok(Self { socket })
+        Ok(Self {
+            socket,
+            sealer: PacketSealer::new(auth, CONTEXT_CONTROL),
+        })
     }
 
     pub async fn send_message(&self, message: &ControlMessage) -> Result<()> {
-        let bytes = message.serialize();
+        let bytes = self.sealer.seal(message.serialize());
         let bytes_sent = self.socket.send(&bytes).await?;
         if bytes_sent != bytes.len() {
             anyhow::bail!(
@@ -12,10 +12,12 @@ use gilrs::Gilrs;
 use tokio::{task::JoinHandle, time::sleep};
 
 use eframe::egui::{self, mutex::Mutex, Color32, Rounding, Sense, Stroke, Vec2};
-use vrrop_control_client::{Client, SetTargetVelocity};
+use vrrop_control_client::{AuthKey, Client, SetTargetVelocity};
 
 struct App {
     server_address: String,
+    /// Pre-shared token of the server, empty if it doesn't require one.
+    auth_token: String,
     gamepad_loop: Option<std::thread::JoinHandle<Result<()>>>,
     state: Arc<SharedState>,
 }
@@ -58,6 +60,7 @@ impl App {
             gamepad_loop: Some(gamepad_loop),
             state,
             server_address: "127.0.0.1:23456".into(),
+            auth_token: std::env::var("VRROP_AUTH_TOKEN").unwrap_or_default(),
         })
     }
 }
@@ -126,6 +129,11 @@ impl eframe::App for App {
             ui.heading("VRROP Control Client Desktop");
             ui.horizontal(|ui| {
                 ui.text_edit_singleline(&mut self.server_address);
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.auth_token)
+                        .password(true)
+                        .hint_text("auth token"),
+                );
                 let connect = ui.button("Connect").clicked();
                 let disconnect = ui
                     .add_enabled_ui(state.client_join_handle.lock().is_some(), |ui| {
@@ -136,10 +144,14 @@ impl eframe::App for App {
                     state.client_error_message.lock().take();
                     let join_handle = tokio::spawn({
                         let addr = self.server_address.clone();
+                        let auth_token = self.auth_token.clone();
                         let state1 = state.clone();
                         let state2 = state1.clone();
                         async move {
-                            let client = Client::new(addr).await?;
+                            let auth = (!auth_token.is_empty())
+                                .then(|| AuthKey::from_token(&auth_token))
+                                .transpose()?;
+                            let client = Client::new(addr, auth).await?;
                             loop {
                                 let gamepad = state1.gamepad_state.lock().clone();
                                 client
@@ -1,10 +1,18 @@
 use anyhow::Result;
-use vrrop_control_server::{Callbacks, Server};
+use vrrop_control_server::{AuthKey, Callbacks, Server};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let auth = match std::env::var("VRROP_AUTH_TOKEN") {
+        Ok(token) => Some(AuthKey::from_token(&token)?),
+        Err(_) => {
+            eprintln!("VRROP_AUTH_TOKEN is not set, accepting unauthenticated commands");
+            None
+        }
+    };
     let _server = Server::new(
         23456,
+        auth,
         Callbacks::new(|command| {
             println!("Received command: {:?}", command);
         }),
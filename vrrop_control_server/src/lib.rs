@@ -1,8 +1,13 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 use tokio::{net::UdpSocket, select};
 use tokio_util::sync::{CancellationToken, DropGuard};
+pub use vrrop_common::auth::{AuthKey, RejectReason};
+use vrrop_common::auth::{PacketOpener, RejectLog, CONTEXT_CONTROL};
 pub use vrrop_control_common::ControlMessage;
 pub use vrrop_control_common::SetTargetVelocity;
 
@@ -20,31 +25,36 @@ impl Callbacks {
 
 pub struct Server {
     _cancellation_guard: DropGuard,
+    rejects: Arc<Mutex<RejectLog>>,
 }
 
 impl Server {
-    pub async fn new(port: u16, callbacks: Callbacks) -> Result<Self> {
+    /// Without a key every datagram is accepted, so anyone on the network can move the robot.
+    pub async fn new(port: u16, auth: Option<AuthKey>, callbacks: Callbacks) -> Result<Self> {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
         let sock = UdpSocket::bind(addr).await?;
         let cancelation = CancellationToken::new();
         let cancellation_clone = cancelation.clone();
+        let mut opener = PacketOpener::new(auth, CONTEXT_CONTROL);
+        let rejects = Arc::new(Mutex::new(RejectLog::new("control")));
+        let rejects_clone = rejects.clone();
         tokio::spawn(async move {
             loop {
                 let mut buf = Vec::with_capacity(1500);
                 let recv_result = select! {
                     _ = cancellation_clone.cancelled() => break,
-                    recv_result = sock.recv_buf(&mut buf) => recv_result,
+                    recv_result = sock.recv_buf_from(&mut buf) => recv_result,
                 };
                 match recv_result {
-                    Ok(n) => {
-                        let data = &buf[..n];
-                        match ControlMessage::deserialize(data) {
+                    Ok((n, src)) => {
+                        let command = opener.open(&buf[..n]).and_then(|data| {
+                            ControlMessage::deserialize(data).map_err(|_| RejectReason::Malformed)
+                        });
+                        match command {
                             Ok(command) => {
                                 (callbacks.on_control_command)(&command);
                             }
-                            Err(e) => {
-                                eprintln!("Error deserializing UDP packet: {:?}", e);
-                            }
+                            Err(reason) => rejects_clone.lock().unwrap().record(src, reason),
                         }
                     }
                     Err(e) => {
@@ -56,6 +66,12 @@ impl Server {
         });
         Ok(Self {
             _cancellation_guard: cancelation.drop_guard(),
+            rejects,
         })
     }
+
+    /// Number of datagrams dropped for the given reason.
+    pub fn rejected_packets(&self, reason: RejectReason) -> u64 {
+        self.rejects.lock().unwrap().count(reason)
+    }
 }
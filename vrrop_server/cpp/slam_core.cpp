@@ -1,8 +1,12 @@
 #include "slam_core.h"
 #include "CameraRs2D4xx.h"
+#include <atomic>
+#include <cstdio>
 #include <cstring>
+#include <filesystem>
 #include <functional>
 #include <memory>
+#include <mutex>
 #include <rtabmap/core/CameraModel.h>
 #include <rtabmap/core/CameraThread.h>
 #include <rtabmap/core/IMUFilter.h>
@@ -11,10 +15,13 @@
 #include <rtabmap/core/OdometryThread.h>
 #include <rtabmap/core/Parameters.h>
 #include <rtabmap/core/Rtabmap.h>
+#include <rtabmap/core/RtabmapEvent.h>
 #include <rtabmap/core/RtabmapThread.h>
 #include <rtabmap/core/SensorCaptureThread.h>
 #include <rtabmap/utilite/UEventsHandler.h>
 #include <rtabmap/utilite/UEventsManager.h>
+#include <unistd.h>
+#include <vector>
 
 struct slam_core_image {
   cv::Mat mat;
@@ -34,17 +41,42 @@ private:
 struct slam_core {
 public:
   using OdometryCallback = std::function<void(slam_core_odometry_event_t *)>;
+  using LocalizationCallback =
+      std::function<void(slam_core_localization_event_t *)>;
+  using GraphCallback = std::function<void(slam_core_graph_event_t *)>;
 
-  static slam_core *create() {
+  static slam_core *create(const slam_core_camera_config_t &camera_config,
+                           const rtabmap::ParametersMap &params,
+                           const std::string &database_path,
+                           std::string &error) {
     try {
       ULogger::setType(ULogger::kTypeConsole);
       ULogger::setLevel(ULogger::kWarning);
 
-      auto camera = new CameraRs2D4xx{};
-      camera->setColorResolution(640, 480, 60);
-      camera->setIrDepthResolution(640, 480, 60);
+      const auto &defaults = rtabmap::Parameters::getDefaultParameters();
+      for (const auto &[key, value] : params) {
+        if (defaults.find(key) == defaults.end()) {
+          UWARN("Unknown RTAB-Map parameter %s", key.c_str());
+        }
+      }
+
+      auto camera = new CameraRs2D4xx{
+          camera_config.serial ? camera_config.serial : ""};
+      camera->setColorResolution(camera_config.width, camera_config.height,
+                                 camera_config.fps);
+      camera->setIrDepthResolution(camera_config.width, camera_config.height,
+                                   camera_config.fps);
+      if (camera_config.json_preset) {
+        camera->setJsonConfig(camera_config.json_preset);
+      }
       if (!camera->init()) {
-        std::cout << "camera initialization failed" << std::endl;
+        delete camera;
+        error = "Camera initialization failed, is the RealSense ";
+        if (camera_config.serial) {
+          error += camera_config.serial;
+          error += " ";
+        }
+        error += "connected?";
         return nullptr;
       }
 
@@ -55,15 +87,26 @@ public:
 
       ret->sensor_thread_ =
           std::make_unique<rtabmap::SensorCaptureThread>(camera);
-      ret->sensor_thread_->enableIMUFiltering(
-          rtabmap::IMUFilter::Type::kMadgwick, rtabmap::ParametersMap(), true);
-      auto odometry = rtabmap::Odometry::create(rtabmap::ParametersMap());
+      switch (camera_config.imu_filter) {
+      case SLAM_CORE_IMU_FILTER_NONE:
+        break;
+      case SLAM_CORE_IMU_FILTER_MADGWICK:
+        ret->sensor_thread_->enableIMUFiltering(
+            rtabmap::IMUFilter::Type::kMadgwick, params, true);
+        break;
+      case SLAM_CORE_IMU_FILTER_COMPLEMENTARY:
+        ret->sensor_thread_->enableIMUFiltering(
+            rtabmap::IMUFilter::Type::kComplementaryFilter, params, true);
+        break;
+      }
+      auto odometry = rtabmap::Odometry::create(params);
 
       ret->odom_thread_ = std::make_unique<rtabmap::OdometryThread>(odometry);
-      rtabmap::ParametersMap params;
-      auto rtabmap = new rtabmap::Rtabmap{};
-      rtabmap->init(params);
-      ret->rtabmap_thread_ = std::make_unique<rtabmap::RtabmapThread>(rtabmap);
+      ret->params_ = params;
+      ret->rtabmap_ = new rtabmap::Rtabmap{};
+      ret->rtabmap_->init(params, database_path);
+      ret->rtabmap_thread_ =
+          std::make_unique<rtabmap::RtabmapThread>(ret->rtabmap_);
 
       ret->event_handler_ = std::make_unique<EventHandler>(
           std::bind(&slam_core::handle_event, ret, std::placeholders::_1));
@@ -71,15 +114,23 @@ public:
       ret->rtabmap_thread_->registerToEventsManager();
       ret->event_handler_->registerToEventsManager();
 
+      // Events are dispatched process-wide; pipes keep those of several
+      // cores apart.
       UEventsManager::createPipe(ret->sensor_thread_.get(),
                                  ret->odom_thread_.get(), "CameraEvent");
+      UEventsManager::createPipe(ret->odom_thread_.get(),
+                                 ret->rtabmap_thread_.get(), "OdometryEvent");
+      UEventsManager::createPipe(ret->odom_thread_.get(),
+                                 ret->event_handler_.get(), "OdometryEvent");
+      UEventsManager::createPipe(ret->rtabmap_thread_.get(),
+                                 ret->event_handler_.get(), "RtabmapEvent");
       ret->rtabmap_thread_->start();
       ret->odom_thread_->start();
       ret->sensor_thread_->start();
 
       return ret;
     } catch (std::exception &e) {
-      UERROR("exception: %s", e.what());
+      error = e.what();
       return nullptr;
     }
   }
@@ -91,12 +142,62 @@ public:
     rtabmap_thread_->join(true);
     odom_thread_->join(true);
     sensor_thread_->join(true);
+    if (!working_database_.empty()) {
+      std::error_code ignored;
+      std::filesystem::remove(working_database_, ignored);
+    }
   }
 
   void register_odometry_event_handler(OdometryCallback callback) {
     odometry_callback_ = callback;
   }
 
+  void register_localization_event_handler(LocalizationCallback callback) {
+    localization_callback_ = callback;
+  }
+
+  void register_graph_event_handler(GraphCallback callback) {
+    graph_callback_ = callback;
+  }
+
+  bool save_map(const std::string &path, std::string &error) {
+    std::lock_guard<std::mutex> lock{save_mutex_};
+    // Mapping pauses while the database is written. It continues in a copy,
+    // so the saved map stays as it was saved.
+    rtabmap_thread_->join(true);
+    bool saved = false;
+    try {
+      rtabmap_->close(true, path);
+      saved = true;
+      if (working_database_.empty()) {
+        static std::atomic<unsigned> next_id{0};
+        working_database_ =
+            std::filesystem::temp_directory_path() /
+            ("vrrop_slam_core_" + std::to_string(::getpid()) + "_" +
+             std::to_string(next_id++) + ".db");
+      }
+      std::filesystem::copy_file(
+          path, working_database_,
+          std::filesystem::copy_options::overwrite_existing);
+      rtabmap_->init(params_, working_database_.string());
+    } catch (std::exception &e) {
+      error = e.what();
+      if (saved) {
+        // Better to keep mapping into the saved file than not at all.
+        error = "Map saved, but copying it failed, mapping continues in it: " +
+                error;
+        try {
+          rtabmap_->init(params_, path);
+        } catch (std::exception &) {
+        }
+      }
+      rtabmap_thread_->start();
+      return false;
+    }
+    rtabmap_thread_->start();
+    return true;
+  }
+
   rtabmap::CameraModel get_color_intrinsics() { return color_intrinsics_; }
   rtabmap::CameraModel get_depth_intrinsics() { return depth_intrinsics_; }
 
@@ -109,6 +210,7 @@ private:
         return false;
       slam_core_odometry_event_t ev;
       memset(&ev, 0, sizeof(ev));
+      ev.stamp = odom_event->data().stamp();
       auto pose = odom_event->pose();
       if (pose.isNull()) {
         std::cout << "pose is null" << std::endl;
@@ -138,11 +240,64 @@ private:
       else
         ev.depth = new slam_core_image_t{odom_event->data().depthRaw()};
       odometry_callback_(&ev);
+    } else if (event->getClassName() == "RtabmapEvent") {
+      const auto &stats =
+          static_cast<rtabmap::RtabmapEvent *>(event)->getStats();
+      int node_id = stats.loopClosureId() > 0 ? stats.loopClosureId()
+                                               : stats.proximityDetectionId();
+      if (node_id > 0 && localization_callback_ != nullptr) {
+        slam_core_localization_event_t ev{node_id};
+        localization_callback_(&ev);
+      }
+      if (graph_callback_ != nullptr) {
+        handle_graph(stats, node_id > 0);
+      }
     }
     return false;
   }
 
+  void handle_graph(const rtabmap::Statistics &stats, bool optimized) {
+    slam_core_graph_event_t ev;
+    memset(&ev, 0, sizeof(ev));
+    const auto &last = stats.getLastSignatureData();
+    if (last.id() > 0) {
+      ev.node_id = last.id();
+      ev.node_stamp = last.getStamp();
+    }
+    std::vector<slam_core_node_pose_t> poses;
+    if (optimized) {
+      for (const auto &[id, pose] : stats.poses()) {
+        if (id <= 0 || pose.isNull())
+          continue;
+        slam_core_node_pose_t node;
+        node.id = id;
+        node.translation[0] = pose.x();
+        node.translation[1] = pose.y();
+        node.translation[2] = pose.z();
+        auto q = pose.getQuaterniond();
+        node.rotation[0] = q.w();
+        node.rotation[1] = q.x();
+        node.rotation[2] = q.y();
+        node.rotation[3] = q.z();
+        poses.push_back(node);
+      }
+    }
+    ev.poses = poses.data();
+    ev.pose_count = poses.size();
+    if (ev.node_id > 0 || ev.pose_count > 0)
+      graph_callback_(&ev);
+  }
+
   OdometryCallback odometry_callback_;
+  LocalizationCallback localization_callback_;
+  GraphCallback graph_callback_;
+  rtabmap::ParametersMap params_;
+  // Mapping continues here after a map was saved, removed with the core.
+  std::filesystem::path working_database_;
+  // Saves may come from several threads.
+  std::mutex save_mutex_;
+  // Owned by rtabmap_thread_.
+  rtabmap::Rtabmap *rtabmap_;
   rtabmap::CameraModel color_intrinsics_;
   rtabmap::CameraModel depth_intrinsics_;
   std::unique_ptr<rtabmap::SensorCaptureThread> sensor_thread_;
@@ -153,7 +308,40 @@ private:
 
 extern "C" {
 
-slam_core_t *slam_core_create() { return slam_core::create(); }
+static void write_error(const std::string &message, char *error,
+                        size_t error_size) {
+  if (error && error_size > 0) {
+    std::snprintf(error, error_size, "%s", message.c_str());
+  }
+}
+
+slam_core_t *slam_core_create(const slam_core_camera_config_t *camera_config,
+                              const slam_core_parameter_t *parameters,
+                              size_t parameter_count,
+                              const char *database_path, char *error,
+                              size_t error_size) {
+  rtabmap::ParametersMap params;
+  for (size_t i = 0; i < parameter_count; ++i) {
+    params[parameters[i].key] = parameters[i].value;
+  }
+  std::string message;
+  auto ret = slam_core::create(*camera_config, params,
+                               database_path ? database_path : "", message);
+  if (!ret) {
+    write_error(message, error, error_size);
+  }
+  return ret;
+}
+
+int slam_core_save_map(slam_core_t *p, const char *path, char *error,
+                       size_t error_size) {
+  std::string message;
+  if (!p->save_map(path, message)) {
+    write_error(message, error, error_size);
+    return -1;
+  }
+  return 0;
+}
 void slam_core_delete(slam_core_t *p) { delete p; }
 
 void slam_core_get_intrinstics(
@@ -185,6 +373,18 @@ void slam_core_register_odometry_event_handler(
       [=](slam_core_odometry_event_t *ev) { handler(userdata, ev); });
 }
 
+void slam_core_register_localization_event_handler(
+    slam_core_t *p, void *userdata, slam_core_localization_handler_t handler) {
+  p->register_localization_event_handler(
+      [=](slam_core_localization_event_t *ev) { handler(userdata, ev); });
+}
+
+void slam_core_register_graph_event_handler(slam_core_t *p, void *userdata,
+                                            slam_core_graph_handler_t handler) {
+  p->register_graph_event_handler(
+      [=](slam_core_graph_event_t *ev) { handler(userdata, ev); });
+}
+
 uint32_t slam_core_image_get_width(slam_core_image_t *image) {
   return image->mat.cols;
 }
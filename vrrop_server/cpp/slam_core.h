@@ -20,8 +20,34 @@ typedef struct slam_core_camera_intrinsics {
   uint32_t height;
 } slam_core_camera_intrinsics_t;
 
+typedef enum slam_core_imu_filter {
+  SLAM_CORE_IMU_FILTER_NONE = 0,
+  SLAM_CORE_IMU_FILTER_MADGWICK = 1,
+  SLAM_CORE_IMU_FILTER_COMPLEMENTARY = 2,
+} slam_core_imu_filter_t;
+
+typedef struct slam_core_camera_config {
+  uint32_t width;
+  uint32_t height;
+  uint32_t fps;
+  /* Path of a RealSense advanced mode JSON preset, or NULL. */
+  const char *json_preset;
+  slam_core_imu_filter_t imu_filter;
+  /* Serial number of the camera to open, or NULL for the first one found. */
+  const char *serial;
+} slam_core_camera_config_t;
+
+/* RTAB-Map parameter, e.g. "Odom/Strategy" = "1". */
+typedef struct slam_core_parameter {
+  const char *key;
+  const char *value;
+} slam_core_parameter_t;
+
 typedef struct slam_core_odometry_event {
+  /* Capture time of the frame in seconds, identifies it in graph events. */
+  double stamp;
   float translation[3];
+  /* Quaternion as (w, x, y, z). */
   float rotation[4];
   slam_core_image_t *color;
   slam_core_image_t *depth;
@@ -30,13 +56,58 @@ typedef struct slam_core_odometry_event {
 typedef void (*slam_core_event_handler_t)(
     void *userdata, const slam_core_odometry_event_t *event);
 
-slam_core_t *slam_core_create();
+typedef struct slam_core_localization_event {
+  /* Node of the map the current frame was matched to. */
+  int32_t node_id;
+} slam_core_localization_event_t;
+
+typedef void (*slam_core_localization_handler_t)(
+    void *userdata, const slam_core_localization_event_t *event);
+
+typedef struct slam_core_node_pose {
+  int32_t id;
+  float translation[3];
+  /* Quaternion as (w, x, y, z). */
+  float rotation[4];
+} slam_core_node_pose_t;
+
+typedef struct slam_core_graph_event {
+  /* Node added to the map for the latest frame, or 0. */
+  int32_t node_id;
+  /* Stamp of that frame, as in the odometry event. */
+  double node_stamp;
+  /* Optimized poses of all nodes after a loop closure, otherwise empty. Nodes
+   * not listed were dropped from the map. */
+  const slam_core_node_pose_t *poses;
+  size_t pose_count;
+} slam_core_graph_event_t;
+
+typedef void (*slam_core_graph_handler_t)(
+    void *userdata, const slam_core_graph_event_t *event);
+
+/* Opens the map saved at `database_path`, or starts an empty one if it is NULL.
+ * Returns NULL on failure and writes the reason to `error`, which may be NULL.
+ */
+slam_core_t *slam_core_create(const slam_core_camera_config_t *camera_config,
+                              const slam_core_parameter_t *parameters,
+                              size_t parameter_count,
+                              const char *database_path, char *error,
+                              size_t error_size);
+/* Writes the map to `path` and continues mapping into a copy of it, so the file
+ * stays as saved. Safe to call from any thread. Returns 0 on success. */
+int slam_core_save_map(slam_core_t *p, const char *path, char *error,
+                       size_t error_size);
 void slam_core_delete(slam_core_t *p);
 void slam_core_get_intrinstics(slam_core_t *p,
                                slam_core_camera_intrinsics_t *color_intrinsics,
                                slam_core_camera_intrinsics_t *depth_intrinsics);
 void slam_core_register_odometry_event_handler(
     slam_core_t *p, void *userdata, slam_core_event_handler_t handler);
+/* Called on loop closures and, with a loaded map, when relocalized in it. */
+void slam_core_register_localization_event_handler(
+    slam_core_t *p, void *userdata, slam_core_localization_handler_t handler);
+void slam_core_register_graph_event_handler(slam_core_t *p, void *userdata,
+                                            slam_core_graph_handler_t handler);
 
 uint32_t slam_core_image_get_width(slam_core_image_t *image);
 uint32_t slam_core_image_get_height(slam_core_image_t *image);
@@ -0,0 +1,509 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use vrrop_common::{
+    auth::AuthKey,
+    codec::{ColorCodec, DepthCodec},
+    KeyframePolicy,
+};
+
+use crate::{congestion::QualityLadder, server::EncodingConfig};
+
+/// Name of the only source when none are configured.
+pub const DEFAULT_SOURCE: &str = "camera";
+
+/// Server configuration, loaded from a TOML file. Every key is optional and
+/// command line arguments override the file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub camera: CameraConfig,
+    pub slam: SlamConfig,
+    pub images: ImagesConfig,
+    pub keyframe: KeyframeConfig,
+    pub fusion: FusionConfig,
+    pub metrics: MetricsConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub paths: PathsConfig,
+    /// Cameras streamed by this server, see [`Config::sources`].
+    pub sources: Vec<SourceConfig>,
+}
+
+/// A camera with its own SLAM core, e.g. one per robot. All of them use the
+/// `[camera]` and `[slam]` settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Shown to clients, which subscribe to sources by name.
+    pub name: String,
+    /// RealSense serial number, the first camera found if unset.
+    #[serde(default)]
+    pub serial: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    /// Port of both the websocket and the UDP socket.
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 6677,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// RealSense advanced mode JSON preset, e.g. exported from the RealSense
+    /// Viewer.
+    pub json_preset: Option<PathBuf>,
+    pub imu_filter: ImuFilter,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            fps: 60,
+            json_preset: None,
+            imu_filter: ImuFilter::Madgwick,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImuFilter {
+    None,
+    Madgwick,
+    Complementary,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlamConfig {
+    /// RTAB-Map parameters for odometry, mapping and the IMU filter, e.g.
+    /// `"Odom/Strategy" = "1"`. Values are strings like in RTAB-Map's own
+    /// config files. Unset parameters keep RTAB-Map's defaults.
+    pub parameters: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesConfig {
+    /// Interval between images. With the motion policy this is the longest time
+    /// between two images.
+    pub interval_ms: u64,
+    #[serde(with = "display_from_str")]
+    pub color_codec: ColorCodec,
+    /// Fixed JPEG quality. Adapted to each client's bandwidth if unset.
+    pub jpeg_quality: Option<u8>,
+    #[serde(with = "display_from_str")]
+    pub depth_codec: DepthCodec,
+    /// Maximum depth error of lossy depth codecs.
+    pub depth_max_error_mm: u16,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 1000,
+            color_codec: ColorCodec::Jpeg,
+            jpeg_quality: None,
+            depth_codec: DepthCodec::Png,
+            depth_max_error_mm: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyframePolicyKind {
+    Interval,
+    Motion,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyframeConfig {
+    pub policy: KeyframePolicyKind,
+    /// Translation in meters that triggers a new keyframe.
+    pub min_translation: f64,
+    /// Rotation in degrees that triggers a new keyframe.
+    pub min_rotation: f64,
+    /// View overlap with the last keyframe below which a new keyframe is sent.
+    pub min_overlap: f64,
+    /// Shortest time between two keyframes.
+    pub min_interval_ms: u64,
+}
+
+impl Default for KeyframeConfig {
+    fn default() -> Self {
+        Self {
+            policy: KeyframePolicyKind::Motion,
+            min_translation: 0.3,
+            min_rotation: 15.0,
+            min_overlap: 0.7,
+            min_interval_ms: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FusionConfig {
+    /// Fuse the point cloud on the server and stream map deltas instead of images.
+    pub enabled: bool,
+    /// Grid size of the fused map in meters.
+    pub grid_size: f64,
+}
+
+impl Default for FusionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            grid_size: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics on `/metrics` and a JSON status page on `/status`.
+    pub enabled: bool,
+    pub bind_address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 6678,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Pre-shared token clients must prove to know. Without one anybody on the
+    /// network can connect and send commands.
+    pub token: Option<String>,
+}
+
+/// Serves `wss://` instead of `ws://` if both files are set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: Option<PathBuf>,
+    /// PEM private key.
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub stats_dir: PathBuf,
+    pub bag_dir: PathBuf,
+    /// Saved RTAB-Map databases.
+    pub map_dir: PathBuf,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        Self {
+            stats_dir: "stats".into(),
+            bag_dir: "bag".into(),
+            map_dir: "maps".into(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// A copy without secrets, to be saved next to recordings. Whether a token
+    /// was set stays visible.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if let Some(token) = &mut config.auth.token {
+            *token = "<redacted>".to_owned();
+        }
+        config
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        fn check(ok: bool, key: &str, message: &str) -> Result<()> {
+            if !ok {
+                bail!("{key}: {message}");
+            }
+            Ok(())
+        }
+        check(self.camera.width > 0, "camera.width", "must be positive")?;
+        check(self.camera.height > 0, "camera.height", "must be positive")?;
+        check(self.camera.fps > 0, "camera.fps", "must be positive")?;
+        check(
+            self.camera
+                .json_preset
+                .as_ref()
+                .is_none_or(|path| path.to_str().is_some_and(|s| !s.contains('\0'))),
+            "camera.json_preset",
+            "must be a valid UTF-8 path",
+        )?;
+        for (i, source) in self.sources.iter().enumerate() {
+            let key = format!("sources[{i}]");
+            check(
+                !source.name.is_empty() && !source.name.contains('\0'),
+                &format!("{key}.name"),
+                "must not be empty or contain NUL characters",
+            )?;
+            check(
+                !self.sources[..i].iter().any(|s| s.name == source.name),
+                &format!("{key}.name"),
+                "must be unique",
+            )?;
+            check(
+                source
+                    .serial
+                    .as_ref()
+                    .is_none_or(|s| !s.is_empty() && !s.contains('\0')),
+                &format!("{key}.serial"),
+                "must not be empty or contain NUL characters",
+            )?;
+        }
+        check(
+            self.sources.len() <= u16::MAX as usize,
+            "sources",
+            "too many sources",
+        )?;
+        for (key, value) in &self.slam.parameters {
+            check(
+                !key.is_empty() && !key.contains('\0') && !value.contains('\0'),
+                &format!("slam.parameters.{key}"),
+                "must not be empty or contain NUL characters",
+            )?;
+        }
+        check(
+            self.images.interval_ms > 0,
+            "images.interval_ms",
+            "must be positive",
+        )?;
+        if let Some(quality) = self.images.jpeg_quality {
+            check(
+                (1..=100).contains(&quality),
+                "images.jpeg_quality",
+                "must be between 1 and 100",
+            )?;
+        }
+        check(
+            self.keyframe.min_translation >= 0.0,
+            "keyframe.min_translation",
+            "must not be negative",
+        )?;
+        check(
+            self.keyframe.min_rotation >= 0.0,
+            "keyframe.min_rotation",
+            "must not be negative",
+        )?;
+        check(
+            (0.0..=1.0).contains(&self.keyframe.min_overlap),
+            "keyframe.min_overlap",
+            "must be between 0 and 1",
+        )?;
+        check(
+            self.keyframe.min_interval_ms <= self.images.interval_ms,
+            "keyframe.min_interval_ms",
+            "must not be longer than images.interval_ms",
+        )?;
+        check(
+            self.fusion.grid_size > 0.0,
+            "fusion.grid_size",
+            "must be positive",
+        )?;
+        check(
+            self.auth.token.as_ref().is_none_or(|t| !t.is_empty()),
+            "auth.token",
+            "must not be empty",
+        )?;
+        check(
+            self.tls.cert.is_some() == self.tls.key.is_some(),
+            if self.tls.cert.is_some() {
+                "tls.key"
+            } else {
+                "tls.cert"
+            },
+            "must be set together with tls.cert and tls.key",
+        )?;
+        check(
+            !self.metrics.enabled || self.metrics.port != self.server.port,
+            "metrics.port",
+            "must differ from server.port",
+        )?;
+        Ok(())
+    }
+
+    /// The configured sources, or a single one named `camera` using the first
+    /// camera found. Their index is the source id sent to clients.
+    pub fn sources(&self) -> Vec<SourceConfig> {
+        if self.sources.is_empty() {
+            vec![SourceConfig {
+                name: DEFAULT_SOURCE.to_owned(),
+                serial: None,
+            }]
+        } else {
+            self.sources.clone()
+        }
+    }
+
+    pub fn keyframe_policy(&self) -> KeyframePolicy {
+        let interval = Duration::from_millis(self.images.interval_ms);
+        match self.keyframe.policy {
+            KeyframePolicyKind::Interval => KeyframePolicy::Interval { interval },
+            KeyframePolicyKind::Motion => KeyframePolicy::Motion {
+                min_translation: self.keyframe.min_translation as f32,
+                min_rotation: self.keyframe.min_rotation.to_radians() as f32,
+                min_overlap: self.keyframe.min_overlap as f32,
+                min_interval: Duration::from_millis(self.keyframe.min_interval_ms),
+                max_interval: interval,
+            },
+        }
+    }
+
+    pub fn auth_key(&self) -> Option<AuthKey> {
+        self.auth
+            .token
+            .as_ref()
+            .map(|token| AuthKey::from_token(token).unwrap())
+    }
+
+    pub fn encoding(&self) -> EncodingConfig {
+        EncodingConfig {
+            ladder: self
+                .images
+                .jpeg_quality
+                .map(QualityLadder::fixed)
+                .unwrap_or_default(),
+            color_codec: self.images.color_codec,
+            depth_codec: self.images.depth_codec,
+            depth_max_error_mm: self.images.depth_max_error_mm,
+        }
+    }
+}
+
+/// Codecs are written with the same names as on the command line.
+mod display_from_str {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::{fmt::Display, str::FromStr};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_roundtrips() {
+        let text = Config::default().to_toml().unwrap();
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.server.port, 6677);
+        assert_eq!(config.images.depth_codec, DepthCodec::Png);
+    }
+
+    #[test]
+    fn redacted_config_has_no_token() {
+        let config = Config::parse("[auth]\ntoken = \"secret\"\n").unwrap();
+        let text = config.redacted().to_toml().unwrap();
+        assert!(!text.contains("secret"), "{text}");
+        assert_eq!(config.auth.token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = Config::parse("[images]\njpeg_quality = 0\n").unwrap_err();
+        assert!(err.to_string().contains("images.jpeg_quality"), "{err}");
+        let err = Config::parse("[images]\ncolor_codec = \"gif\"\n").unwrap_err();
+        assert!(format!("{err:#}").contains("color_codec"), "{err:#}");
+        let err = Config::parse("[fusion]\ngrid = 1.0\n").unwrap_err();
+        assert!(format!("{err:#}").contains("grid"), "{err:#}");
+    }
+
+    #[test]
+    fn slam_parameters_are_strings() {
+        let config = Config::parse(
+            "[camera]\nimu_filter = \"none\"\n[slam.parameters]\n\"Odom/Strategy\" = \"1\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.camera.imu_filter, ImuFilter::None);
+        assert_eq!(config.slam.parameters["Odom/Strategy"], "1");
+        assert!(Config::parse("[slam.parameters]\n\"Odom/Strategy\" = 1\n").is_err());
+    }
+
+    #[test]
+    fn sources_have_unique_names() {
+        assert_eq!(Config::default().sources()[0].name, DEFAULT_SOURCE);
+        let text =
+            "[[sources]]\nname = \"front\"\nserial = \"123\"\n[[sources]]\nname = \"rear\"\n";
+        let config = Config::parse(text).unwrap();
+        let names: Vec<_> = config.sources().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["front", "rear"]);
+        let config = Config::parse(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(config.sources[0].serial.as_deref(), Some("123"));
+        let err =
+            Config::parse("[[sources]]\nname = \"a\"\n[[sources]]\nname = \"a\"\n").unwrap_err();
+        assert!(err.to_string().contains("sources[1].name"), "{err}");
+    }
+}
@@ -0,0 +1,229 @@
+use std::time::{Duration, Instant};
+
+/// One step of the quality ladder. Lower rungs trade image quality for bandwidth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rung {
+    pub jpeg_quality: u8,
+    /// Both images are downscaled by this factor before encoding.
+    pub scale: u32,
+    /// Only every `frame_interval`-th frame is sent.
+    pub frame_interval: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct QualityLadder {
+    rungs: Vec<Rung>,
+    initial: usize,
+}
+
+impl Default for QualityLadder {
+    fn default() -> Self {
+        Self {
+            rungs: vec![
+                Rung {
+                    jpeg_quality: 85,
+                    scale: 1,
+                    frame_interval: 1,
+                },
+                Rung {
+                    jpeg_quality: 70,
+                    scale: 1,
+                    frame_interval: 1,
+                },
+                Rung {
+                    jpeg_quality: 50,
+                    scale: 1,
+                    frame_interval: 1,
+                },
+                Rung {
+                    jpeg_quality: 50,
+                    scale: 2,
+                    frame_interval: 1,
+                },
+                Rung {
+                    jpeg_quality: 40,
+                    scale: 2,
+                    frame_interval: 2,
+                },
+            ],
+            initial: 1,
+        }
+    }
+}
+
+impl QualityLadder {
+    /// A single-rung ladder, which disables adaptation.
+    pub fn fixed(jpeg_quality: u8) -> Self {
+        Self {
+            rungs: vec![Rung {
+                jpeg_quality,
+                scale: 1,
+                frame_interval: 1,
+            }],
+            initial: 0,
+        }
+    }
+
+    pub fn rungs(&self) -> &[Rung] {
+        &self.rungs
+    }
+
+    pub fn rung(&self, idx: usize) -> &Rung {
+        &self.rungs[idx]
+    }
+
+    pub fn initial(&self) -> usize {
+        self.initial
+    }
+}
+
+const LATENCY_HIGH: Duration = Duration::from_millis(500);
+const LATENCY_LOW: Duration = Duration::from_millis(250);
+const SEND_BUDGET: Duration = Duration::from_millis(200);
+const CLEAN_FRAMES_TO_UPGRADE: u32 = 5;
+const DOWNGRADE_HOLD: Duration = Duration::from_secs(1);
+const UPGRADE_HOLD: Duration = Duration::from_secs(5);
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+/// Picks a ladder rung for a single client from its measured throughput,
+/// send-queue depth and the latency the client reports back.
+#[derive(Debug)]
+pub struct CongestionController {
+    ladder: QualityLadder,
+    rung: usize,
+    frame_counter: u64,
+    throughput: Option<f64>,
+    client_latency: Option<Duration>,
+    clean_frames: u32,
+    last_change: Instant,
+}
+
+impl CongestionController {
+    pub fn new(ladder: QualityLadder) -> Self {
+        Self {
+            rung: ladder.initial(),
+            ladder,
+            frame_counter: 0,
+            throughput: None,
+            client_latency: None,
+            clean_frames: 0,
+            last_change: Instant::now(),
+        }
+    }
+
+    pub fn rung_index(&self) -> usize {
+        self.rung
+    }
+
+    pub fn rung(&self) -> &Rung {
+        self.ladder.rung(self.rung)
+    }
+
+    /// Estimated throughput in bytes per second.
+    pub fn throughput(&self) -> Option<f64> {
+        self.throughput
+    }
+
+    /// Decides whether the next frame should be sent at all at the current rung.
+    pub fn should_send(&mut self) -> bool {
+        let interval = self.rung().frame_interval.max(1) as u64;
+        let send = self.frame_counter.is_multiple_of(interval);
+        self.frame_counter += 1;
+        send
+    }
+
+    pub fn on_sent(&mut self, bytes: usize, elapsed: Duration, queue_depth: usize) {
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+        self.throughput = Some(match self.throughput {
+            Some(prev) => prev + THROUGHPUT_SMOOTHING * (sample - prev),
+            None => sample,
+        });
+        let latency_high = self.client_latency.is_some_and(|l| l > LATENCY_HIGH);
+        let latency_low = self.client_latency.is_none_or(|l| l < LATENCY_LOW);
+        if queue_depth > 0 || elapsed > SEND_BUDGET || latency_high {
+            self.downgrade();
+        } else if latency_low && self.has_headroom(bytes) {
+            self.clean_frames += 1;
+            if self.clean_frames >= CLEAN_FRAMES_TO_UPGRADE {
+                self.upgrade();
+            }
+        } else {
+            self.clean_frames = 0;
+        }
+    }
+
+    pub fn on_lagged(&mut self) {
+        self.downgrade();
+    }
+
+    pub fn on_feedback(&mut self, latency: Duration) {
+        self.client_latency = Some(latency);
+    }
+
+    fn has_headroom(&self, bytes: usize) -> bool {
+        let Some(throughput) = self.throughput else {
+            return false;
+        };
+        // Leave room for the larger frames of the next rung.
+        throughput * SEND_BUDGET.as_secs_f64() > bytes as f64 * 2.0
+    }
+
+    fn downgrade(&mut self) {
+        self.clean_frames = 0;
+        if self.rung + 1 >= self.ladder.rungs().len() || self.last_change.elapsed() < DOWNGRADE_HOLD
+        {
+            return;
+        }
+        self.rung += 1;
+        self.last_change = Instant::now();
+    }
+
+    fn upgrade(&mut self) {
+        self.clean_frames = 0;
+        if self.rung == 0 || self.last_change.elapsed() < UPGRADE_HOLD {
+            return;
+        }
+        self.rung -= 1;
+        self.last_change = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> CongestionController {
+        let mut cc = CongestionController::new(QualityLadder::default());
+        cc.last_change = Instant::now() - Duration::from_secs(60);
+        cc
+    }
+
+    #[test]
+    fn downgrades_when_queue_builds_up() {
+        let mut cc = controller();
+        let initial = cc.rung_index();
+        cc.on_sent(100_000, Duration::from_millis(10), 1);
+        assert_eq!(cc.rung_index(), initial + 1);
+        // Held for a while after a change.
+        cc.on_lagged();
+        assert_eq!(cc.rung_index(), initial + 1);
+    }
+
+    #[test]
+    fn upgrades_after_clean_frames() {
+        let mut cc = controller();
+        let initial = cc.rung_index();
+        for _ in 0..CLEAN_FRAMES_TO_UPGRADE {
+            cc.on_sent(10_000, Duration::from_millis(5), 0);
+        }
+        assert_eq!(cc.rung_index(), initial - 1);
+    }
+
+    #[test]
+    fn skips_frames_on_low_rungs() {
+        let mut cc = controller();
+        cc.rung = cc.ladder.rungs().len() - 1;
+        let sent = (0..4).filter(|_| cc.should_send()).count();
+        assert_eq!(sent, 2);
+    }
+}
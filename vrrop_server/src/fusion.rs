@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use tokio::{sync::broadcast, task::JoinHandle};
+use vrrop_common::decoded::ImagesMessage;
+use vrrop_common::pointcloud::PointCloud;
+use vrrop_common::{MapDeltaMessage, SourceId, WebSocketServerMessage};
+
+use crate::metrics::{metrics, Channel};
+use crate::server::ImageFrame;
+
+/// Serialized [`WebSocketServerMessage::MapDelta`] shared by all clients, with
+/// the source it is of.
+pub type SerializedDelta = (SourceId, Arc<Vec<u8>>);
+
+/// A delta as broadcast, numbered so a subscriber can skip the ones its
+/// snapshot already contains.
+pub type SequencedDelta = (u64, SerializedDelta);
+
+/// Held only briefly, so subscribing doesn't wait for a merge.
+struct FusionState {
+    /// Map epoch of each source that was reset, the others are at 0.
+    epochs: BTreeMap<SourceId, u64>,
+    /// Sequence number of the last delta sent.
+    sequence: u64,
+    delta_sender: broadcast::Sender<SequencedDelta>,
+}
+
+impl FusionState {
+    fn epoch(&self, source: SourceId) -> u64 {
+        self.epochs.get(&source).copied().unwrap_or(0)
+    }
+
+    fn send(&mut self, delta: SerializedDelta) {
+        self.sequence += 1;
+        // Nobody listening is fine, late joiners get a snapshot.
+        let _ = self.delta_sender.send((self.sequence, delta));
+    }
+}
+
+/// Fuses the images into a point cloud per source on the server and streams
+/// the changes to the clients, so they don't have to receive and merge full
+/// frames.
+pub struct Fusion {
+    grid_size: f32,
+    state: Mutex<FusionState>,
+    /// One map per source with the epoch it is of, created by its first frame.
+    /// Only locked on blocking threads, always before `state`.
+    clouds: Mutex<BTreeMap<SourceId, (u64, PointCloud)>>,
+}
+
+impl Fusion {
+    pub fn new(grid_size: f32) -> Arc<Self> {
+        let (delta_sender, _) = broadcast::channel(16);
+        Arc::new(Self {
+            grid_size,
+            state: Mutex::new(FusionState {
+                epochs: BTreeMap::new(),
+                sequence: 0,
+                delta_sender,
+            }),
+            clouds: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn spawn(
+        self: &Arc<Self>,
+        mut image_receiver: broadcast::Receiver<Arc<ImageFrame>>,
+    ) -> JoinHandle<()> {
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let frame = match image_receiver.recv().await {
+                    Ok(frame) => frame,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics().lagged(Channel::FusionImages, n);
+                        eprintln!("Fusion dropped {n} frames");
+                        continue;
+                    }
+                };
+                let res = async {
+                    let msg = frame.decode().await?;
+                    let this = Arc::clone(&this);
+                    tokio::task::spawn_blocking(move || this.merge(&msg)).await?
+                }
+                .await;
+                if let Err(e) = res {
+                    eprintln!("Error fusing images: {e:?}");
+                }
+            }
+        })
+    }
+
+    fn merge(&self, msg: &ImagesMessage) -> Result<()> {
+        let source = msg.odometry.source;
+        let epoch = self.state.lock().unwrap().epoch(source);
+        // Frames queued before a reset belong to the old map.
+        if msg.odometry.epoch != epoch {
+            return Ok(());
+        }
+        let mut clouds = self.clouds.lock().unwrap();
+        let (cloud_epoch, cloud) = clouds
+            .entry(source)
+            .or_insert_with(|| (epoch, PointCloud::new(self.grid_size)));
+        // The reset already sent the clients an empty map.
+        if *cloud_epoch != epoch {
+            *cloud_epoch = epoch;
+            *cloud = PointCloud::new(self.grid_size);
+        }
+        let (delta, _) = cloud.merge_images_msg_with_delta(msg);
+        let delta = cloud.encode_delta(&delta);
+        if delta.cells.is_empty() {
+            return Ok(());
+        }
+        let delta = serialize(epoch, source, delta)?;
+        let mut state = self.state.lock().unwrap();
+        // A reset while merging makes the delta stale.
+        if state.epoch(source) == epoch {
+            state.send(delta);
+        }
+        Ok(())
+    }
+
+    /// Returns a receiver for all following deltas, then a snapshot of each
+    /// current map and the sequence number of the last delta it contains.
+    pub async fn subscribe(
+        self: &Arc<Self>,
+    ) -> Result<(
+        broadcast::Receiver<SequencedDelta>,
+        u64,
+        Vec<SerializedDelta>,
+    )> {
+        // Subscribed first, so no delta falls between it and the snapshot.
+        let receiver = self.state.lock().unwrap().delta_sender.subscribe();
+        let this = Arc::clone(self);
+        let (sequence, snapshots) = tokio::task::spawn_blocking(move || this.snapshot()).await??;
+        Ok((receiver, sequence, snapshots))
+    }
+
+    fn snapshot(&self) -> Result<(u64, Vec<SerializedDelta>)> {
+        let clouds = self.clouds.lock().unwrap();
+        // Merges send while holding `clouds`, so this is all the snapshot has.
+        let (sequence, epochs) = {
+            let state = self.state.lock().unwrap();
+            (state.sequence, state.epochs.clone())
+        };
+        let snapshots = clouds
+            .iter()
+            .filter(|(source, (epoch, _))| epochs.get(source).copied().unwrap_or(0) == *epoch)
+            .map(|(source, (epoch, cloud))| serialize(*epoch, *source, cloud.snapshot()))
+            .collect::<Result<_>>()?;
+        Ok((sequence, snapshots))
+    }
+
+    /// Starts a new map of `source` for `epoch`. Clients receive an empty
+    /// snapshot.
+    pub fn reset(&self, source: SourceId, epoch: u64) -> Result<()> {
+        let empty = serialize(epoch, source, PointCloud::new(self.grid_size).snapshot())?;
+        let mut state = self.state.lock().unwrap();
+        state.epochs.insert(source, epoch);
+        state.send(empty);
+        Ok(())
+    }
+}
+
+fn serialize(epoch: u64, source: SourceId, delta: MapDeltaMessage) -> Result<SerializedDelta> {
+    let delta = MapDeltaMessage {
+        epoch,
+        source,
+        ..delta
+    };
+    Ok((
+        source,
+        Arc::new(bincode::serialize(&WebSocketServerMessage::MapDelta(
+            delta,
+        ))?),
+    ))
+}
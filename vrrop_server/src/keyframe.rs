@@ -0,0 +1,242 @@
+use std::{
+    ops::Deref,
+    time::{Duration, SystemTime},
+};
+
+use image::{ImageBuffer, Luma};
+use nalgebra::{Isometry3, Point3, Translation3};
+use vrrop_common::{CameraIntrinsics, KeyframePolicy};
+
+use crate::server::OdometryMessage;
+
+/// A [`crate::slam_core::DepthImage`], or one owning its data.
+type DepthImage<C> = ImageBuffer<Luma<u16>, C>;
+
+/// Pixel stride used when sampling the depth image for the overlap estimate.
+const OVERLAP_SAMPLE_STRIDE: u32 = 16;
+const DEPTH_UNIT: f32 = 0.001;
+
+struct Keyframe {
+    stamp: SystemTime,
+    pose: Isometry3<f32>,
+    /// Sampled points of the keyframe in world coordinates.
+    points: Vec<Point3<f32>>,
+}
+
+pub struct KeyframeSelector {
+    last: Option<Keyframe>,
+}
+
+impl KeyframeSelector {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Returns true and remembers the frame as the new keyframe if it should be sent.
+    pub fn select(
+        &mut self,
+        policy: &KeyframePolicy,
+        odometry: &OdometryMessage,
+        depth: &DepthImage<impl Deref<Target = [u16]>>,
+        depth_intrinsics: &CameraIntrinsics,
+    ) -> bool {
+        let pose =
+            Isometry3::from_parts(Translation3::from(odometry.translation), odometry.rotation);
+        if let Some(last) = &self.last {
+            let elapsed = odometry
+                .stamp
+                .duration_since(last.stamp)
+                .unwrap_or(Duration::ZERO);
+            let send = match policy {
+                KeyframePolicy::Interval { interval } => elapsed >= *interval,
+                KeyframePolicy::Motion {
+                    min_translation,
+                    min_rotation,
+                    min_overlap,
+                    min_interval,
+                    max_interval,
+                } => {
+                    if elapsed < *min_interval {
+                        false
+                    } else if elapsed >= *max_interval {
+                        true
+                    } else {
+                        let delta = last.pose.inverse() * pose;
+                        delta.translation.vector.norm() >= *min_translation
+                            || delta.rotation.angle() >= *min_rotation
+                            || overlap(&last.points, &pose, depth_intrinsics) < *min_overlap
+                    }
+                }
+            };
+            if !send {
+                return false;
+            }
+        }
+        self.last = Some(Keyframe {
+            stamp: odometry.stamp,
+            pose,
+            points: sample_points(depth, depth_intrinsics, &pose),
+        });
+        true
+    }
+}
+
+fn sample_points(
+    depth: &DepthImage<impl Deref<Target = [u16]>>,
+    intrinsics: &CameraIntrinsics,
+    pose: &Isometry3<f32>,
+) -> Vec<Point3<f32>> {
+    let mut points = Vec::new();
+    for y in (0..depth.height()).step_by(OVERLAP_SAMPLE_STRIDE as usize) {
+        for x in (0..depth.width()).step_by(OVERLAP_SAMPLE_STRIDE as usize) {
+            let d = depth.get_pixel(x, y)[0] as f32 * DEPTH_UNIT;
+            if d == 0.0 {
+                continue;
+            }
+            // Same camera convention as the client: x forward, y left, z up.
+            let py = -(x as f32 - intrinsics.cx) / intrinsics.fx;
+            let pz = -(y as f32 - intrinsics.cy) / intrinsics.fy;
+            points.push(pose * Point3::new(d, py * d, pz * d));
+        }
+    }
+    points
+}
+
+/// Fraction of the keyframe's points that are still inside the current view.
+fn overlap(points: &[Point3<f32>], pose: &Isometry3<f32>, intrinsics: &CameraIntrinsics) -> f32 {
+    if points.is_empty() {
+        return 1.0;
+    }
+    let inv = pose.inverse();
+    let visible = points
+        .iter()
+        .filter(|p| {
+            let p = inv * *p;
+            if p.x <= 0.0 {
+                return false;
+            }
+            let u = intrinsics.fx * -p.y / p.x + intrinsics.cx;
+            let v = intrinsics.fy * -p.z / p.x + intrinsics.cy;
+            (0.0..intrinsics.width as f32).contains(&u)
+                && (0.0..intrinsics.height as f32).contains(&v)
+        })
+        .count();
+    visible as f32 / points.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{UnitQuaternion, Vector3};
+
+    use super::*;
+
+    const INTRINSICS: CameraIntrinsics = CameraIntrinsics {
+        width: 64,
+        height: 48,
+        fx: 50.0,
+        fy: 50.0,
+        cx: 32.0,
+        cy: 24.0,
+    };
+
+    /// A wall 2 m in front of the camera.
+    fn wall() -> DepthImage<Vec<u16>> {
+        ImageBuffer::from_pixel(INTRINSICS.width, INTRINSICS.height, Luma([2000]))
+    }
+
+    fn odometry(ms: u64, forward: f32, yaw: f32) -> OdometryMessage {
+        OdometryMessage {
+            epoch: 0,
+            source: 0,
+            stamp: SystemTime::UNIX_EPOCH + Duration::from_millis(ms),
+            translation: Vector3::new(forward, 0.0, 0.0),
+            rotation: UnitQuaternion::from_euler_angles(0.0, 0.0, yaw),
+        }
+    }
+
+    fn motion() -> KeyframePolicy {
+        KeyframePolicy::Motion {
+            min_translation: 0.2,
+            min_rotation: 0.3,
+            min_overlap: 0.5,
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_secs(1),
+        }
+    }
+
+    fn selects(
+        selector: &mut KeyframeSelector,
+        policy: &KeyframePolicy,
+        odometry: OdometryMessage,
+    ) -> bool {
+        selector.select(policy, &odometry, &wall(), &INTRINSICS)
+    }
+
+    #[test]
+    fn first_frame_is_a_keyframe() {
+        for policy in [
+            motion(),
+            KeyframePolicy::Interval {
+                interval: Duration::from_secs(1),
+            },
+        ] {
+            assert!(selects(
+                &mut KeyframeSelector::new(),
+                &policy,
+                odometry(0, 0.0, 0.0)
+            ));
+        }
+    }
+
+    #[test]
+    fn interval() {
+        let policy = KeyframePolicy::Interval {
+            interval: Duration::from_millis(500),
+        };
+        let mut selector = KeyframeSelector::new();
+        assert!(selects(&mut selector, &policy, odometry(0, 0.0, 0.0)));
+        assert!(!selects(&mut selector, &policy, odometry(499, 5.0, 0.0)));
+        assert!(selects(&mut selector, &policy, odometry(500, 0.0, 0.0)));
+        // Counted from the last keyframe.
+        assert!(!selects(&mut selector, &policy, odometry(900, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn distance_and_angle_thresholds() {
+        let policy = motion();
+        let mut selector = KeyframeSelector::new();
+        assert!(selects(&mut selector, &policy, odometry(0, 0.0, 0.0)));
+        assert!(!selects(&mut selector, &policy, odometry(200, 0.1, 0.0)));
+        assert!(selects(&mut selector, &policy, odometry(400, 0.25, 0.0)));
+        assert!(!selects(&mut selector, &policy, odometry(600, 0.25, 0.2)));
+        assert!(selects(&mut selector, &policy, odometry(800, 0.25, 0.35)));
+    }
+
+    #[test]
+    fn time_thresholds() {
+        let policy = motion();
+        let mut selector = KeyframeSelector::new();
+        assert!(selects(&mut selector, &policy, odometry(0, 0.0, 0.0)));
+        // Moved far, but too soon.
+        assert!(!selects(&mut selector, &policy, odometry(50, 1.0, 0.0)));
+        // Didn't move, sent once the max interval passed.
+        assert!(!selects(&mut selector, &policy, odometry(999, 0.0, 0.0)));
+        assert!(selects(&mut selector, &policy, odometry(1000, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn overlap_threshold() {
+        let policy = KeyframePolicy::Motion {
+            min_translation: f32::INFINITY,
+            min_rotation: f32::INFINITY,
+            min_overlap: 0.5,
+            min_interval: Duration::ZERO,
+            max_interval: Duration::from_secs(10),
+        };
+        let mut selector = KeyframeSelector::new();
+        assert!(selects(&mut selector, &policy, odometry(0, 0.0, 0.0)));
+        // The view is about 65° wide, turning by 20° keeps most of the wall.
+        assert!(!selects(&mut selector, &policy, odometry(100, 0.0, 0.35)));
+        assert!(selects(&mut selector, &policy, odometry(200, 0.0, 0.8)));
+    }
+}
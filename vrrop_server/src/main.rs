@@ -1,46 +1,80 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Parser;
+use config::{Config, KeyframePolicyKind};
+use fusion::Fusion;
 use futures::pin_mut;
+use keyframe::KeyframeSelector;
+use maps::MapStore;
+use metrics::metrics;
+use pose_graph::PoseGraph;
 use server::{
-    encode_images_message, encode_odometry_message, Callbacks, ImagesMessage, OdometryMessage,
-    Server,
+    encode_odometry_message, Callbacks, ImageFrame, ImagesMessage, OdometryMessage, Server,
 };
 use slam_core::SlamCore;
-use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::{
     path::Path,
-    sync::Arc,
-    time::{Duration, UNIX_EPOCH},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 use tokio::select;
 use tokio::sync::{broadcast, mpsc};
-use tokio::time::sleep_until;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, MissedTickBehavior};
+use tokio_rustls::TlsAcceptor;
+use vrrop_common::auth::AuthKey;
 use vrrop_common::bag::{self, Player, Recorder};
-use vrrop_common::{Command, Stats};
+use vrrop_common::codec::{ColorCodec, DepthCodec};
+use vrrop_common::{Command, KeyframePolicy, PoseGraphMessage, SourceId};
 
+mod config;
+mod congestion;
+mod fusion;
+mod keyframe;
+mod maps;
+mod metrics;
+mod pose_graph;
+mod send_queue;
 mod server;
+mod session;
 mod slam_core;
 mod slam_core_sys;
+mod stats;
+mod tls;
+
+/// Suggested to clients when the server stops, expecting to be restarted.
+const RESTART_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Without odometry events for this long the camera is considered stalled or
+/// unplugged.
+const CAMERA_STALL_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often to check for a stalled camera and retry starting the SLAM core.
+const CAMERA_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// Camera stamps further behind the server clock are taken to be on a clock
+/// of their own and aren't used as capture times.
+const MAX_CAPTURE_AGE: Duration = Duration::from_secs(10);
 
 #[derive(clap::Parser)]
 struct ServeArgs {
-    #[clap(long, short, default_value_t = 6677)]
-    port: u16,
+    #[clap(long, short)]
+    port: Option<u16>,
+    /// Saved map to localize in instead of starting a new one
+    #[clap(long)]
+    map: Option<String>,
 }
 
 #[derive(clap::Parser)]
 struct RecordArgs {
-    #[clap(long, short, default_value = "bag")]
-    bag_dir: PathBuf,
+    #[clap(long, short)]
+    bag_dir: Option<PathBuf>,
 }
 
 #[derive(clap::Parser)]
 struct ReplayArgs {
-    #[clap(long, short, default_value = "bag")]
-    bag_dir: PathBuf,
-    #[clap(long, short, default_value_t = 6677)]
-    port: u16,
+    #[clap(long, short)]
+    bag_dir: Option<PathBuf>,
+    #[clap(long, short)]
+    port: Option<u16>,
     #[clap(long = "loop", short, default_value_t = false)]
     loop_: bool,
 }
@@ -50,167 +84,632 @@ enum Subcommand {
     Serve(ServeArgs),
     Record(RecordArgs),
     Replay(ReplayArgs),
+    /// Print the configuration after applying the config file and command line arguments
+    PrintDefaultConfig,
 }
 
+/// Command line arguments override the corresponding keys of the config file.
 #[derive(clap::Parser)]
 struct Args {
     #[clap(subcommand)]
     subcommand: Subcommand,
-    #[clap(long, default_value = "1000")]
-    image_interval: u64,
+    /// TOML config file
+    #[clap(long, short)]
+    config: Option<PathBuf>,
+    /// Address the websocket and UDP sockets are bound to
+    #[clap(long)]
+    bind_address: Option<IpAddr>,
+    /// Interval between images in milliseconds. With the motion policy this is the
+    /// longest time between two images.
+    #[clap(long)]
+    image_interval: Option<u64>,
+    #[clap(long, value_enum)]
+    keyframe_policy: Option<KeyframePolicyKind>,
+    /// Translation in meters that triggers a new keyframe
+    #[clap(long)]
+    keyframe_min_translation: Option<f64>,
+    /// Rotation in degrees that triggers a new keyframe
+    #[clap(long)]
+    keyframe_min_rotation: Option<f64>,
+    /// View overlap with the last keyframe below which a new keyframe is sent
+    #[clap(long)]
+    keyframe_min_overlap: Option<f64>,
+    /// Shortest time between two keyframes in milliseconds
+    #[clap(long)]
+    keyframe_min_interval: Option<u64>,
+    /// Preferred color codec, used for clients that support it
+    #[clap(long)]
+    color_codec: Option<ColorCodec>,
+    /// Use a fixed JPEG quality instead of adapting it to each client's bandwidth
+    #[clap(long)]
+    jpeg_quality: Option<u8>,
+    /// Preferred depth codec, used for clients that support it
+    #[clap(long)]
+    depth_codec: Option<DepthCodec>,
+    /// Maximum depth error of lossy depth codecs
+    #[clap(long)]
+    depth_max_error_mm: Option<u16>,
+    /// Fuse the point cloud on the server and stream map deltas instead of images
+    #[clap(long, default_value_t = false)]
+    fusion: bool,
+    /// Grid size of the fused map in meters
+    #[clap(long)]
+    fusion_grid_size: Option<f64>,
+    /// Directory statistics sent by clients are saved to
+    #[clap(long)]
+    stats_dir: Option<PathBuf>,
+    /// Directory maps are saved to and loaded from
+    #[clap(long)]
+    map_dir: Option<PathBuf>,
+    /// Pre-shared token clients must authenticate with
+    #[clap(long, env = "VRROP_AUTH_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+    /// PEM certificate chain to serve wss:// with
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[clap(long)]
+    tls_key: Option<PathBuf>,
 }
 
-fn init_slam_core<'a>(
-    image_sender: broadcast::Sender<vrrop_common::ImagesMessage>,
+impl Args {
+    fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        fn set<T: Clone>(dest: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *dest = value.clone();
+            }
+        }
+        set(&mut config.server.bind_address, &self.bind_address);
+        set(&mut config.images.interval_ms, &self.image_interval);
+        set(&mut config.keyframe.policy, &self.keyframe_policy);
+        set(
+            &mut config.keyframe.min_translation,
+            &self.keyframe_min_translation,
+        );
+        set(
+            &mut config.keyframe.min_rotation,
+            &self.keyframe_min_rotation,
+        );
+        set(&mut config.keyframe.min_overlap, &self.keyframe_min_overlap);
+        set(
+            &mut config.keyframe.min_interval_ms,
+            &self.keyframe_min_interval,
+        );
+        set(&mut config.images.color_codec, &self.color_codec);
+        if self.jpeg_quality.is_some() {
+            config.images.jpeg_quality = self.jpeg_quality;
+        }
+        set(&mut config.images.depth_codec, &self.depth_codec);
+        set(
+            &mut config.images.depth_max_error_mm,
+            &self.depth_max_error_mm,
+        );
+        config.fusion.enabled |= self.fusion;
+        set(&mut config.fusion.grid_size, &self.fusion_grid_size);
+        set(&mut config.paths.stats_dir, &self.stats_dir);
+        set(&mut config.paths.map_dir, &self.map_dir);
+        if self.auth_token.is_some() {
+            config.auth.token = self.auth_token.clone();
+        }
+        if self.tls_cert.is_some() {
+            config.tls.cert = self.tls_cert.clone();
+        }
+        if self.tls_key.is_some() {
+            config.tls.key = self.tls_key.clone();
+        }
+        match &self.subcommand {
+            Subcommand::Serve(args) => set(&mut config.server.port, &args.port),
+            Subcommand::Record(args) => set(&mut config.paths.bag_dir, &args.bag_dir),
+            Subcommand::Replay(args) => {
+                set(&mut config.server.port, &args.port);
+                set(&mut config.paths.bag_dir, &args.bag_dir);
+            }
+            Subcommand::PrintDefaultConfig => {}
+        }
+        config
+            .validate()
+            .context("Invalid configuration after applying command line arguments")?;
+        Ok(config)
+    }
+}
+
+/// Where the SLAM core's output goes.
+#[derive(Clone)]
+struct SlamOutputs {
+    image_sender: broadcast::Sender<Arc<ImageFrame>>,
     odometry_sender: broadcast::Sender<vrrop_common::OdometryMessage>,
-    image_interval: Duration,
+    /// Loop closure corrections, not tracked without it.
+    pose_graph_sender: Option<mpsc::UnboundedSender<PoseGraphMessage>>,
+}
+
+/// Poses are stamped with the map `epoch` and `source`. `last_event` is the
+/// time of the last odometry event, to detect a stalled camera.
+fn init_slam_core<'a>(
+    config: &Config,
+    source: SourceId,
+    epoch: u64,
+    map: Option<&Path>,
+    outputs: SlamOutputs,
+    keyframe_policy: Arc<Mutex<KeyframePolicy>>,
+    last_event: Arc<Mutex<Instant>>,
 ) -> Result<SlamCore<'a>> {
-    let mut slam_core = SlamCore::new();
-    let last_image_send = Arc::new(std::sync::Mutex::new(std::time::SystemTime::now()));
+    let serial = config.sources()[source as usize].serial.clone();
+    let mut slam_core = SlamCore::new(&config.camera, serial.as_deref(), &config.slam, map)?;
+    *last_event.lock().unwrap() = Instant::now();
+    let keyframe_selector = Mutex::new(KeyframeSelector::new());
     let color_intrinsics = *slam_core.color_intrinsics();
     let depth_intrinsics = *slam_core.depth_intrinsics();
     println!("color_intrinsics: {:?}", color_intrinsics);
     println!("depth_intrinsics: {:?}", depth_intrinsics);
-    let (raw_image_sender, mut raw_image_receiver) = mpsc::unbounded_channel();
+    let SlamOutputs {
+        image_sender,
+        odometry_sender,
+        pose_graph_sender,
+    } = outputs;
+    let pose_graph = Arc::new(Mutex::new(PoseGraph::new(epoch, source)));
+    if let Some(pose_graph_sender) = pose_graph_sender {
+        let pose_graph = pose_graph.clone();
+        slam_core.register_graph_event_handler(move |ev| {
+            if let Some(message) = pose_graph.lock().unwrap().update(&ev) {
+                let _ = pose_graph_sender.send(message);
+            }
+        });
+    }
     slam_core.register_odometry_event_handler(move |ev| {
+        *last_event.lock().unwrap() = Instant::now();
         let stamp = std::time::SystemTime::now();
         let pose_is_finite = ev.translation.iter().all(|x| x.is_finite())
             && ev.rotation.as_vector().iter().all(|x| x.is_finite());
+        metrics().slam_pose(pose_is_finite);
         if !pose_is_finite {
             return;
         }
         let odometry = OdometryMessage {
+            epoch,
+            source,
             stamp,
             translation: ev.translation,
             rotation: ev.rotation,
         };
+        pose_graph.lock().unwrap().record(ev.stamp, &odometry);
         match odometry_sender.send(encode_odometry_message(&odometry)) {
-            Ok(_) => {}
+            Ok(_) => metrics().odometry_published(),
             Err(_) => {
                 // eprintln!("odometry message dropped!");
             }
         }
-        {
-            let mut guard = last_image_send.lock().unwrap();
-            if stamp.duration_since(*guard).unwrap() < image_interval {
+        if let Some((color, depth)) = ev.color_image.zip(ev.depth_image) {
+            let policy = keyframe_policy.lock().unwrap().clone();
+            let selected = keyframe_selector.lock().unwrap().select(
+                &policy,
+                &odometry,
+                &depth,
+                &depth_intrinsics,
+            );
+            if !selected {
                 return;
             }
-            *guard = stamp;
-        }
-        if let Some((color, depth)) = ev.color_image.zip(ev.depth_image) {
-            raw_image_sender
-                .send(ImagesMessage {
-                    odometry,
-                    color: Arc::new(color),
-                    color_intrinsics,
-                    depth: Arc::new(depth),
-                    depth_intrinsics,
-                })
-                .unwrap();
-        }
-    });
-    tokio::spawn(async move {
-        while let Some(msg) = raw_image_receiver.recv().await {
-            let encoded = encode_images_message(&msg).await?;
-            image_sender.send(encoded).unwrap();
+            let capture = Duration::try_from_secs_f64(ev.stamp)
+                .ok()
+                .map(|since_epoch| UNIX_EPOCH + since_epoch)
+                .filter(|&capture| {
+                    stamp
+                        .duration_since(capture)
+                        .is_ok_and(|age| age < MAX_CAPTURE_AGE)
+                });
+            let frame = ImageFrame::raw(ImagesMessage {
+                odometry,
+                color: Arc::new(color),
+                color_intrinsics,
+                depth: Arc::new(depth),
+                depth_intrinsics,
+                capture,
+            });
+            match image_sender.send(Arc::new(frame)) {
+                Ok(_) => metrics().images_published(),
+                Err(_) => {
+                    // eprintln!("images message dropped!");
+                }
+            }
         }
-        anyhow::Ok(())
     });
     Ok(slam_core)
 }
 
-fn save_stats(stats: Stats, dir: &Path) -> Result<()> {
-    std::fs::create_dir_all(dir)?;
-    let image_stats_path = dir.join("images.csv");
-    let mut image_stats_dest = std::fs::File::create(image_stats_path)?;
-    writeln!(image_stats_dest, "stamp,size,latency")?;
-    for ((stamp, size), &latency) in stats
-        .images_stamps
-        .iter()
-        .zip(stats.images_original_sizes.iter())
-        .zip(stats.images_latencies.iter())
-    {
-        writeln!(
-            image_stats_dest,
-            "{},{},{}",
-            stamp.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-            size,
-            latency as f64 / 1e9
-        )?;
+/// A camera the server streams, see [`config::SourceConfig`].
+struct Source {
+    name: String,
+    /// Shared with maps being saved.
+    slam_core: Option<Arc<SlamCore<'static>>>,
+    /// Time of the last odometry event.
+    last_event: Arc<Mutex<Instant>>,
+    /// Last failure to start the SLAM core, so each one is reported once.
+    camera_error: Option<String>,
+    /// Set while the SLAM core is restarted on a blocking thread.
+    restarting: bool,
+    /// Another restart was asked for meanwhile, e.g. by a reset.
+    restart_again: bool,
+}
+
+impl Source {
+    fn stalled(&self) -> bool {
+        self.slam_core.is_some() && self.last_event.lock().unwrap().elapsed() > CAMERA_STALL_TIMEOUT
     }
-    let odometry_stats_path = dir.join("odometry.csv");
-    let mut odometry_stats_dest = std::fs::File::create(odometry_stats_path)?;
-    writeln!(odometry_stats_dest, "stamp,size,latency")?;
-    for ((stamp, size), &latency) in stats
-        .odometry_stamps
-        .iter()
-        .zip(stats.odometry_original_sizes.iter())
-        .zip(stats.odometry_latencies.iter())
-    {
-        writeln!(
-            odometry_stats_dest,
-            "{},{},{}",
-            stamp.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-            size,
-            latency as f64 / 1e9
+}
+
+/// Starts the SLAM cores of [`serve`], localizing source 0 in saved maps.
+#[derive(Clone)]
+struct SlamStarter {
+    config: Arc<Config>,
+    outputs: SlamOutputs,
+    keyframe_policy: Arc<Mutex<KeyframePolicy>>,
+    /// Map epoch and node of relocalizations in a saved map.
+    localized_sender: mpsc::UnboundedSender<(u64, i32)>,
+}
+
+impl SlamStarter {
+    fn start(
+        &self,
+        source: SourceId,
+        epoch: u64,
+        map: Option<&Path>,
+        last_event: Arc<Mutex<Instant>>,
+    ) -> Result<SlamCore<'static>> {
+        let mut slam_core = init_slam_core(
+            &self.config,
+            source,
+            epoch,
+            map,
+            self.outputs.clone(),
+            self.keyframe_policy.clone(),
+            last_event,
         )?;
+        if map.is_some() {
+            let localized_sender = self.localized_sender.clone();
+            slam_core.register_localization_event_handler(move |ev| {
+                let _ = localized_sender.send((epoch, ev.node_id));
+            });
+        }
+        Ok(slam_core)
+    }
+}
+
+/// A SLAM core started by [`restart_slam_core`], and the id of its source.
+type StartedSlamCore = (SourceId, Result<SlamCore<'static>>);
+
+/// Stops the SLAM core of `source`, if it has one, and starts a new one on its
+/// current map epoch. Both take a while, so they run on a blocking thread and
+/// the result goes to [`slam_core_started`]. A restart asked for while one is
+/// running follows it.
+fn restart_slam_core(
+    server: &Server,
+    starter: &SlamStarter,
+    id: SourceId,
+    source: &mut Source,
+    map: Option<PathBuf>,
+    starting: &mut JoinSet<StartedSlamCore>,
+) {
+    if source.restarting {
+        source.restart_again = true;
+        return;
+    }
+    source.restarting = true;
+    let old = source.slam_core.take();
+    let epoch = server.map_epoch(id);
+    let last_event = source.last_event.clone();
+    let starter = starter.clone();
+    starting.spawn_blocking(move || {
+        // The camera can only be opened once the old core let go of it, unless
+        // a map is still being saved from it.
+        drop(old);
+        (id, starter.start(id, epoch, map.as_deref(), last_event))
+    });
+}
+
+/// Takes the SLAM core started for `source` and tells the clients when its
+/// camera goes away or comes back. Returns whether to restart it right away.
+fn slam_core_started(
+    server: &Server,
+    id: SourceId,
+    source: &mut Source,
+    res: Result<SlamCore<'static>>,
+) -> bool {
+    source.restarting = false;
+    match res {
+        Ok(slam_core) => {
+            metrics().camera_connected(&source.name, true);
+            if source.camera_error.take().is_some() {
+                println!("Camera {} is back", source.name);
+                server.camera_status(id, true, "Camera reconnected");
+            }
+            source.slam_core = Some(Arc::new(slam_core));
+        }
+        Err(e) => {
+            metrics().camera_connected(&source.name, false);
+            let message = format!("{e:#}");
+            if source.camera_error.as_ref() != Some(&message) {
+                eprintln!(
+                    "{}: {message}, retrying every {}s",
+                    source.name,
+                    CAMERA_CHECK_INTERVAL.as_secs()
+                );
+                server.camera_status(id, false, message.clone());
+                source.camera_error = Some(message);
+            }
+        }
+    }
+    std::mem::take(&mut source.restart_again)
+}
+
+/// Saved maps are of a single camera; with more it's unclear which one a map
+/// is of, or which one should localize in it.
+fn check_single_source(source_count: usize) -> Result<()> {
+    if source_count > 1 {
+        bail!("Saved maps need a single source, but {source_count} are configured");
     }
     Ok(())
 }
 
+/// Sends the saved maps to the clients.
+fn send_maps(server: &Server, maps: &MapStore, loaded: &Option<(String, PathBuf)>) {
+    match maps.list() {
+        Ok(list) => server.maps(list, loaded.as_ref().map(|(name, _)| name.clone())),
+        Err(e) => eprintln!("Failed to list maps: {e:#}"),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    let interval = Duration::from_millis(args.image_interval);
+    let config = args.config()?;
     match args.subcommand {
-        Subcommand::Serve(args) => serve(interval, args.port).await?,
-        Subcommand::Record(args) => record(interval, &args.bag_dir).await?,
-        Subcommand::Replay(args) => replay(args.port, &args.bag_dir, args.loop_).await?,
+        Subcommand::Serve(args) => serve(&config, args.map.as_deref()).await?,
+        Subcommand::Record(_) => record(&config).await?,
+        Subcommand::Replay(args) => replay(&config, args.loop_).await?,
+        Subcommand::PrintDefaultConfig => print!("{}", config.to_toml()?),
     }
     Ok(())
 }
 
-async fn serve(image_interval: Duration, port: u16) -> Result<()> {
+fn spawn_metrics(config: &Config) {
+    if !config.metrics.enabled {
+        return;
+    }
+    let addr = SocketAddr::new(config.metrics.bind_address, config.metrics.port);
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(addr).await {
+            eprintln!("Error serving metrics: {:?}", e);
+        }
+    });
+}
+
+fn auth(config: &Config) -> Option<AuthKey> {
+    let auth = config.auth_key();
+    if auth.is_none() {
+        eprintln!("Warning: no auth token configured, anyone on the network can connect");
+    }
+    auth
+}
+
+fn tls(config: &Config) -> Result<Option<TlsAcceptor>> {
+    let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) else {
+        return Ok(None);
+    };
+    let (acceptor, fingerprint) = tls::load_acceptor(cert, key)?;
+    println!("Serving wss:// with certificate {fingerprint}");
+    Ok(Some(acceptor))
+}
+
+fn fusion(config: &Config) -> Option<Arc<Fusion>> {
+    config
+        .fusion
+        .enabled
+        .then(|| Fusion::new(config.fusion.grid_size as f32))
+}
+
+async fn serve(config: &Config, map: Option<&str>) -> Result<()> {
+    let maps = MapStore::new(&config.paths.map_dir);
+    // Saved map SLAM localizes in, and its path.
+    let mut loaded_map = map
+        .map(|name| {
+            check_single_source(config.sources().len())?;
+            anyhow::Ok((name.to_owned(), maps.find(name)?))
+        })
+        .transpose()?;
+    spawn_metrics(config);
+    let keyframe_policy = Arc::new(Mutex::new(config.keyframe_policy()));
     let (command_sender, mut command_receiver) = mpsc::unbounded_channel();
     let server = Server::new(
-        port,
+        SocketAddr::new(config.server.bind_address, config.server.port),
+        config.encoding(),
+        auth(config),
+        tls(config)?,
+        fusion(config),
+        config.sources().into_iter().map(|s| s.name).collect(),
         Callbacks {
-            on_command: Box::new(move |command| {
-                command_sender.send(command).unwrap();
+            on_command: Box::new(move |command, client| {
+                command_sender.send((command, client.clone())).unwrap();
             }),
         },
     )
     .await?;
-    let image_sender = server.image_sender();
-    let odometry_sender = server.odometry_sender();
-    let mut slam_core = Some(init_slam_core(
-        image_sender,
-        odometry_sender,
-        image_interval,
-    )?);
+    let (localized_sender, mut localized_receiver) = mpsc::unbounded_channel();
+    let (pose_graph_sender, mut pose_graph_receiver) = mpsc::unbounded_channel();
+    let starter = SlamStarter {
+        config: Arc::new(config.clone()),
+        outputs: SlamOutputs {
+            image_sender: server.image_sender(),
+            odometry_sender: server.odometry_sender(),
+            pose_graph_sender: Some(pose_graph_sender),
+        },
+        keyframe_policy: keyframe_policy.clone(),
+        localized_sender,
+    };
+    let mut starting = JoinSet::new();
+    let mut saving = JoinSet::new();
+    // Saved maps are loaded into the only source.
+    let restart = |id: SourceId,
+                   source: &mut Source,
+                   loaded_map: &Option<(String, PathBuf)>,
+                   starting: &mut JoinSet<StartedSlamCore>| {
+        let map = loaded_map
+            .as_ref()
+            .filter(|_| id == 0)
+            .map(|(_, path)| path.clone());
+        restart_slam_core(&server, &starter, id, source, map, starting);
+    };
+    // Starts the SLAM cores that aren't running or starting.
+    let start_all = |sources: &mut [Source],
+                     loaded_map: &Option<(String, PathBuf)>,
+                     starting: &mut JoinSet<StartedSlamCore>| {
+        for (id, source) in sources.iter_mut().enumerate() {
+            if source.slam_core.is_none() && !source.restarting {
+                restart(id as SourceId, source, loaded_map, starting);
+            }
+        }
+    };
+    // Relocalization is reported once per epoch.
+    let mut relocalized_epoch = None;
+    // SLAM cores are started by the first check, which fires immediately.
+    let mut sources: Vec<_> = config
+        .sources()
+        .into_iter()
+        .map(|source| Source {
+            name: source.name,
+            slam_core: None,
+            last_event: Arc::new(Mutex::new(Instant::now())),
+            camera_error: None,
+            restarting: false,
+            restart_again: false,
+        })
+        .collect();
+    let mut camera_check = tokio::time::interval(CAMERA_CHECK_INTERVAL);
+    camera_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
         select! {
+            _ = camera_check.tick() => {
+                // A stalled SLAM core starts over at the origin of a new map,
+                // the other sources keep theirs.
+                for (id, source) in sources.iter_mut().enumerate() {
+                    if source.stalled() {
+                        let message = "Camera stalled or unplugged, reconnecting...";
+                        eprintln!("{}: {message}", source.name);
+                        metrics().camera_stalled();
+                        metrics().camera_connected(&source.name, false);
+                        server.reset_map(id as SourceId)?;
+                        server.camera_status(id as SourceId, false, message);
+                        source.camera_error = Some(message.to_owned());
+                        restart(id as SourceId, source, &loaded_map, &mut starting);
+                    }
+                }
+                start_all(&mut sources, &loaded_map, &mut starting);
+            }
+            Some(saved) = saving.join_next() => {
+                let (name, res) = saved?;
+                match res {
+                    Ok(()) => {
+                        println!("Saved map {name}");
+                        server.notice(format!("Saved map {name}"));
+                        send_maps(&server, &maps, &loaded_map);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to save map {name}: {e:#}");
+                        server.notice(format!("Failed to save map {name}: {e:#}"));
+                    }
+                }
+            }
+            Some(started) = starting.join_next() => {
+                let (id, res) = started?;
+                let source = &mut sources[id as usize];
+                if slam_core_started(&server, id, source, res) {
+                    restart(id, source, &loaded_map, &mut starting);
+                }
+            }
+            Some((epoch, node_id)) = localized_receiver.recv() => {
+                if let Some((name, _)) = &loaded_map {
+                    if epoch == server.map_epoch(0) && relocalized_epoch != Some(epoch) {
+                        println!("Relocalized in map {name} (node {node_id})");
+                        server.relocalized(0, name);
+                        relocalized_epoch = Some(epoch);
+                    }
+                }
+            }
+            Some(pose_graph) = pose_graph_receiver.recv() => {
+                if pose_graph.epoch == server.map_epoch(pose_graph.source) {
+                    metrics().pose_graph_updated();
+                    server.pose_graph(pose_graph);
+                }
+            }
             command = command_receiver.recv() => {
+                let Some((command, client)) = command else {
+                    break;
+                };
                 match command {
-                    Some(Command::Reset) => {
+                    Command::Reset => {
                         println!("Resetting SLAM core...");
-                        // Shutdown the old slam core
-                        drop(slam_core.take());
-                        let image_sender = server.image_sender();
-                        let odometry_sender = server.odometry_sender();
-                        slam_core = Some(init_slam_core(
-                            image_sender,
-                            odometry_sender,
-                            image_interval,
-                        )?);
-                        println!("SLAM core reset!");
+                        let left_map = loaded_map.take().is_some();
+                        for (id, source) in sources.iter_mut().enumerate() {
+                            server.reset_map(id as SourceId)?;
+                            restart(id as SourceId, source, &loaded_map, &mut starting);
+                        }
+                        if left_map {
+                            send_maps(&server, &maps, &loaded_map);
+                        }
+                    }
+                    Command::SaveMap(name) => {
+                        let path = check_single_source(sources.len())
+                            .and_then(|()| maps.prepare(&name));
+                        let slam_core = sources[0].slam_core.clone();
+                        // Writing the database takes a while.
+                        saving.spawn_blocking(move || {
+                            let res = path.and_then(|path| {
+                                slam_core.context("SLAM is not running")?.save_map(&path)
+                            });
+                            (name, res)
+                        });
                     }
-                    Some(Command::SaveStats(stats)) => {
+                    Command::LoadMap(name) => match check_single_source(sources.len())
+                        .and_then(|()| maps.find(&name))
+                    {
+                        Ok(path) => {
+                            println!("Loading map {name}...");
+                            server.reset_map(0)?;
+                            loaded_map = Some((name, path));
+                            restart(0, &mut sources[0], &loaded_map, &mut starting);
+                            send_maps(&server, &maps, &loaded_map);
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to load map: {e:#}");
+                            server.notice(format!("{e:#}"));
+                        }
+                    },
+                    Command::ListMaps => send_maps(&server, &maps, &loaded_map),
+                    Command::SaveStats(stats) => {
                         println!("Saving statistics...");
-                        save_stats(stats, Path::new("stats"))?;
+                        let keyframe_policy = keyframe_policy.lock().unwrap().clone();
+                        let metadata = stats::Metadata {
+                            client: &client,
+                            config,
+                            keyframe_policy: &keyframe_policy,
+                        };
+                        match stats::save(&stats, &config.paths.stats_dir, &metadata) {
+                            Ok(dir) => {
+                                println!("Saved statistics to {}", dir.display());
+                                server.notice(format!("Saved statistics to {}", dir.display()));
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to save statistics: {e:#}");
+                                server.notice(format!("Failed to save statistics: {e:#}"));
+                            }
+                        }
                     }
-                    None => {
-                        break;
+                    Command::SetKeyframePolicy(policy) => {
+                        println!("Keyframe policy: {policy:?}");
+                        *keyframe_policy.lock().unwrap() = policy;
                     }
                 }
             }
@@ -221,20 +720,51 @@ async fn serve(image_interval: Duration, port: u16) -> Result<()> {
         }
     }
     println!("Exiting...");
-    server.shutdown().await?;
+    server
+        .shutdown("Server is shutting down", Some(RESTART_RETRY_AFTER))
+        .await?;
+    // SLAM cores still starting or saving are stopped along with the others.
+    while starting.join_next().await.is_some() {}
+    while saving.join_next().await.is_some() {}
     Ok(())
 }
 
-async fn record(image_interval: Duration, bag_dir: &Path) -> Result<()> {
-    let mut recorder = Recorder::new(bag_dir)?;
-    let (image_sender, mut image_receiver) = broadcast::channel(1);
-    let (odometry_sender, mut odometry_receiver) = broadcast::channel(1);
-    let _slam_core = init_slam_core(image_sender, odometry_sender, image_interval)?;
+async fn record(config: &Config) -> Result<()> {
+    let keyframe_policy = Arc::new(Mutex::new(config.keyframe_policy()));
+    let sources = config.sources();
+    let names: Vec<_> = sources.iter().map(|s| s.name.clone()).collect();
+    let mut recorder = Recorder::new(&config.paths.bag_dir, &names)?;
+    let (image_sender, mut image_receiver) = broadcast::channel(sources.len());
+    let (odometry_sender, mut odometry_receiver) = broadcast::channel(sources.len());
+    let _slam_cores = (0..sources.len())
+        .map(|source| {
+            init_slam_core(
+                config,
+                source as SourceId,
+                0,
+                None,
+                SlamOutputs {
+                    image_sender: image_sender.clone(),
+                    odometry_sender: odometry_sender.clone(),
+                    pose_graph_sender: None,
+                },
+                keyframe_policy.clone(),
+                Arc::new(Mutex::new(Instant::now())),
+            )
+            .with_context(|| format!("Failed to start source {}", names[source]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let encoding = config.encoding();
+    let encoding = encoding.encoding(
+        encoding.ladder.rung(encoding.ladder.initial()),
+        encoding.color_codec,
+        encoding.depth_codec,
+    );
     loop {
         select! {
             res = image_receiver.recv() => {
-                if let Ok(msg) = res {
-                    recorder.feed_images(&msg)?;
+                if let Ok(frame) = res {
+                    recorder.feed_images(&frame.encode(&encoding).await?)?;
                 }
             }
             res = odometry_receiver.recv() => {
@@ -250,14 +780,35 @@ async fn record(image_interval: Duration, bag_dir: &Path) -> Result<()> {
     Ok(())
 }
 
-async fn replay(port: u16, bag_dir: &Path, loop_: bool) -> Result<()> {
+async fn replay(config: &Config, loop_: bool) -> Result<()> {
+    spawn_metrics(config);
+    let stats_config = config.clone();
+    // Bags recorded before sources were named have a single one.
+    let mut sources = Player::new(&config.paths.bag_dir)?.sources().to_vec();
+    if sources.is_empty() {
+        sources.push(config::DEFAULT_SOURCE.to_owned());
+    }
+    let source_count = sources.len() as SourceId;
     let server = Server::new(
-        port,
+        SocketAddr::new(config.server.bind_address, config.server.port),
+        config.encoding(),
+        auth(config),
+        tls(config)?,
+        fusion(config),
+        sources,
         Callbacks {
-            on_command: Box::new(|command| {
+            on_command: Box::new(move |command, client| {
                 if let Command::SaveStats(stats) = command {
                     println!("Saving statistics...");
-                    let _ = save_stats(stats, Path::new("stats"));
+                    let metadata = stats::Metadata {
+                        client,
+                        config: &stats_config,
+                        keyframe_policy: &stats_config.keyframe_policy(),
+                    };
+                    match stats::save(&stats, &stats_config.paths.stats_dir, &metadata) {
+                        Ok(dir) => println!("Saved statistics to {}", dir.display()),
+                        Err(e) => eprintln!("Failed to save statistics: {e:#}"),
+                    }
                 }
             }),
         },
@@ -267,8 +818,16 @@ async fn replay(port: u16, bag_dir: &Path, loop_: bool) -> Result<()> {
     let odometry_sender = server.odometry_sender();
     let ctrl_c = tokio::signal::ctrl_c();
     pin_mut!(ctrl_c);
+    let mut first = true;
     'outer: loop {
-        let mut player = Player::new(bag_dir)?;
+        if !first {
+            // The trajectories start over.
+            for source in 0..source_count {
+                server.reset_map(source)?;
+            }
+        }
+        first = false;
+        let mut player = Player::new(&config.paths.bag_dir)?;
         loop {
             let Some(next_time) = player.poll_next_event_time() else {
                 break;
@@ -282,12 +841,17 @@ async fn replay(port: u16, bag_dir: &Path, loop_: bool) -> Result<()> {
             let Some(event) = player.next_event()? else {
                 break;
             };
+            // The bag's epochs are from the recording.
             match event {
-                bag::Event::Odometry(msg) => {
+                bag::Event::Odometry(mut msg) => {
+                    msg.epoch = server.map_epoch(msg.source);
                     odometry_sender.send(msg)?;
+                    metrics().odometry_published();
                 }
-                bag::Event::Images(msg) => {
-                    image_sender.send(msg)?;
+                bag::Event::Images(mut msg) => {
+                    msg.odometry.epoch = server.map_epoch(msg.odometry.source);
+                    image_sender.send(Arc::new(ImageFrame::encoded(msg)))?;
+                    metrics().images_published();
                 }
             }
         }
@@ -295,5 +859,9 @@ async fn replay(port: u16, bag_dir: &Path, loop_: bool) -> Result<()> {
             break;
         }
     }
+    println!("Exiting...");
+    server
+        .shutdown("Replay stopped", Some(RESTART_RETRY_AFTER))
+        .await?;
     Ok(())
 }
@@ -0,0 +1,104 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use vrrop_common::MapInfo;
+
+const EXTENSION: &str = "db";
+
+/// RTAB-Map databases saved by name in a directory.
+#[derive(Debug, Clone)]
+pub struct MapStore {
+    dir: PathBuf,
+}
+
+impl MapStore {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Where the map `name` is saved. Names come from clients, so they can't
+    /// point outside the directory.
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        ensure!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "Invalid map name {name:?}, use letters, digits, - and _"
+        );
+        Ok(self.dir.join(name).with_extension(EXTENSION))
+    }
+
+    /// Path of an existing map.
+    pub fn find(&self, name: &str) -> Result<PathBuf> {
+        let path = self.path(name)?;
+        ensure!(path.is_file(), "No map named {name}");
+        Ok(path)
+    }
+
+    /// Creates the directory before saving `name`.
+    pub fn prepare(&self, name: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        self.path(name)
+    }
+
+    /// Saved maps sorted by name. A missing directory has none.
+    pub fn list(&self) -> Result<Vec<MapInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.dir.display()))
+            }
+        };
+        let mut maps = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let metadata = fs::metadata(&path)?;
+            maps.push(MapInfo {
+                name: name.to_owned(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+        maps.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(maps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_in_the_directory() {
+        let dir = std::env::temp_dir().join(format!("vrrop_maps_{}", std::process::id()));
+        let store = MapStore::new(&dir);
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(store.path("lab_2").unwrap(), dir.join("lab_2.db"));
+        for name in ["", "../etc", "a/b", "lab.db"] {
+            assert!(store.path(name).is_err(), "{name}");
+        }
+        assert!(store.find("lab").is_err());
+
+        fs::write(store.prepare("lab").unwrap(), b"").unwrap();
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+        let maps = store.list().unwrap();
+        assert_eq!(maps.len(), 1);
+        assert_eq!(maps[0].name, "lab");
+        assert!(store.find("lab").is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,753 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{http::header, response::IntoResponse, routing::get, Json, Router};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use vrrop_common::auth::RejectReason;
+
+use crate::session::Sessions;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Process-wide metrics, exported by [`serve`].
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Events counted over this window make up the published rates.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the encode time histogram buckets in seconds.
+const ENCODE_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    WebsocketImages,
+    WebsocketDeltas,
+    UdpOdometry,
+    FusionImages,
+    WebsocketControl,
+}
+
+impl Channel {
+    const ALL: &'static [Channel] = &[
+        Channel::WebsocketImages,
+        Channel::WebsocketDeltas,
+        Channel::UdpOdometry,
+        Channel::FusionImages,
+        Channel::WebsocketControl,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::WebsocketImages => "websocket_images",
+            Channel::WebsocketDeltas => "websocket_deltas",
+            Channel::UdpOdometry => "udp_odometry",
+            Channel::FusionImages => "fusion_images",
+            Channel::WebsocketControl => "websocket_control",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Websocket,
+    Udp,
+}
+
+impl Transport {
+    const ALL: &'static [Transport] = &[Transport::Websocket, Transport::Udp];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Transport::Websocket => "websocket",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpEvent {
+    Subscribed,
+    Left,
+    TimedOut,
+    /// The websocket connection of the session closed.
+    Closed,
+}
+
+impl UdpEvent {
+    const ALL: &'static [UdpEvent] = &[
+        UdpEvent::Subscribed,
+        UdpEvent::Left,
+        UdpEvent::TimedOut,
+        UdpEvent::Closed,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            UdpEvent::Subscribed => "subscribed",
+            UdpEvent::Left => "left",
+            UdpEvent::TimedOut => "timed_out",
+            UdpEvent::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodeStage {
+    Color,
+    Depth,
+    Total,
+}
+
+impl EncodeStage {
+    const ALL: &'static [EncodeStage] =
+        &[EncodeStage::Color, EncodeStage::Depth, EncodeStage::Total];
+
+    fn name(&self) -> &'static str {
+        match self {
+            EncodeStage::Color => "color",
+            EncodeStage::Depth => "depth",
+            EncodeStage::Total => "total",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RateMeter {
+    events: VecDeque<Instant>,
+}
+
+impl RateMeter {
+    fn record(&mut self) {
+        let now = Instant::now();
+        self.events.push_back(now);
+        self.prune(now);
+    }
+
+    fn rate(&mut self) -> f64 {
+        self.prune(Instant::now());
+        self.events.len() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .events
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > RATE_WINDOW)
+        {
+            self.events.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Histogram {
+    /// Cumulative counts for each bound of [`ENCODE_BUCKETS`].
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; ENCODE_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(ENCODE_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebSocketClientStatus {
+    pub address: SocketAddr,
+    #[serde(skip)]
+    connected_at: Instant,
+    pub connected_secs: f64,
+    pub mode: &'static str,
+    pub codecs: String,
+    pub rung: usize,
+    pub messages_sent: u64,
+    pub messages_dropped: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UdpClientStatus {
+    pub session: u64,
+    pub websocket_address: SocketAddr,
+    pub address: SocketAddr,
+    pub subscribed_secs: f64,
+    pub last_seen_secs: f64,
+    pub pings: u64,
+    pub odometry_sent: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Default)]
+struct SlamState {
+    tracking: bool,
+    last_pose: Option<Instant>,
+    camera_connected: BTreeMap<String, bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlamStatus {
+    pub tracking: bool,
+    pub last_pose_age_secs: Option<f64>,
+    pub lost_poses: u64,
+    /// By source name.
+    pub camera_connected: BTreeMap<String, bool>,
+    pub camera_stalls: u64,
+    pub pose_graph_updates: u64,
+}
+
+pub struct Metrics {
+    started_at: Instant,
+    websocket_clients: Mutex<HashMap<SocketAddr, WebSocketClientStatus>>,
+    sessions: Mutex<Option<Arc<Sessions>>>,
+    udp_events: Mutex<HashMap<UdpEvent, u64>>,
+    odometry_published: AtomicU64,
+    images_published: AtomicU64,
+    odometry_rate: Mutex<RateMeter>,
+    images_rate: Mutex<RateMeter>,
+    encode_seconds: Mutex<HashMap<EncodeStage, Histogram>>,
+    lagged: Mutex<HashMap<Channel, u64>>,
+    rejected: Mutex<HashMap<Transport, HashMap<&'static str, u64>>>,
+    slam: Mutex<SlamState>,
+    lost_poses: AtomicU64,
+    camera_stalls: AtomicU64,
+    pose_graph_updates: AtomicU64,
+    send_timeouts: AtomicU64,
+}
+
+/// Removes a websocket client from the metrics when its connection ends.
+pub struct WebSocketClientGuard {
+    address: SocketAddr,
+}
+
+impl Drop for WebSocketClientGuard {
+    fn drop(&mut self) {
+        metrics()
+            .websocket_clients
+            .lock()
+            .unwrap()
+            .remove(&self.address);
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            websocket_clients: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(None),
+            udp_events: Mutex::new(HashMap::new()),
+            odometry_published: AtomicU64::new(0),
+            images_published: AtomicU64::new(0),
+            odometry_rate: Mutex::new(RateMeter::default()),
+            images_rate: Mutex::new(RateMeter::default()),
+            encode_seconds: Mutex::new(HashMap::new()),
+            lagged: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(HashMap::new()),
+            slam: Mutex::new(SlamState::default()),
+            lost_poses: AtomicU64::new(0),
+            camera_stalls: AtomicU64::new(0),
+            pose_graph_updates: AtomicU64::new(0),
+            send_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn websocket_client_connected(
+        &self,
+        address: SocketAddr,
+        mode: &'static str,
+    ) -> WebSocketClientGuard {
+        self.websocket_clients.lock().unwrap().insert(
+            address,
+            WebSocketClientStatus {
+                address,
+                connected_at: Instant::now(),
+                connected_secs: 0.0,
+                mode,
+                codecs: String::new(),
+                rung: 0,
+                messages_sent: 0,
+                messages_dropped: 0,
+                bytes_sent: 0,
+            },
+        );
+        WebSocketClientGuard { address }
+    }
+
+    pub fn update_websocket_client(
+        &self,
+        address: SocketAddr,
+        f: impl FnOnce(&mut WebSocketClientStatus),
+    ) {
+        if let Some(client) = self.websocket_clients.lock().unwrap().get_mut(&address) {
+            f(client);
+        }
+    }
+
+    pub fn set_sessions(&self, sessions: Arc<Sessions>) {
+        *self.sessions.lock().unwrap() = Some(sessions);
+    }
+
+    pub fn udp_event(&self, event: UdpEvent) {
+        *self.udp_events.lock().unwrap().entry(event).or_default() += 1;
+    }
+
+    pub fn odometry_published(&self) {
+        self.odometry_published.fetch_add(1, Ordering::Relaxed);
+        self.odometry_rate.lock().unwrap().record();
+    }
+
+    pub fn images_published(&self) {
+        self.images_published.fetch_add(1, Ordering::Relaxed);
+        self.images_rate.lock().unwrap().record();
+    }
+
+    pub fn observe_encode(&self, stage: EncodeStage, elapsed: Duration) {
+        self.encode_seconds
+            .lock()
+            .unwrap()
+            .entry(stage)
+            .or_insert_with(Histogram::new)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn lagged(&self, channel: Channel, dropped: u64) {
+        *self.lagged.lock().unwrap().entry(channel).or_default() += dropped;
+    }
+
+    /// A connection or datagram failed authentication or couldn't be parsed.
+    pub fn rejected(&self, transport: Transport, reason: RejectReason) {
+        *self
+            .rejected
+            .lock()
+            .unwrap()
+            .entry(transport)
+            .or_default()
+            .entry(reason.name())
+            .or_default() += 1;
+    }
+
+    /// A websocket client was disconnected because it stopped accepting messages.
+    pub fn send_timed_out(&self) {
+        self.send_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slam_pose(&self, tracking: bool) {
+        let mut slam = self.slam.lock().unwrap();
+        slam.tracking = tracking;
+        if tracking {
+            slam.last_pose = Some(Instant::now());
+        } else {
+            self.lost_poses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn camera_connected(&self, source: &str, connected: bool) {
+        self.slam
+            .lock()
+            .unwrap()
+            .camera_connected
+            .insert(source.to_owned(), connected);
+    }
+
+    pub fn camera_stalled(&self) {
+        self.camera_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pose_graph_updated(&self) {
+        self.pose_graph_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> Status {
+        let now = Instant::now();
+        let mut websocket_clients: Vec<_> = self
+            .websocket_clients
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .map(|mut client| {
+                client.connected_secs = now.duration_since(client.connected_at).as_secs_f64();
+                client
+            })
+            .collect();
+        websocket_clients.sort_by_key(|c| c.address);
+        let udp_clients = self
+            .sessions
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|sessions| sessions.udp_clients())
+            .unwrap_or_default();
+        let slam = self.slam.lock().unwrap();
+        Status {
+            uptime_secs: now.duration_since(self.started_at).as_secs_f64(),
+            slam: SlamStatus {
+                tracking: slam.tracking,
+                last_pose_age_secs: slam.last_pose.map(|t| now.duration_since(t).as_secs_f64()),
+                lost_poses: self.lost_poses.load(Ordering::Relaxed),
+                camera_connected: slam.camera_connected.clone(),
+                camera_stalls: self.camera_stalls.load(Ordering::Relaxed),
+                pose_graph_updates: self.pose_graph_updates.load(Ordering::Relaxed),
+            },
+            websocket_clients,
+            websocket_send_timeouts: self.send_timeouts.load(Ordering::Relaxed),
+            udp_clients,
+            udp_events: self.udp_events.lock().unwrap().clone(),
+            odometry_published: self.odometry_published.load(Ordering::Relaxed),
+            odometry_rate_hz: self.odometry_rate.lock().unwrap().rate(),
+            images_published: self.images_published.load(Ordering::Relaxed),
+            images_rate_hz: self.images_rate.lock().unwrap().rate(),
+            lagged: self.lagged.lock().unwrap().clone(),
+            rejected: self.rejected.lock().unwrap().clone(),
+            encode_seconds: self.encode_seconds.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub uptime_secs: f64,
+    pub slam: SlamStatus,
+    pub websocket_clients: Vec<WebSocketClientStatus>,
+    pub websocket_send_timeouts: u64,
+    pub udp_clients: Vec<UdpClientStatus>,
+    pub udp_events: HashMap<UdpEvent, u64>,
+    pub odometry_published: u64,
+    pub odometry_rate_hz: f64,
+    pub images_published: u64,
+    pub images_rate_hz: f64,
+    pub lagged: HashMap<Channel, u64>,
+    pub rejected: HashMap<Transport, HashMap<&'static str, u64>>,
+    pub encode_seconds: HashMap<EncodeStage, Histogram>,
+}
+
+impl Status {
+    /// Renders the status in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let single = |value: String| [(String::new(), value)];
+        metric(
+            "vrrop_uptime_seconds",
+            "gauge",
+            "Time since the server started.",
+            &single(self.uptime_secs.to_string()),
+        );
+        metric(
+            "vrrop_slam_tracking",
+            "gauge",
+            "1 if the last odometry pose was valid.",
+            &single((self.slam.tracking as u8).to_string()),
+        );
+        metric(
+            "vrrop_slam_lost_poses_total",
+            "counter",
+            "Odometry poses dropped because tracking was lost.",
+            &single(self.slam.lost_poses.to_string()),
+        );
+        metric(
+            "vrrop_camera_connected",
+            "gauge",
+            "1 while the SLAM core is running on a camera.",
+            &self
+                .slam
+                .camera_connected
+                .iter()
+                .map(|(source, connected)| {
+                    (
+                        format!("{{source=\"{source}\"}}"),
+                        (*connected as u8).to_string(),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "vrrop_camera_stalls_total",
+            "counter",
+            "Times the camera stopped delivering frames and the SLAM core was restarted.",
+            &single(self.slam.camera_stalls.to_string()),
+        );
+        metric(
+            "vrrop_pose_graph_updates_total",
+            "counter",
+            "Loop closure corrections sent to the clients.",
+            &single(self.slam.pose_graph_updates.to_string()),
+        );
+        metric(
+            "vrrop_websocket_clients",
+            "gauge",
+            "Connected websocket clients.",
+            &single(self.websocket_clients.len().to_string()),
+        );
+        let client_samples = |f: &dyn Fn(&WebSocketClientStatus) -> String| {
+            self.websocket_clients
+                .iter()
+                .map(|c| {
+                    (
+                        format!("{{address=\"{}\",mode=\"{}\"}}", c.address, c.mode),
+                        f(c),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        metric(
+            "vrrop_websocket_client_rung",
+            "gauge",
+            "Image quality rung of each websocket client.",
+            &client_samples(&|c| c.rung.to_string()),
+        );
+        metric(
+            "vrrop_websocket_client_sent_bytes_total",
+            "counter",
+            "Bytes sent to each websocket client.",
+            &client_samples(&|c| c.bytes_sent.to_string()),
+        );
+        metric(
+            "vrrop_websocket_client_sent_messages_total",
+            "counter",
+            "Messages sent to each websocket client.",
+            &client_samples(&|c| c.messages_sent.to_string()),
+        );
+        metric(
+            "vrrop_websocket_client_dropped_messages_total",
+            "counter",
+            "Messages dropped because each websocket client couldn't keep up.",
+            &client_samples(&|c| c.messages_dropped.to_string()),
+        );
+        metric(
+            "vrrop_websocket_send_timeouts_total",
+            "counter",
+            "Websocket clients disconnected because they stopped accepting messages.",
+            &single(self.websocket_send_timeouts.to_string()),
+        );
+        metric(
+            "vrrop_udp_clients",
+            "gauge",
+            "UDP endpoints subscribed to odometry.",
+            &single(self.udp_clients.len().to_string()),
+        );
+        let udp_samples = |f: &dyn Fn(&UdpClientStatus) -> String| {
+            self.udp_clients
+                .iter()
+                .map(|c| {
+                    (
+                        format!("{{session=\"{}\",address=\"{}\"}}", c.session, c.address),
+                        f(c),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        metric(
+            "vrrop_udp_client_pings_total",
+            "counter",
+            "Pings received from each UDP subscription.",
+            &udp_samples(&|c| c.pings.to_string()),
+        );
+        metric(
+            "vrrop_udp_client_sent_messages_total",
+            "counter",
+            "Odometry messages sent to each UDP subscription.",
+            &udp_samples(&|c| c.odometry_sent.to_string()),
+        );
+        metric(
+            "vrrop_udp_client_sent_bytes_total",
+            "counter",
+            "Bytes sent to each UDP subscription.",
+            &udp_samples(&|c| c.bytes_sent.to_string()),
+        );
+        let udp_events: Vec<_> = UdpEvent::ALL
+            .iter()
+            .map(|event| {
+                (
+                    format!("{{event=\"{}\"}}", event.name()),
+                    self.udp_events
+                        .get(event)
+                        .copied()
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            })
+            .collect();
+        metric(
+            "vrrop_udp_session_events_total",
+            "counter",
+            "UDP subscriptions started and ended, by how they ended.",
+            &udp_events,
+        );
+        metric(
+            "vrrop_odometry_published_total",
+            "counter",
+            "Odometry messages published.",
+            &single(self.odometry_published.to_string()),
+        );
+        metric(
+            "vrrop_odometry_rate_hz",
+            "gauge",
+            "Odometry publish rate over the last 5 seconds.",
+            &single(self.odometry_rate_hz.to_string()),
+        );
+        metric(
+            "vrrop_images_published_total",
+            "counter",
+            "Images messages published.",
+            &single(self.images_published.to_string()),
+        );
+        metric(
+            "vrrop_images_rate_hz",
+            "gauge",
+            "Images publish rate over the last 5 seconds.",
+            &single(self.images_rate_hz.to_string()),
+        );
+        let lagged: Vec<_> = Channel::ALL
+            .iter()
+            .map(|channel| {
+                (
+                    format!("{{channel=\"{}\"}}", channel.name()),
+                    self.lagged
+                        .get(channel)
+                        .copied()
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            })
+            .collect();
+        metric(
+            "vrrop_lagged_messages_total",
+            "counter",
+            "Messages dropped because a receiver fell behind a broadcast channel.",
+            &lagged,
+        );
+        let mut rejected = Vec::new();
+        for transport in Transport::ALL {
+            for reason in RejectReason::ALL {
+                let count = self
+                    .rejected
+                    .get(transport)
+                    .and_then(|r| r.get(reason.name()))
+                    .copied()
+                    .unwrap_or_default();
+                rejected.push((
+                    format!(
+                        "{{transport=\"{}\",reason=\"{}\"}}",
+                        transport.name(),
+                        reason.name()
+                    ),
+                    count.to_string(),
+                ));
+            }
+        }
+        metric(
+            "vrrop_rejected_total",
+            "counter",
+            "Connections and datagrams rejected by authentication or parsing.",
+            &rejected,
+        );
+        let mut encode = Vec::new();
+        for stage in EncodeStage::ALL {
+            let Some(histogram) = self.encode_seconds.get(stage) else {
+                continue;
+            };
+            let stage = stage.name();
+            for (bound, count) in ENCODE_BUCKETS.iter().zip(&histogram.buckets) {
+                encode.push((
+                    format!("_bucket{{stage=\"{stage}\",le=\"{bound}\"}}"),
+                    count.to_string(),
+                ));
+            }
+            encode.push((
+                format!("_bucket{{stage=\"{stage}\",le=\"+Inf\"}}"),
+                histogram.count.to_string(),
+            ));
+            encode.push((
+                format!("_sum{{stage=\"{stage}\"}}"),
+                histogram.sum.to_string(),
+            ));
+            encode.push((
+                format!("_count{{stage=\"{stage}\"}}"),
+                histogram.count.to_string(),
+            ));
+        }
+        metric(
+            "vrrop_encode_seconds",
+            "histogram",
+            "Time spent encoding images messages.",
+            &encode,
+        );
+        out
+    }
+}
+
+/// Serves `/metrics` in Prometheus text format and `/status` as JSON.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let app = Router::new()
+        .route(
+            "/metrics",
+            get(|| async {
+                (
+                    [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    metrics().status().to_prometheus(),
+                )
+                    .into_response()
+            }),
+        )
+        .route("/status", get(|| async { Json(metrics().status()) }));
+    let listener = TcpListener::bind(addr).await?;
+    println!("Serving metrics on http://{addr}/metrics");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new();
+        histogram.observe(0.003);
+        histogram.observe(0.07);
+        histogram.observe(2.0);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[4], 2);
+        assert_eq!(*histogram.buckets.last().unwrap(), 2);
+        assert_eq!(histogram.count, 3);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use nalgebra::{Isometry3, Translation3};
+use vrrop_common::{GraphNode, Pose, PoseGraphMessage, SourceId};
+
+use crate::server::{encode_odometry_message, OdometryMessage};
+use crate::slam_core::GraphEvent;
+
+/// Frames RTAB-Map may still be working on, about 10 s of odometry.
+const RECENT_FRAMES: usize = 600;
+
+/// Stamps RTAB-Map reports may be off by this many seconds from the ones of
+/// the odometry events.
+const STAMP_TOLERANCE: f64 = 1e-3;
+
+/// Nodes moving less than this many meters or radians aren't sent again.
+const MIN_POSE_CHANGE: f32 = 1e-3;
+
+/// Ties RTAB-Map's map nodes to the odometry messages clients got for their
+/// frames, so loop closures can be sent as corrections of those poses.
+pub struct PoseGraph {
+    epoch: u64,
+    source: SourceId,
+    /// Odometry by camera stamp, until RTAB-Map has processed the frame.
+    recent: VecDeque<(f64, OdometryMessage)>,
+    nodes: BTreeMap<i32, OdometryMessage>,
+    /// Optimized pose of each node as last sent.
+    sent: BTreeMap<i32, Isometry3<f32>>,
+}
+
+impl PoseGraph {
+    pub fn new(epoch: u64, source: SourceId) -> Self {
+        Self {
+            epoch,
+            source,
+            recent: VecDeque::with_capacity(RECENT_FRAMES),
+            nodes: BTreeMap::new(),
+            sent: BTreeMap::new(),
+        }
+    }
+
+    /// Remembers the odometry sent for the frame captured at `stamp`.
+    pub fn record(&mut self, stamp: f64, odometry: &OdometryMessage) {
+        if self.recent.len() >= RECENT_FRAMES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((stamp, *odometry));
+    }
+
+    /// Returns the nodes that moved or were dropped when the graph was
+    /// optimized. Nodes whose frame clients never got, e.g. those of a loaded
+    /// map, are left out.
+    pub fn update(&mut self, event: &GraphEvent) -> Option<PoseGraphMessage> {
+        if let Some((id, stamp)) = event.node {
+            // The frame is usually among the last ones.
+            if let Some((_, odometry)) = self
+                .recent
+                .iter()
+                .rev()
+                .find(|(s, _)| (s - stamp).abs() <= STAMP_TOLERANCE)
+            {
+                self.nodes.insert(id, *odometry);
+            }
+        }
+        if event.poses.is_empty() {
+            return None;
+        }
+        let reported: HashSet<_> = event.poses.iter().map(|pose| pose.id).collect();
+        self.nodes.retain(|id, _| reported.contains(id));
+        let removed: Vec<_> = self
+            .sent
+            .keys()
+            .copied()
+            .filter(|id| !reported.contains(id))
+            .collect();
+        self.sent.retain(|id, _| reported.contains(id));
+        let mut nodes: Vec<_> = event
+            .poses
+            .iter()
+            .filter_map(|pose| {
+                let odometry = encode_odometry_message(self.nodes.get(&pose.id)?);
+                let optimized =
+                    Isometry3::from_parts(Translation3::from(pose.translation), pose.rotation);
+                if let Some(sent) = self.sent.get(&pose.id) {
+                    let change = sent.inverse() * optimized;
+                    if change.translation.vector.norm() < MIN_POSE_CHANGE
+                        && change.rotation.angle() < MIN_POSE_CHANGE
+                    {
+                        return None;
+                    }
+                }
+                self.sent.insert(pose.id, optimized);
+                Some(GraphNode {
+                    id: pose.id,
+                    stamp: odometry.stamp,
+                    odometry: Pose {
+                        translation: odometry.translation,
+                        rotation: odometry.rotation,
+                    },
+                    optimized: Pose {
+                        translation: pose.translation.into(),
+                        rotation: (*pose.rotation.into_inner().as_vector()).into(),
+                    },
+                })
+            })
+            .collect();
+        if nodes.is_empty() && removed.is_empty() {
+            return None;
+        }
+        nodes.sort_by_key(|node| node.stamp);
+        Some(PoseGraphMessage {
+            epoch: self.epoch,
+            source: self.source,
+            nodes,
+            removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use nalgebra::{UnitQuaternion, Vector3};
+
+    use super::*;
+    use crate::slam_core::NodePose;
+
+    fn odometry(x: f32) -> OdometryMessage {
+        OdometryMessage {
+            epoch: 3,
+            source: 1,
+            stamp: SystemTime::UNIX_EPOCH + Duration::from_secs_f32(x),
+            translation: Vector3::new(x, 0.0, 0.0),
+            rotation: UnitQuaternion::identity(),
+        }
+    }
+
+    fn node_pose(id: i32, x: f32) -> NodePose {
+        NodePose {
+            id,
+            translation: Vector3::new(x, 1.0, 0.0),
+            rotation: UnitQuaternion::identity(),
+        }
+    }
+
+    fn added(id: i32, stamp: f64) -> GraphEvent {
+        GraphEvent {
+            node: Some((id, stamp)),
+            poses: Vec::new(),
+        }
+    }
+
+    fn optimized(poses: Vec<NodePose>) -> GraphEvent {
+        GraphEvent { node: None, poses }
+    }
+
+    #[test]
+    fn nodes_are_matched_to_odometry_by_camera_stamp() {
+        let mut graph = PoseGraph::new(3, 1);
+        graph.record(10.0, &odometry(1.0));
+        graph.record(10.5, &odometry(2.0));
+        assert!(graph.update(&added(1, 10.0)).is_none());
+        // Stamps may have lost some precision on the way through RTAB-Map.
+        assert!(graph.update(&added(2, 10.500_01)).is_none());
+        // Not recorded, e.g. dropped as non-finite.
+        assert!(graph.update(&added(3, 11.0)).is_none());
+
+        let message = graph
+            .update(&optimized(vec![
+                node_pose(2, 2.5),
+                node_pose(1, 1.5),
+                node_pose(3, 3.5),
+            ]))
+            .unwrap();
+        assert_eq!((message.epoch, message.source), (3, 1));
+        let ids: Vec<_> = message.nodes.iter().map(|node| node.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(message.nodes[0].odometry.translation, [1.0, 0.0, 0.0]);
+        assert_eq!(message.nodes[0].optimized.translation, [1.5, 1.0, 0.0]);
+        assert_eq!(message.nodes[0].optimized.rotation, [0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn only_changes_are_sent() {
+        let mut graph = PoseGraph::new(3, 1);
+        let mut full = PoseGraphMessage::new(3, 1);
+        for (id, x) in [(1, 1.0), (2, 2.0), (3, 3.0)] {
+            graph.record(10.0 + x as f64, &odometry(x));
+            graph.update(&added(id, 10.0 + x as f64));
+        }
+        let first = graph
+            .update(&optimized(vec![
+                node_pose(1, 1.5),
+                node_pose(2, 2.5),
+                node_pose(3, 3.5),
+            ]))
+            .unwrap();
+        assert_eq!(first.nodes.len(), 3);
+        full.merge(first);
+
+        // Node 2 moved, node 1 was dropped from the map.
+        let second = graph
+            .update(&optimized(vec![node_pose(2, 2.7), node_pose(3, 3.5)]))
+            .unwrap();
+        let ids: Vec<_> = second.nodes.iter().map(|node| node.id).collect();
+        assert_eq!((ids, second.removed.clone()), (vec![2], vec![1]));
+        full.merge(second);
+        let ids: Vec<_> = full.nodes.iter().map(|node| node.id).collect();
+        assert_eq!(ids, [2, 3]);
+        assert_eq!(full.nodes[0].optimized.translation, [2.7, 1.0, 0.0]);
+        assert!(graph.nodes.keys().eq(&[2, 3]));
+
+        assert!(graph
+            .update(&optimized(vec![node_pose(2, 2.7), node_pose(3, 3.5)]))
+            .is_none());
+    }
+}
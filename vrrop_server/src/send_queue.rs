@@ -0,0 +1,151 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use tokio::sync::Notify;
+
+/// Bounded queue between a websocket connection and its writer. When the client
+/// can't keep up the oldest message is dropped, so it always gets the latest.
+#[derive(Debug)]
+pub struct SendQueue<T> {
+    capacity: usize,
+    state: Mutex<State<T>>,
+    notify: Notify,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    /// Dropped since the last [`SendQueue::take_dropped`].
+    dropped: u64,
+    total_dropped: u64,
+}
+
+impl<T> State<T> {
+    fn add_dropped(&mut self, n: u64) {
+        self.dropped += n;
+        self.total_dropped += n;
+    }
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            capacity,
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                dropped: 0,
+                total_dropped: 0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Returns the message dropped to make room, if any.
+    pub fn push(&self, item: T) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let dropped = if state.items.len() >= self.capacity {
+            state.add_dropped(1);
+            state.items.pop_front()
+        } else {
+            None
+        };
+        state.items.push_back(item);
+        drop(state);
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Like [`push`](Self::push), but at most `limit` messages that `same`
+    /// matches are queued, and room is made among them. Keeps one kind of
+    /// message from pushing out the others.
+    pub fn push_limited(&self, item: T, limit: usize, same: impl Fn(&T) -> bool) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let dropped = if state.items.iter().filter(|queued| same(queued)).count() >= limit {
+            let oldest = state.items.iter().position(&same);
+            oldest.and_then(|oldest| state.items.remove(oldest))
+        } else if state.items.len() >= self.capacity {
+            state.items.pop_front()
+        } else {
+            None
+        };
+        if dropped.is_some() {
+            state.add_dropped(1);
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Drops all queued messages, e.g. before queueing a snapshot that replaces
+    /// them.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        let n = state.items.len() as u64;
+        state.add_dropped(n);
+        state.items.clear();
+    }
+
+    /// Counts messages dropped before they reached the queue.
+    pub fn record_dropped(&self, n: u64) {
+        self.state.lock().unwrap().add_dropped(n);
+    }
+
+    pub fn take_dropped(&self) -> u64 {
+        std::mem::take(&mut self.state.lock().unwrap().dropped)
+    }
+
+    pub fn total_dropped(&self) -> u64 {
+        self.state.lock().unwrap().total_dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    /// Waits for the next message.
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.state.lock().unwrap().items.pop_front() {
+                return item;
+            }
+            // `notify_one` stores a permit, so a push between the check and here
+            // isn't missed.
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drops_oldest_when_full() {
+        let queue = SendQueue::new(2);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), None);
+        assert_eq!(queue.push(3), Some(1));
+        queue.record_dropped(2);
+        assert_eq!(queue.take_dropped(), 3);
+        assert_eq!(queue.take_dropped(), 0);
+        assert_eq!(queue.total_dropped(), 3);
+        assert_eq!(queue.pop().await, 2);
+        assert_eq!(queue.pop().await, 3);
+
+        queue.push(4);
+        queue.clear();
+        assert_eq!((queue.len(), queue.take_dropped()), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn limited_push_drops_same_kind() {
+        let queue = SendQueue::new(4);
+        let odd = |n: &i32| n % 2 == 1;
+        assert_eq!(queue.push_limited(1, 1, odd), None);
+        assert_eq!(queue.push_limited(2, 1, |n| n % 2 == 0), None);
+        assert_eq!(queue.push_limited(3, 1, odd), Some(1));
+        assert_eq!(queue.pop().await, 2);
+        assert_eq!(queue.pop().await, 3);
+    }
+}
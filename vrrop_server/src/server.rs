@@ -1,31 +1,56 @@
 use std::{
     collections::HashMap,
+    net::SocketAddr,
     panic,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{anyhow, Result};
-use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt};
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{stream::SplitSink, FutureExt, SinkExt, StreamExt, TryStreamExt};
 use image::{
-    codecs::{jpeg::JpegEncoder, png::PngEncoder},
-    EncodableLayout, ExtendedColorType, ImageEncoder,
+    imageops::{self, FilterType},
+    ImageBuffer, Pixel,
 };
 use nalgebra::{UnitQuaternion, Vector3};
+use serde::Serialize;
 use tokio::{
-    net::{TcpListener, TcpStream, UdpSocket},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
     select,
-    sync::broadcast,
-    task::JoinHandle,
-    time::sleep,
+    sync::{broadcast, mpsc, OnceCell},
+    task::{AbortHandle, JoinHandle},
+    time::{sleep, timeout},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use vrrop_common::{
+    auth::{
+        self, AuthKey, PacketOpener, PacketSealer, RejectLog, RejectReason, CONTEXT_CLIENT,
+        CONTEXT_SERVER,
+    },
+    codec::{self, ColorCodec, DepthCodec},
+    decoded, CameraIntrinsics, CameraStatusMessage, ChallengeMessage, Command, DroppedMessage,
+    GoodbyeMessage, HelloMessage, ImageTimings, MapInfo, MapResetMessage, MapsMessage,
+    NoticeMessage, PongMessage, PoseGraphMessage, RelocalizedMessage, SourceId, UdpClientMessage,
+    UdpServerMessage, WebSocketClientMessage, WebSocketServerMessage, WelcomeMessage,
 };
-use tokio_tungstenite::WebSocketStream;
-use vrrop_common::{CameraIntrinsics, Command, PongMessage, UdpClientMessage, UdpServerMessage};
 
-use crate::slam_core::{ColorImage, DepthImage};
+use crate::congestion::{CongestionController, QualityLadder, Rung};
+use crate::fusion::{Fusion, SequencedDelta, SerializedDelta};
+use crate::metrics::{metrics, Channel, EncodeStage, Transport};
+use crate::send_queue::SendQueue;
+use crate::session::{Sessions, SourceFilter};
+use crate::slam_core::{ColorImage, DepthImage, ImageData};
 
 #[derive(Debug, Clone, Copy)]
 pub struct OdometryMessage {
+    /// See [`Server::reset_map`].
+    pub epoch: u64,
+    pub source: SourceId,
     pub stamp: std::time::SystemTime,
     pub translation: Vector3<f32>,
     pub rotation: UnitQuaternion<f32>,
@@ -38,24 +63,285 @@ pub struct ImagesMessage {
     pub color_intrinsics: CameraIntrinsics,
     pub depth: Arc<DepthImage>,
     pub depth_intrinsics: CameraIntrinsics,
+    /// The camera's stamp of the frame, if it has a usable one.
+    pub capture: Option<SystemTime>,
+}
+
+/// Encoder settings shared by all clients. The quality rung is picked per client
+/// by congestion control and the codec by negotiation with the client.
+#[derive(Debug, Clone)]
+pub struct EncodingConfig {
+    pub ladder: QualityLadder,
+    pub color_codec: ColorCodec,
+    pub depth_codec: DepthCodec,
+    pub depth_max_error_mm: u16,
 }
 
+impl EncodingConfig {
+    /// Uses the configured color codec if the client can decode it, JPEG otherwise.
+    fn negotiate_color_codec(&self, supported: &[ColorCodec]) -> ColorCodec {
+        if supported.contains(&self.color_codec) {
+            self.color_codec
+        } else {
+            ColorCodec::Jpeg
+        }
+    }
+
+    /// Uses the configured depth codec if the client can decode it, PNG otherwise.
+    fn negotiate_depth_codec(&self, supported: &[DepthCodec]) -> DepthCodec {
+        if supported.contains(&self.depth_codec) {
+            self.depth_codec
+        } else {
+            DepthCodec::Png
+        }
+    }
+
+    pub fn encoding(
+        &self,
+        rung: &Rung,
+        color_codec: ColorCodec,
+        depth_codec: DepthCodec,
+    ) -> Encoding {
+        Encoding {
+            rung: *rung,
+            color_codec,
+            depth_codec,
+            depth_max_error_mm: self.depth_max_error_mm,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Encoding {
+    pub rung: Rung,
+    pub color_codec: ColorCodec,
+    pub depth_codec: DepthCodec,
+    pub depth_max_error_mm: u16,
+}
+
+/// A frame shared by all websocket clients. Clients may request different
+/// encodings; each result is cached so it is encoded only once.
+pub struct ImageFrame {
+    data: FrameData,
+    encoded: Mutex<HashMap<Option<Encoding>, EncodedCell>>,
+}
+
+type EncodedCell = Arc<OnceCell<Arc<Vec<u8>>>>;
+
+enum FrameData {
+    Raw(ImagesMessage),
+    /// Already encoded frames (e.g. replayed from a bag) are sent as-is for every encoding.
+    Encoded(vrrop_common::ImagesMessage),
+}
+
+impl ImageFrame {
+    pub fn raw(msg: ImagesMessage) -> Self {
+        Self {
+            data: FrameData::Raw(msg),
+            encoded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn encoded(msg: vrrop_common::ImagesMessage) -> Self {
+        Self {
+            data: FrameData::Encoded(msg),
+            encoded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn encode(&self, encoding: &Encoding) -> Result<vrrop_common::ImagesMessage> {
+        match &self.data {
+            FrameData::Raw(msg) => encode_images_message(msg, encoding).await,
+            FrameData::Encoded(msg) => Ok(msg.clone()),
+        }
+    }
+
+    /// Decoded images at full resolution, as a client would see them.
+    pub async fn decode(&self) -> Result<decoded::ImagesMessage> {
+        match &self.data {
+            FrameData::Raw(msg) => {
+                let odometry = encode_odometry_message(&msg.odometry);
+                let (color, depth) = (msg.color.clone(), msg.depth.clone());
+                let (color, depth) =
+                    tokio::task::spawn_blocking(move || (to_owned(&color), to_owned(&depth)))
+                        .await?;
+                Ok(decoded::ImagesMessage {
+                    original_size: 0,
+                    odometry: decoded::decode_odometry_message(odometry, 0),
+                    color: color.context("Invalid color image")?,
+                    color_intrinsics: msg.color_intrinsics,
+                    depth: depth.context("Invalid depth image")?,
+                    depth_intrinsics: msg.depth_intrinsics,
+                    depth_unit: 0.001,
+                    timings: Default::default(),
+                    stages: Default::default(),
+                })
+            }
+            FrameData::Encoded(msg) => {
+                let msg = msg.clone();
+                tokio::task::spawn_blocking(move || decoded::decode_images_message(msg, 0)).await?
+            }
+        }
+    }
+
+    pub fn source(&self) -> SourceId {
+        match &self.data {
+            FrameData::Raw(msg) => msg.odometry.source,
+            FrameData::Encoded(msg) => msg.odometry.source,
+        }
+    }
+
+    pub async fn serialized(&self, encoding: &Encoding) -> Result<Arc<Vec<u8>>> {
+        let key = match self.data {
+            FrameData::Raw(_) => Some(*encoding),
+            FrameData::Encoded(_) => None,
+        };
+        let cell = self.encoded.lock().unwrap().entry(key).or_default().clone();
+        let serialized = cell
+            .get_or_try_init(|| async {
+                let mut msg = self.encode(encoding).await?;
+                // Reserves the field, see `stamp_send`.
+                msg.timings.send = Some(SystemTime::now());
+                let msg = WebSocketServerMessage::Images(msg);
+                anyhow::Ok(Arc::new(bincode::serialize(&msg)?))
+            })
+            .await?;
+        Ok(serialized.clone())
+    }
+}
+
+impl std::fmt::Debug for ImageFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageFrame").finish_non_exhaustive()
+    }
+}
+
+/// How long a websocket client has to answer the challenge.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Clients that don't accept a message for this long are disconnected.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long [`Server::shutdown`] waits for the clients to disconnect.
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Only the latest image of each source waits while the previous one is being
+/// sent.
+const IMAGE_QUEUE_CAPACITY: usize = 1;
+/// Control messages wait here for the clients. Maps lists and pose graphs are
+/// sizeable and come in bursts, e.g. after loop closures of several sources.
+const CONTROL_CHANNEL_CAPACITY: usize = 256;
+/// Deltas can't be dropped individually; overflowing the queue replaces it
+/// with a snapshot.
+const DELTA_QUEUE_CAPACITY: usize = 32;
+
 #[derive(Debug)]
 pub struct Server {
-    image_sender: broadcast::Sender<vrrop_common::ImagesMessage>,
+    image_sender: broadcast::Sender<Arc<ImageFrame>>,
     odometry_sender: broadcast::Sender<vrrop_common::OdometryMessage>,
-    _dummy_image_receiver: broadcast::Receiver<vrrop_common::ImagesMessage>,
+    _dummy_image_receiver: broadcast::Receiver<Arc<ImageFrame>>,
     _dummy_odometry_receiver: broadcast::Receiver<vrrop_common::OdometryMessage>,
     serve_websocket_join_handle: JoinHandle<()>,
     serve_udp_join_handle: JoinHandle<()>,
+    fusion_join_handle: Option<JoinHandle<()>>,
+    context: Arc<WebSocketContext>,
+}
+
+/// Shared by all websocket connections.
+struct WebSocketContext {
+    callbacks: Callbacks,
+    config: EncodingConfig,
+    auth: Option<AuthKey>,
+    tls: Option<TlsAcceptor>,
+    fusion: Option<Arc<Fusion>>,
+    sessions: Arc<Sessions>,
+    /// Goodbyes, map resets and notices for all clients.
+    control_sender: broadcast::Sender<WebSocketServerMessage>,
+    /// Current map epoch of each source, indexed by id.
+    map_epochs: Vec<AtomicU64>,
+    /// Source names, indexed by id.
+    sources: Vec<String>,
+    /// Last reported status of each camera, repeated to clients connecting
+    /// while it is down.
+    camera_status: Mutex<HashMap<SourceId, CameraStatusMessage>>,
+    /// Repeated to clients connecting later in the same map epoch.
+    relocalized: Mutex<Option<RelocalizedMessage>>,
+    /// Last saved maps list, repeated to clients connecting later.
+    maps: Mutex<Option<MapsMessage>>,
+    /// Whole pose graph of each source, repeated to clients connecting later
+    /// in the same map epoch.
+    pose_graphs: Mutex<HashMap<SourceId, PoseGraphMessage>>,
+}
+
+impl WebSocketContext {
+    fn map_epoch(&self, source: SourceId) -> u64 {
+        self.map_epochs
+            .get(source as usize)
+            .map_or(0, |epoch| epoch.load(Ordering::Relaxed))
+    }
+
+    /// Control messages a client connecting now missed, to be sent after the
+    /// welcome.
+    fn current_state(&self, sources: &SourceFilter) -> Vec<WebSocketServerMessage> {
+        let mut state: Vec<_> = self
+            .camera_status
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| !s.connected && sources.contains(s.source))
+            .cloned()
+            .map(WebSocketServerMessage::CameraStatus)
+            .collect();
+        let relocalized = self.relocalized.lock().unwrap().clone();
+        state.extend(
+            relocalized
+                .filter(|r| r.epoch == self.map_epoch(r.source) && sources.contains(r.source))
+                .map(WebSocketServerMessage::Relocalized),
+        );
+        let maps = self.maps.lock().unwrap().clone();
+        state.extend(maps.map(WebSocketServerMessage::Maps));
+        state.extend(
+            self.pose_graphs
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|g| g.epoch == self.map_epoch(g.source) && sources.contains(g.source))
+                .cloned()
+                .map(WebSocketServerMessage::PoseGraph),
+        );
+        state
+    }
+
+    /// Sources named in a client's hello, or the names no source has.
+    fn source_filter(&self, names: &[String]) -> Result<SourceFilter, Vec<String>> {
+        if names.is_empty() {
+            return Ok(SourceFilter::default());
+        }
+        let mut ids = Vec::new();
+        let mut unknown = Vec::new();
+        for name in names {
+            match self.sources.iter().position(|source| source == name) {
+                Some(id) => ids.push(id as SourceId),
+                None => unknown.push(format!("{name:?}")),
+            }
+        }
+        if unknown.is_empty() {
+            Ok(SourceFilter::only(ids))
+        } else {
+            Err(unknown)
+        }
+    }
+}
+
+impl std::fmt::Debug for WebSocketContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketContext").finish_non_exhaustive()
+    }
 }
 
 async fn serve_websocket(
-    port: u16,
-    image_receiver: broadcast::Sender<vrrop_common::ImagesMessage>,
-    callbacks: Arc<Callbacks>,
+    addr: SocketAddr,
+    image_receiver: broadcast::Sender<Arc<ImageFrame>>,
+    context: Arc<WebSocketContext>,
 ) -> Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let listener = TcpListener::bind(addr).await?;
     futures::stream::try_unfold(listener, move |listener| async move {
         match listener.accept().await {
             Ok((stream, peer_addr)) => Ok(Some(((stream, peer_addr), listener))),
@@ -65,10 +351,19 @@ async fn serve_websocket(
     .map_ok(|(stream, peer_addr)| {
         println!("Accepted websocket connection from {peer_addr}");
         let image_receiver = image_receiver.subscribe();
-        let callbacks = callbacks.clone();
+        let context = context.clone();
         tokio::spawn(async move {
-            let websocket = tokio_tungstenite::accept_async(stream).await?;
-            handle_websocket_connection(websocket, image_receiver, callbacks).await
+            let stream: Box<dyn IoStream> = match &context.tls {
+                Some(acceptor) => Box::new(
+                    timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                        .await
+                        .context("Timed out during TLS handshake")??,
+                ),
+                None => Box::new(stream),
+            };
+            let mut websocket = tokio_tungstenite::accept_async(stream).await?;
+            let hello = handshake(&mut websocket, context.auth.as_ref()).await?;
+            handle_websocket_connection(websocket, peer_addr, hello, image_receiver, &context).await
         })
         .map(move |e| {
             let res = e.unwrap();
@@ -90,103 +385,546 @@ async fn serve_websocket(
     Ok(())
 }
 
+/// A plain or TLS connection.
+trait IoStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IoStream for T {}
+
+/// Sends a challenge and waits for the client's hello, which has to answer it if
+/// the server has a key.
+async fn handshake(
+    websocket: &mut WebSocketStream<Box<dyn IoStream>>,
+    auth: Option<&AuthKey>,
+) -> Result<HelloMessage> {
+    fn rejected(reason: RejectReason, message: &str) -> anyhow::Error {
+        metrics().rejected(Transport::Websocket, reason);
+        anyhow!("{message} ({reason})")
+    }
+    let nonce = auth::new_nonce();
+    let challenge = WebSocketServerMessage::Challenge(ChallengeMessage { nonce });
+    websocket
+        .send(Message::binary(bincode::serialize(&challenge)?))
+        .await?;
+    let msg = match timeout(HANDSHAKE_TIMEOUT, websocket.next()).await {
+        Ok(Some(msg)) => msg?,
+        Ok(None) => bail!("Connection closed during handshake"),
+        Err(_) => {
+            return Err(rejected(
+                RejectReason::Unauthenticated,
+                "Timed out waiting for hello",
+            ))
+        }
+    };
+    let hello = match bincode::deserialize(&msg.into_data()) {
+        Ok(WebSocketClientMessage::Hello(hello)) => hello,
+        Ok(_) => {
+            return Err(rejected(
+                RejectReason::Unauthenticated,
+                "Expected hello as the first message",
+            ))
+        }
+        Err(_) => return Err(rejected(RejectReason::Malformed, "Invalid hello")),
+    };
+    if let Some(key) = auth {
+        match &hello.auth_response {
+            None => {
+                return Err(rejected(
+                    RejectReason::Unauthenticated,
+                    "Client sent no auth token",
+                ))
+            }
+            Some(response) if !key.verify_response(&nonce, response) => {
+                return Err(rejected(RejectReason::BadMac, "Wrong auth token"));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(hello)
+}
+
 async fn handle_websocket_connection(
-    websocket: WebSocketStream<TcpStream>,
-    mut image_receiver: broadcast::Receiver<vrrop_common::ImagesMessage>,
-    callbacks: Arc<Callbacks>,
+    websocket: WebSocketStream<Box<dyn IoStream>>,
+    peer_addr: SocketAddr,
+    hello: HelloMessage,
+    image_receiver: broadcast::Receiver<Arc<ImageFrame>>,
+    context: &WebSocketContext,
 ) -> Result<()> {
+    let WebSocketContext {
+        callbacks,
+        config,
+        fusion,
+        sessions,
+        ..
+    } = context;
     let (mut writer, mut reader) = websocket.split();
+    // Before the state is sent, so no change in between is missed.
+    let control_receiver = context.control_sender.subscribe();
+    let sources = match context.source_filter(&hello.sources) {
+        Ok(sources) => sources,
+        Err(unknown) => {
+            let reason = format!("No source named {}", unknown.join(", "));
+            let goodbye = WebSocketServerMessage::Goodbye(GoodbyeMessage {
+                reason: reason.clone(),
+                retry_after: None,
+            });
+            writer
+                .send(Message::binary(bincode::serialize(&goodbye)?))
+                .await?;
+            let _ = timeout(SEND_TIMEOUT, writer.close()).await;
+            bail!("{reason}");
+        }
+    };
+    let session = sessions.open(peer_addr, sources.clone());
+    let welcome = WebSocketServerMessage::Welcome(WelcomeMessage {
+        session_id: session.id,
+        session_token: session.token,
+        map_epochs: (0..context.sources.len())
+            .map(|source| context.map_epoch(source as SourceId))
+            .collect(),
+        sources: context.sources.clone(),
+    });
+    writer
+        .send(Message::binary(bincode::serialize(&welcome)?))
+        .await?;
+    for msg in context.current_state(&sources) {
+        writer
+            .send(Message::binary(bincode::serialize(&msg)?))
+            .await?;
+    }
+    let mode = if fusion.is_some() { "fusion" } else { "images" };
+    let _metrics_guard = metrics().websocket_client_connected(peer_addr, mode);
+    let mut congestion = CongestionController::new(config.ladder.clone());
+    metrics().update_websocket_client(peer_addr, |c| c.rung = congestion.rung_index());
+    let color_codec = config.negotiate_color_codec(&hello.color_codecs);
+    let depth_codec = config.negotiate_depth_codec(&hello.depth_codecs);
+    println!("{peer_addr}: using codecs {color_codec}/{depth_codec}");
+    let client = ClientInfo {
+        session: session.id,
+        address: peer_addr,
+        codecs: format!("{color_codec}/{depth_codec}"),
+    };
+    metrics().update_websocket_client(peer_addr, |c| c.codecs = client.codecs.clone());
+    let queue = Arc::new(SendQueue::new(if fusion.is_some() {
+        DELTA_QUEUE_CAPACITY
+    } else {
+        IMAGE_QUEUE_CAPACITY * context.sources.len().max(1)
+    }));
+    let (sent_sender, mut sent_receiver) = mpsc::unbounded_channel();
+    let mut writer_task = tokio::spawn(write_queued(
+        writer,
+        queue.clone(),
+        control_receiver,
+        sources.clone(),
+        sent_sender,
+        peer_addr,
+    ));
+    let _abort_writer = AbortOnDrop(writer_task.abort_handle());
+    // In fusion mode the client gets the map instead of images.
+    let subscribe = |fusion: Arc<Fusion>| {
+        let queue = queue.clone();
+        let sources = sources.clone();
+        async move {
+            let (receiver, sequence, snapshots) = fusion.subscribe().await?;
+            for (_, snapshot) in snapshots
+                .into_iter()
+                .filter(|(source, _)| sources.contains(*source))
+            {
+                queue.push(Outgoing::other(snapshot));
+            }
+            anyhow::Ok((receiver, sequence))
+        }
+    };
+    let mut delta_receiver = match fusion.clone() {
+        Some(fusion) => Some(subscribe(fusion).await?),
+        None => None,
+    };
+    let (mut image_receiver, _abort_images) = match fusion {
+        Some(_) => (None, None),
+        None => {
+            let capacity = IMAGE_QUEUE_CAPACITY * context.sources.len().max(1);
+            let (receiver, task) = filter_images(image_receiver, sources.clone(), capacity);
+            (Some(receiver), Some(AbortOnDrop(task.abort_handle())))
+        }
+    };
     loop {
+        let prev_rung = congestion.rung_index();
         select! {
-            res = image_receiver.recv() => {
+            res = recv_delta(&mut delta_receiver) => {
+                match res {
+                    Ok((source, _)) if !sources.contains(source) => continue,
+                    Ok((_, delta)) => {
+                        if queue.push(Outgoing::other(delta)).is_none() {
+                            continue;
+                        }
+                        println!("{peer_addr}: send queue overflowed, resending snapshot");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics().lagged(Channel::WebsocketDeltas, n);
+                        queue.record_dropped(n);
+                        println!("{peer_addr}: lagged behind map deltas, resending snapshot");
+                    }
+                }
+                // Missed deltas can't be recovered, start over from a snapshot.
+                queue.clear();
+                delta_receiver = Some(subscribe(fusion.clone().unwrap()).await?);
+            }
+            res = recv_image(&mut image_receiver) => {
                 match res {
-                    Ok(images) => {
-                let encoded_msg = bincode::serialize(&images)?;
-                writer.send(tokio_tungstenite::tungstenite::Message::binary(encoded_msg)).await?;
+                    Ok(frame) => {
+                        if !congestion.should_send() {
+                            continue;
+                        }
+                        let encoding = config.encoding(congestion.rung(), color_codec, depth_codec);
+                        let source = frame.source();
+                        let encoded_msg = frame.serialized(&encoding).await?;
+                        let outgoing = Outgoing { data: encoded_msg, images: Some(source) };
+                        let same_source = |queued: &Outgoing| queued.images == Some(source);
+                        if queue.push_limited(outgoing, IMAGE_QUEUE_CAPACITY, same_source).is_some() {
+                            congestion.on_lagged();
+                        }
                     }
                     Err(broadcast::error::RecvError::Closed) => return Ok(()),
-                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics().lagged(Channel::WebsocketImages, n);
+                        queue.record_dropped(n);
+                        congestion.on_lagged();
+                    }
+                }
+            }
+            Some((bytes, elapsed)) = sent_receiver.recv() => {
+                if delta_receiver.is_none() {
+                    // Without fusion the queue only has images. Frames of other
+                    // sources don't count, see `filter_images`.
+                    let pending = image_receiver.as_ref().map_or(0, |r| r.len());
+                    congestion.on_sent(bytes, elapsed, queue.len() + pending);
                 }
             }
+            res = &mut writer_task => return res?,
             res = reader.next() => {
                 match res {
                     Some(Ok(msg)) => {
-                        let cmd: Command = bincode::deserialize(&msg.into_data())?;
-                        (callbacks.on_command)(cmd);
+                        match bincode::deserialize(&msg.into_data())? {
+                            WebSocketClientMessage::Hello(_) => {
+                                eprintln!("{peer_addr}: ignoring repeated hello");
+                            }
+                            WebSocketClientMessage::Command(cmd) => (callbacks.on_command)(cmd, &client),
+                            WebSocketClientMessage::Feedback(feedback) => {
+                                congestion.on_feedback(Duration::from_nanos(feedback.latency_ns.max(0) as u64));
+                            }
+                        }
                     }
                     Some(Err(e)) => return Err(anyhow!(e)),
                     None => return Ok(()),
                 }
             }
         }
+        metrics()
+            .update_websocket_client(peer_addr, |c| c.messages_dropped = queue.total_dropped());
+        if congestion.rung_index() != prev_rung {
+            metrics().update_websocket_client(peer_addr, |c| c.rung = congestion.rung_index());
+            println!(
+                "{peer_addr}: image quality rung {} -> {} (throughput {:.0} kB/s)",
+                prev_rung,
+                congestion.rung_index(),
+                congestion.throughput().unwrap_or_default() / 1e3
+            );
+        }
+    }
+}
+
+/// A serialized message in a client's send queue.
+struct Outgoing {
+    data: Arc<Vec<u8>>,
+    /// Source of an images message. Images get their send time written in,
+    /// see [`stamp_send`].
+    images: Option<SourceId>,
+}
+
+impl Outgoing {
+    fn other(data: Arc<Vec<u8>>) -> Self {
+        Self { data, images: None }
+    }
+}
+
+/// Writes `send` to the [`vrrop_common::ImageTimings::send`] of a serialized
+/// images message, its last field. Images are serialized once for all clients
+/// with a send time, so the field has its full size.
+fn stamp_send(data: &mut [u8], send: SystemTime) -> Result<()> {
+    let stamp = bincode::serialize(&send)?;
+    let start = data
+        .len()
+        .checked_sub(stamp.len())
+        .context("Images message too short")?;
+    data[start..].copy_from_slice(&stamp);
+    Ok(())
+}
+
+/// The source a control message is about, if it's about one.
+fn control_source(msg: &WebSocketServerMessage) -> Option<SourceId> {
+    match msg {
+        WebSocketServerMessage::MapReset(reset) => Some(reset.source),
+        WebSocketServerMessage::CameraStatus(status) => Some(status.source),
+        WebSocketServerMessage::Relocalized(relocalized) => Some(relocalized.source),
+        WebSocketServerMessage::PoseGraph(pose_graph) => Some(pose_graph.source),
+        _ => None,
+    }
+}
+
+/// Sends queued messages to the client, preceded by a notice if any were
+/// dropped. Reports the size and send time of each message. Control messages
+/// skip the queue, those about sources the client didn't ask for are left out;
+/// after a goodbye the connection is closed.
+async fn write_queued(
+    mut writer: SplitSink<WebSocketStream<Box<dyn IoStream>>, Message>,
+    queue: Arc<SendQueue<Outgoing>>,
+    mut control_receiver: broadcast::Receiver<WebSocketServerMessage>,
+    sources: SourceFilter,
+    sent_sender: mpsc::UnboundedSender<(usize, Duration)>,
+    peer_addr: SocketAddr,
+) -> Result<()> {
+    async fn send(
+        writer: &mut SplitSink<WebSocketStream<Box<dyn IoStream>>, Message>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        match timeout(SEND_TIMEOUT, writer.send(Message::binary(data))).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                metrics().send_timed_out();
+                bail!("Client didn't accept a message within {SEND_TIMEOUT:?}")
+            }
+        }
+    }
+    loop {
+        let msg = select! {
+            biased;
+            res = control_receiver.recv() => {
+                match res {
+                    Ok(msg) if control_source(&msg).is_some_and(|s| !sources.contains(s)) => {}
+                    Ok(msg) => {
+                        send(&mut writer, bincode::serialize(&msg)?).await?;
+                        if let WebSocketServerMessage::Goodbye(_) = msg {
+                            let _ = timeout(SEND_TIMEOUT, writer.close()).await;
+                            return Ok(());
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        // The client's state would be off, it gets it anew when
+                        // it reconnects.
+                        metrics().lagged(Channel::WebsocketControl, n);
+                        bail!("Missed {n} control messages, closing the connection");
+                    }
+                }
+                continue;
+            }
+            msg = queue.pop() => msg,
+        };
+        let dropped = queue.take_dropped();
+        if dropped > 0 {
+            let notice = WebSocketServerMessage::Dropped(DroppedMessage { messages: dropped });
+            send(&mut writer, bincode::serialize(&notice)?).await?;
+        }
+        let mut data = msg.data.to_vec();
+        if msg.images.is_some() {
+            stamp_send(&mut data, SystemTime::now())?;
+        }
+        let (len, start) = (data.len(), Instant::now());
+        send(&mut writer, data).await?;
+        let _ = sent_sender.send((len, start.elapsed()));
+        metrics().update_websocket_client(peer_addr, |c| {
+            c.messages_sent += 1;
+            c.bytes_sent += len as u64;
+        });
+    }
+}
+
+/// Aborts a task when dropped.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+type ImageResult = Result<Arc<ImageFrame>, broadcast::error::RecvError>;
+
+/// Forwards the frames of `sources`, so only those wait for the client and
+/// count as its backlog.
+fn filter_images(
+    mut image_receiver: broadcast::Receiver<Arc<ImageFrame>>,
+    sources: SourceFilter,
+    capacity: usize,
+) -> (mpsc::Receiver<ImageResult>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let task = tokio::spawn(async move {
+        loop {
+            let res = image_receiver.recv().await;
+            if matches!(&res, Ok(frame) if !sources.contains(frame.source())) {
+                continue;
+            }
+            let closed = matches!(res, Err(broadcast::error::RecvError::Closed));
+            if sender.send(res).await.is_err() || closed {
+                return;
+            }
+        }
+    });
+    (receiver, task)
+}
+
+async fn recv_image(receiver: &mut Option<mpsc::Receiver<ImageResult>>) -> ImageResult {
+    let Some(receiver) = receiver else {
+        return std::future::pending().await;
+    };
+    receiver
+        .recv()
+        .await
+        .unwrap_or(Err(broadcast::error::RecvError::Closed))
+}
+
+/// Skips the deltas up to the sequence number of the snapshot, it has them
+/// already.
+async fn recv_delta(
+    receiver: &mut Option<(broadcast::Receiver<SequencedDelta>, u64)>,
+) -> Result<SerializedDelta, broadcast::error::RecvError> {
+    let Some((receiver, snapshot_sequence)) = receiver else {
+        return std::future::pending().await;
+    };
+    loop {
+        let (sequence, delta) = receiver.recv().await?;
+        if sequence > *snapshot_sequence {
+            return Ok(delta);
+        }
     }
 }
 
+/// Sends odometry to the UDP endpoints subscribed by websocket sessions.
 async fn serve_udp(
-    port: u16,
+    addr: SocketAddr,
+    auth: Option<AuthKey>,
     mut odometry_receiver: broadcast::Receiver<vrrop_common::OdometryMessage>,
+    sessions: Arc<Sessions>,
 ) -> Result<()> {
-    let udp_sock = Arc::new(UdpSocket::bind(("0.0.0.0", port)).await?);
-    let mut clients = HashMap::new();
+    let udp_sock = Arc::new(UdpSocket::bind(addr).await?);
+    let sealer = PacketSealer::new(auth.clone(), CONTEXT_SERVER);
+    // Replays are checked per session, see `Sessions`.
+    let opener = PacketOpener::new(auth, CONTEXT_CLIENT);
+    let mut rejects = RejectLog::new("udp");
+    let mut expire_interval = tokio::time::interval(Duration::from_secs(1));
     loop {
         let mut buf = [0u8; 2048];
         select! {
             res = udp_sock.recv_from(&mut buf) => {
                 let (n, src) = res?;
-                clients.insert(src, Instant::now());
-                let data = &buf[..n];
-                let msg: UdpClientMessage = bincode::deserialize(data)?;
-                match msg {
+                let msg = opener.authenticate(&buf[..n]).and_then(|(data, packet)| {
+                    let msg = bincode::deserialize::<UdpClientMessage>(data)
+                        .map_err(|_| RejectReason::Malformed)?;
+                    Ok((msg, packet))
+                });
+                let res = msg.and_then(|(msg, packet)| match msg {
                     UdpClientMessage::Ping(ping) => {
+                        sessions.ping(&ping.session_token, packet, src).map(|_| Some(ping))
+                    }
+                    UdpClientMessage::Subscribe(subscribe) => {
+                        sessions.subscribe(&subscribe.session_token, packet, src).map(|_| None)
+                    }
+                    UdpClientMessage::Leave(leave) => {
+                        sessions.leave(&leave.session_token, packet, src).map(|_| None)
+                    }
+                });
+                match res {
+                    Ok(Some(ping)) => {
                         let pong = UdpServerMessage::Pong(PongMessage {
                             client_time: ping.client_time,
                             server_time: SystemTime::now(),
                         });
-                        let encoded_msg = bincode::serialize(&pong)?;
+                        let encoded_msg = sealer.seal(bincode::serialize(&pong)?);
                         udp_sock.send_to(&encoded_msg, src).await?;
                     }
+                    Ok(None) => {}
+                    Err(reason) => {
+                        rejects.record(src, reason);
+                        metrics().rejected(Transport::Udp, reason);
+                    }
                 }
             }
+            _ = expire_interval.tick() => sessions.expire(),
             res = odometry_receiver.recv() => {
                 match res {
                     Ok(msg) => {
-                        clients = clients
-                            .into_iter()
-                            .filter_map(|(src, time)| {
-                                if time.elapsed().as_secs() < 5 {
-                                    Some((src, time))
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-                        let encoded_msg = bincode::serialize(&UdpServerMessage::Odometry(msg))?;
-                        for src in clients.keys() {
-                            udp_sock.send_to(&encoded_msg, src).await?;
+                        let source = msg.source;
+                        let encoded_msg = sealer.seal(bincode::serialize(&UdpServerMessage::Odometry(msg))?);
+                        for endpoint in sessions.endpoints(source) {
+                            udp_sock.send_to(&encoded_msg, endpoint).await?;
+                            sessions.odometry_sent(endpoint, encoded_msg.len());
                         }
                     }
                     Err(broadcast::error::RecvError::Closed) => return Ok(()),
-                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics().lagged(Channel::UdpOdometry, n);
+                        continue;
+                    }
                 }
             }
         }
     }
 }
 
+/// The websocket client a command came from.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    pub session: u64,
+    pub address: SocketAddr,
+    /// Negotiated color and depth codecs, e.g. `jpeg/png`.
+    pub codecs: String,
+}
+
+pub type CommandHandler = Box<dyn Fn(Command, &ClientInfo) + Send + Sync>;
+
 pub struct Callbacks {
-    pub on_command: Box<dyn Fn(Command) + Send + Sync>,
+    pub on_command: CommandHandler,
 }
 
 impl Server {
-    pub async fn new(port: u16, callbacks: Callbacks) -> Result<Self> {
-        let callbacks = Arc::new(callbacks);
-        let (image_sender, _image_receiver) = broadcast::channel(2);
-        let (odometry_sender, _odometry_receiver) = broadcast::channel(10);
+    pub async fn new(
+        addr: SocketAddr,
+        config: EncodingConfig,
+        auth: Option<AuthKey>,
+        tls: Option<TlsAcceptor>,
+        fusion: Option<Arc<Fusion>>,
+        sources: Vec<String>,
+        callbacks: Callbacks,
+    ) -> Result<Self> {
+        let sessions = Arc::new(Sessions::default());
+        metrics().set_sessions(sessions.clone());
+        // Room for a frame per source.
+        let (image_sender, _image_receiver) = broadcast::channel(2 * sources.len().max(1));
+        let (odometry_sender, _odometry_receiver) = broadcast::channel(10 * sources.len().max(1));
+        let fusion_join_handle = fusion
+            .as_ref()
+            .map(|fusion| fusion.spawn(image_sender.subscribe()));
+        let context = Arc::new(WebSocketContext {
+            callbacks,
+            config,
+            auth: auth.clone(),
+            tls,
+            fusion,
+            sessions: sessions.clone(),
+            control_sender: broadcast::channel(CONTROL_CHANNEL_CAPACITY).0,
+            map_epochs: sources.iter().map(|_| AtomicU64::new(0)).collect(),
+            sources,
+            camera_status: Mutex::new(HashMap::new()),
+            relocalized: Mutex::new(None),
+            maps: Mutex::new(None),
+            pose_graphs: Mutex::new(HashMap::new()),
+        });
         let serve_websocket_join_handle = tokio::spawn({
             let image_sender = image_sender.clone();
+            let context = context.clone();
             async move {
                 loop {
-                    match serve_websocket(port, image_sender.clone(), callbacks.clone()).await {
+                    match serve_websocket(addr, image_sender.clone(), context.clone()).await {
                         Ok(_) => {}
                         Err(e) => {
                             eprintln!("Error serving websocket: {:?}", e);
@@ -200,7 +938,14 @@ impl Server {
             let odometry_sender = odometry_sender.clone();
             async move {
                 loop {
-                    match serve_udp(port, odometry_sender.subscribe()).await {
+                    match serve_udp(
+                        addr,
+                        auth.clone(),
+                        odometry_sender.subscribe(),
+                        sessions.clone(),
+                    )
+                    .await
+                    {
                         Ok(_) => {}
                         Err(e) => {
                             eprintln!("Error serving udp: {:?}", e);
@@ -217,10 +962,115 @@ impl Server {
             _dummy_odometry_receiver: _odometry_receiver,
             serve_websocket_join_handle,
             serve_udp_join_handle,
+            fusion_join_handle,
+            context,
         })
     }
 
-    pub async fn shutdown(self) -> Result<()> {
+    pub fn map_epoch(&self, source: SourceId) -> u64 {
+        self.context.map_epoch(source)
+    }
+
+    /// Shows `text` to all connected clients.
+    pub fn notice(&self, text: impl Into<String>) {
+        let notice = WebSocketServerMessage::Notice(NoticeMessage { text: text.into() });
+        let _ = self.context.control_sender.send(notice);
+    }
+
+    /// Tells the clients when the camera of `source` is lost and when it's back.
+    pub fn camera_status(&self, source: SourceId, connected: bool, message: impl Into<String>) {
+        let status = CameraStatusMessage {
+            source,
+            connected,
+            message: message.into(),
+        };
+        self.context
+            .camera_status
+            .lock()
+            .unwrap()
+            .insert(source, status.clone());
+        let _ = self
+            .context
+            .control_sender
+            .send(WebSocketServerMessage::CameraStatus(status));
+    }
+
+    pub fn maps(&self, maps: Vec<MapInfo>, loaded: Option<String>) {
+        let maps = MapsMessage { maps, loaded };
+        *self.context.maps.lock().unwrap() = Some(maps.clone());
+        let _ = self
+            .context
+            .control_sender
+            .send(WebSocketServerMessage::Maps(maps));
+    }
+
+    /// Tells the clients that poses of the current epoch of `source` are in
+    /// the frame of the saved map `map`.
+    pub fn relocalized(&self, source: SourceId, map: impl Into<String>) {
+        let relocalized = RelocalizedMessage {
+            map: map.into(),
+            source,
+            epoch: self.map_epoch(source),
+        };
+        *self.context.relocalized.lock().unwrap() = Some(relocalized.clone());
+        let _ = self
+            .context
+            .control_sender
+            .send(WebSocketServerMessage::Relocalized(relocalized));
+    }
+
+    /// Sends loop closure corrections of the current epoch. New clients get
+    /// the whole graph.
+    pub fn pose_graph(&self, pose_graph: PoseGraphMessage) {
+        self.context
+            .pose_graphs
+            .lock()
+            .unwrap()
+            .entry(pose_graph.source)
+            .or_insert_with(|| PoseGraphMessage::new(pose_graph.epoch, pose_graph.source))
+            .merge(pose_graph.clone());
+        let _ = self
+            .context
+            .control_sender
+            .send(WebSocketServerMessage::PoseGraph(pose_graph));
+    }
+
+    /// Tells the clients to drop their map of `source` and clears the fused
+    /// one. The other sources keep their maps. Returns the new map epoch.
+    pub fn reset_map(&self, source: SourceId) -> Result<u64> {
+        let epoch = self
+            .context
+            .map_epochs
+            .get(source as usize)
+            .with_context(|| format!("Unknown source {source}"))?
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        let reset = WebSocketServerMessage::MapReset(MapResetMessage { source, epoch });
+        let _ = self.context.control_sender.send(reset);
+        if let Some(fusion) = &self.context.fusion {
+            fusion.reset(source, epoch)?;
+        }
+        Ok(epoch)
+    }
+
+    /// Says goodbye to the clients and waits briefly for them to disconnect
+    /// before stopping.
+    pub async fn shutdown(self, reason: &str, retry_after: Option<Duration>) -> Result<()> {
+        let goodbye = WebSocketServerMessage::Goodbye(GoodbyeMessage {
+            reason: reason.to_owned(),
+            retry_after,
+        });
+        if self.context.control_sender.send(goodbye).is_ok() {
+            let _ = timeout(GOODBYE_TIMEOUT, async {
+                while !self.context.sessions.is_empty() {
+                    sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await;
+        }
+        if let Some(fusion_join_handle) = &self.fusion_join_handle {
+            fusion_join_handle.abort();
+        }
         self.serve_websocket_join_handle.abort();
         self.serve_udp_join_handle.abort();
         match self.serve_websocket_join_handle.await {
@@ -246,66 +1096,217 @@ impl Server {
         self.odometry_sender.clone()
     }
 
-    pub fn image_sender(&self) -> broadcast::Sender<vrrop_common::ImagesMessage> {
+    pub fn image_sender(&self) -> broadcast::Sender<Arc<ImageFrame>> {
         self.image_sender.clone()
     }
 }
 
 pub fn encode_odometry_message(msg: &OdometryMessage) -> vrrop_common::OdometryMessage {
     vrrop_common::OdometryMessage {
+        epoch: msg.epoch,
+        source: msg.source,
         stamp: msg.stamp,
         translation: msg.translation.into(),
         rotation: (*msg.rotation.into_inner().as_vector()).into(),
     }
 }
 
-pub async fn encode_images_message(msg: &ImagesMessage) -> Result<vrrop_common::ImagesMessage> {
+pub async fn encode_images_message(
+    msg: &ImagesMessage,
+    encoding: &Encoding,
+) -> Result<vrrop_common::ImagesMessage> {
+    let (start, encode_start) = (Instant::now(), SystemTime::now());
     let (color, depth) = tokio::join!(
-        encode_color(msg.color.clone()),
-        encode_depth(msg.depth.clone())
+        encode_color(msg.color.clone(), encoding),
+        encode_depth(msg.depth.clone(), encoding)
     );
+    metrics().observe_encode(EncodeStage::Total, start.elapsed());
+    let timings = ImageTimings {
+        capture: msg.capture,
+        odometry: Some(msg.odometry.stamp),
+        encode_start: Some(encode_start),
+        encode_end: Some(SystemTime::now()),
+        send: None,
+    };
     Ok(vrrop_common::ImagesMessage {
         odometry: encode_odometry_message(&msg.odometry),
         color_image: color?,
-        color_intrinsics: msg.color_intrinsics,
+        color_codec: encoding.color_codec,
+        color_intrinsics: scale_intrinsics(msg.color_intrinsics, encoding.rung.scale),
         depth_image: depth?,
-        depth_intrinsics: msg.depth_intrinsics,
+        depth_codec: encoding.depth_codec,
+        depth_intrinsics: scale_intrinsics(msg.depth_intrinsics, encoding.rung.scale),
         depth_unit: 0.001,
+        timings,
     })
 }
 
-async fn encode_color(img: Arc<ColorImage>) -> Result<Vec<u8>> {
+fn scale_intrinsics(intrinsics: CameraIntrinsics, scale: u32) -> CameraIntrinsics {
+    let s = scale as f32;
+    CameraIntrinsics {
+        width: intrinsics.width / scale,
+        height: intrinsics.height / scale,
+        fx: intrinsics.fx / s,
+        fy: intrinsics.fy / s,
+        cx: intrinsics.cx / s,
+        cy: intrinsics.cy / s,
+    }
+}
+
+/// Copies the `width * height` pixels of an image of the SLAM core.
+fn to_owned<P: Pixel>(
+    img: &ImageBuffer<P, ImageData<P::Subpixel>>,
+) -> Option<ImageBuffer<P, Vec<P::Subpixel>>> {
+    let len = img.width() as usize * img.height() as usize * P::CHANNEL_COUNT as usize;
+    let samples = img.as_raw().get(..len)?.to_vec();
+    ImageBuffer::from_raw(img.width(), img.height(), samples)
+}
+
+async fn encode_color(img: Arc<ColorImage>, encoding: &Encoding) -> Result<Vec<u8>> {
+    let Encoding {
+        rung, color_codec, ..
+    } = *encoding;
     tokio::task::spawn_blocking(move || {
-        let mut dst = Vec::new();
-        let enc = JpegEncoder::new_with_quality(&mut dst, 70);
-        enc.write_image(
-            &img.as_bytes()[..image_size(img.as_ref())],
-            img.width(),
-            img.height(),
-            ExtendedColorType::Rgb8,
-        )?;
-        Ok(dst)
+        let start = Instant::now();
+        let encoded = if rung.scale > 1 {
+            let resized = imageops::resize(
+                img.as_ref(),
+                img.width() / rung.scale,
+                img.height() / rung.scale,
+                FilterType::Triangle,
+            );
+            codec::encode_color(
+                color_codec,
+                resized.as_raw(),
+                resized.width(),
+                resized.height(),
+                rung.jpeg_quality,
+            )
+        } else {
+            codec::encode_color(
+                color_codec,
+                img.as_raw(),
+                img.width(),
+                img.height(),
+                rung.jpeg_quality,
+            )
+        };
+        metrics().observe_encode(EncodeStage::Color, start.elapsed());
+        encoded
     })
     .await?
 }
 
-async fn encode_depth(img: Arc<DepthImage>) -> Result<Vec<u8>> {
+async fn encode_depth(img: Arc<DepthImage>, encoding: &Encoding) -> Result<Vec<u8>> {
+    let Encoding {
+        rung,
+        depth_codec,
+        depth_max_error_mm,
+        ..
+    } = *encoding;
     tokio::task::spawn_blocking(move || {
-        let mut dst = Vec::new();
-        let enc = PngEncoder::new(&mut dst);
-        enc.write_image(
-            &img.as_bytes()[..image_size(img.as_ref())],
-            img.width(),
-            img.height(),
-            ExtendedColorType::L16,
-        )?;
-        Ok(dst)
+        let start = Instant::now();
+        let encoded = if rung.scale > 1 {
+            // Interpolating depth would create points between foreground and background.
+            let resized = imageops::resize(
+                img.as_ref(),
+                img.width() / rung.scale,
+                img.height() / rung.scale,
+                FilterType::Nearest,
+            );
+            codec::encode_depth(
+                depth_codec,
+                resized.as_raw(),
+                resized.width(),
+                resized.height(),
+                depth_max_error_mm,
+            )
+        } else {
+            codec::encode_depth(
+                depth_codec,
+                img.as_raw(),
+                img.width(),
+                img.height(),
+                depth_max_error_mm,
+            )
+        };
+        metrics().observe_encode(EncodeStage::Depth, start.elapsed());
+        encoded
     })
     .await?
 }
 
-fn image_size<V: image::GenericImageView>(img: &V) -> usize {
-    let channels = <V::Pixel as image::Pixel>::CHANNEL_COUNT as usize;
-    let subpixel_size = std::mem::size_of::<<V::Pixel as image::Pixel>::Subpixel>();
-    img.width() as usize * img.height() as usize * subpixel_size * channels
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn images_message() -> vrrop_common::ImagesMessage {
+        let intrinsics = CameraIntrinsics {
+            width: 1,
+            height: 1,
+            fx: 1.0,
+            fy: 1.0,
+            cx: 0.0,
+            cy: 0.0,
+        };
+        vrrop_common::ImagesMessage {
+            odometry: vrrop_common::OdometryMessage {
+                epoch: 1,
+                source: 0,
+                stamp: at(10),
+                translation: [0.0; 3],
+                rotation: [0.0, 0.0, 0.0, 1.0],
+            },
+            color_image: vec![1, 2, 3],
+            color_codec: ColorCodec::default(),
+            color_intrinsics: intrinsics,
+            depth_image: vec![4, 5],
+            depth_codec: DepthCodec::default(),
+            depth_intrinsics: intrinsics,
+            depth_unit: 0.001,
+            timings: ImageTimings {
+                encode_end: Some(at(11)),
+                send: Some(at(11)),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn send_time_is_written_into_serialized_images() {
+        let msg = WebSocketServerMessage::Images(images_message());
+        let mut data = bincode::serialize(&msg).unwrap();
+        stamp_send(&mut data, at(12) + Duration::from_nanos(5)).unwrap();
+        let WebSocketServerMessage::Images(msg) = bincode::deserialize(&data).unwrap() else {
+            panic!("not images");
+        };
+        assert_eq!(msg.timings.encode_end, Some(at(11)));
+        assert_eq!(msg.timings.send, Some(at(12) + Duration::from_nanos(5)));
+        assert_eq!(msg.depth_image, [4, 5]);
+    }
+
+    /// `stamp_send` overwrites the end of the message, which must be the send
+    /// time. Stops compiling when a field is added, new ones go before
+    /// `timings`.
+    #[test]
+    fn send_time_is_serialized_last() {
+        let vrrop_common::ImagesMessage {
+            odometry: _,
+            color_image: _,
+            color_codec: _,
+            color_intrinsics: _,
+            depth_image: _,
+            depth_codec: _,
+            depth_intrinsics: _,
+            depth_unit: _,
+            timings,
+        } = images_message();
+        let data = bincode::serialize(&WebSocketServerMessage::Images(images_message())).unwrap();
+        assert!(data.ends_with(&bincode::serialize(&timings).unwrap()));
+        assert!(data.ends_with(&bincode::serialize(&timings.send).unwrap()));
+    }
 }
@@ -0,0 +1,386 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use vrrop_common::{
+    auth,
+    auth::{PacketId, RejectReason},
+    SessionToken, SourceId,
+};
+
+use crate::metrics::{metrics, UdpClientStatus, UdpEvent};
+
+/// UDP subscriptions that haven't sent anything for this long end.
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Per-subscription counters.
+#[derive(Debug, Clone)]
+pub struct UdpSubscription {
+    pub endpoint: SocketAddr,
+    pub subscribed_at: Instant,
+    pub last_seen: Instant,
+    pub pings: u64,
+    pub odometry_sent: u64,
+    pub bytes_sent: u64,
+}
+
+/// Sources a client receives.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceFilter(Option<Vec<SourceId>>);
+
+impl SourceFilter {
+    pub fn only(sources: Vec<SourceId>) -> Self {
+        Self(Some(sources))
+    }
+
+    pub fn contains(&self, source: SourceId) -> bool {
+        self.0
+            .as_ref()
+            .is_none_or(|sources| sources.contains(&source))
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    websocket_peer: SocketAddr,
+    token: SessionToken,
+    sources: SourceFilter,
+    udp: Option<UdpSubscription>,
+    /// Last datagram accepted for the session. Clients seal the datagrams of a
+    /// session with one sender id, the replay state goes with the session.
+    last_packet: Option<PacketId>,
+}
+
+impl Session {
+    fn accept(&mut self, packet: Option<PacketId>) -> Result<(), RejectReason> {
+        let Some(packet) = packet else {
+            return Ok(());
+        };
+        if let Some(last) = self.last_packet {
+            if packet.sender_id != last.sender_id || packet.seq <= last.seq {
+                return Err(RejectReason::Replayed);
+            }
+        }
+        self.last_packet = Some(packet);
+        Ok(())
+    }
+}
+
+/// Websocket clients that completed the handshake. Each may subscribe one UDP
+/// endpoint to odometry by presenting the token it got over the websocket.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    inner: Mutex<SessionsInner>,
+}
+
+#[derive(Debug, Default)]
+struct SessionsInner {
+    next_id: u64,
+    sessions: HashMap<u64, Session>,
+}
+
+/// Ends the session when the websocket connection closes.
+pub struct SessionGuard {
+    sessions: Arc<Sessions>,
+    pub id: u64,
+    pub token: SessionToken,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let session = self
+            .sessions
+            .inner
+            .lock()
+            .unwrap()
+            .sessions
+            .remove(&self.id);
+        if let Some(udp) = session.and_then(|s| s.udp) {
+            println!(
+                "Session {}: UDP {} left (websocket closed)",
+                self.id, udp.endpoint
+            );
+            metrics().udp_event(UdpEvent::Closed);
+        }
+    }
+}
+
+impl SessionsInner {
+    fn find(&mut self, token: &SessionToken) -> Result<(u64, &mut Session), RejectReason> {
+        self.sessions
+            .iter_mut()
+            .find(|(_, s)| s.token == *token)
+            .map(|(id, s)| (*id, s))
+            .ok_or(RejectReason::Unauthenticated)
+    }
+}
+
+impl Sessions {
+    pub fn open(
+        self: &Arc<Self>,
+        websocket_peer: SocketAddr,
+        sources: SourceFilter,
+    ) -> SessionGuard {
+        let mut token = SessionToken::default();
+        let len = token.len();
+        token.copy_from_slice(&auth::new_nonce()[..len]);
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.sessions.insert(
+            id,
+            Session {
+                websocket_peer,
+                token,
+                sources,
+                udp: None,
+                last_packet: None,
+            },
+        );
+        SessionGuard {
+            sessions: Arc::clone(self),
+            id,
+            token,
+        }
+    }
+
+    /// Subscribes `endpoint`, replacing the previous endpoint of the session.
+    /// Clients repeat this as a keepalive. `packet` is the id of the datagram,
+    /// if authenticated, as for the other UDP messages.
+    pub fn subscribe(
+        &self,
+        token: &SessionToken,
+        packet: Option<PacketId>,
+        endpoint: SocketAddr,
+    ) -> Result<(), RejectReason> {
+        let mut inner = self.inner.lock().unwrap();
+        let (id, session) = inner.find(token)?;
+        session.accept(packet)?;
+        let now = Instant::now();
+        match &mut session.udp {
+            Some(udp) if udp.endpoint == endpoint => udp.last_seen = now,
+            udp => {
+                match udp {
+                    Some(prev) => println!(
+                        "Session {id}: UDP moved from {} to {endpoint}",
+                        prev.endpoint
+                    ),
+                    None => println!(
+                        "Session {id}: UDP {endpoint} subscribed (websocket {})",
+                        session.websocket_peer
+                    ),
+                }
+                metrics().udp_event(UdpEvent::Subscribed);
+                *udp = Some(UdpSubscription {
+                    endpoint,
+                    subscribed_at: now,
+                    last_seen: now,
+                    pings: 0,
+                    odometry_sent: 0,
+                    bytes_sent: 0,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Pings are only answered on the subscribed endpoint.
+    pub fn ping(
+        &self,
+        token: &SessionToken,
+        packet: Option<PacketId>,
+        endpoint: SocketAddr,
+    ) -> Result<(), RejectReason> {
+        let mut inner = self.inner.lock().unwrap();
+        let (_, session) = inner.find(token)?;
+        session.accept(packet)?;
+        match &mut session.udp {
+            Some(udp) if udp.endpoint == endpoint => {
+                udp.last_seen = Instant::now();
+                udp.pings += 1;
+                Ok(())
+            }
+            _ => Err(RejectReason::Unauthenticated),
+        }
+    }
+
+    pub fn leave(
+        &self,
+        token: &SessionToken,
+        packet: Option<PacketId>,
+        endpoint: SocketAddr,
+    ) -> Result<(), RejectReason> {
+        let mut inner = self.inner.lock().unwrap();
+        let (id, session) = inner.find(token)?;
+        session.accept(packet)?;
+        match &session.udp {
+            Some(udp) if udp.endpoint == endpoint => {
+                println!("Session {id}: UDP {endpoint} left");
+                metrics().udp_event(UdpEvent::Left);
+                session.udp = None;
+                Ok(())
+            }
+            _ => Err(RejectReason::Unauthenticated),
+        }
+    }
+
+    /// Ends subscriptions that haven't been refreshed within [`UDP_TIMEOUT`].
+    pub fn expire(&self) {
+        let now = Instant::now();
+        for (id, session) in self.inner.lock().unwrap().sessions.iter_mut() {
+            if let Some(udp) = &session.udp {
+                if now.duration_since(udp.last_seen) > UDP_TIMEOUT {
+                    println!("Session {id}: UDP {} timed out", udp.endpoint);
+                    metrics().udp_event(UdpEvent::TimedOut);
+                    session.udp = None;
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().sessions.is_empty()
+    }
+
+    /// Endpoints of the sessions receiving `source`.
+    pub fn endpoints(&self, source: SourceId) -> Vec<SocketAddr> {
+        self.inner
+            .lock()
+            .unwrap()
+            .sessions
+            .values()
+            .filter(|s| s.sources.contains(source))
+            .filter_map(|s| s.udp.as_ref().map(|udp| udp.endpoint))
+            .collect()
+    }
+
+    pub fn odometry_sent(&self, endpoint: SocketAddr, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let udp = inner
+            .sessions
+            .values_mut()
+            .filter_map(|s| s.udp.as_mut())
+            .find(|udp| udp.endpoint == endpoint);
+        if let Some(udp) = udp {
+            udp.odometry_sent += 1;
+            udp.bytes_sent += bytes as u64;
+        }
+    }
+
+    pub fn udp_clients(&self) -> Vec<UdpClientStatus> {
+        let now = Instant::now();
+        let mut clients: Vec<_> = self
+            .inner
+            .lock()
+            .unwrap()
+            .sessions
+            .iter()
+            .filter_map(|(id, session)| {
+                let udp = session.udp.as_ref()?;
+                Some(UdpClientStatus {
+                    session: *id,
+                    websocket_address: session.websocket_peer,
+                    address: udp.endpoint,
+                    subscribed_secs: now.duration_since(udp.subscribed_at).as_secs_f64(),
+                    last_seen_secs: now.duration_since(udp.last_seen).as_secs_f64(),
+                    pings: udp.pings,
+                    odometry_sent: udp.odometry_sent,
+                    bytes_sent: udp.bytes_sent,
+                })
+            })
+            .collect();
+        clients.sort_by_key(|c| c.session);
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_endpoints_follow_the_websocket_session() {
+        let sessions = Arc::new(Sessions::default());
+        let guard = sessions.open("10.0.0.2:40000".parse().unwrap(), SourceFilter::default());
+        let endpoint: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let stranger: SocketAddr = "10.0.0.3:50000".parse().unwrap();
+
+        assert_eq!(
+            sessions.subscribe(&[0; 16], None, endpoint),
+            Err(RejectReason::Unauthenticated)
+        );
+        assert_eq!(
+            sessions.ping(&guard.token, None, endpoint),
+            Err(RejectReason::Unauthenticated)
+        );
+        sessions.subscribe(&guard.token, None, endpoint).unwrap();
+        sessions.ping(&guard.token, None, endpoint).unwrap();
+        assert!(sessions.ping(&guard.token, None, stranger).is_err());
+        assert_eq!(sessions.endpoints(0), vec![endpoint]);
+
+        sessions.odometry_sent(endpoint, 100);
+        let status = sessions.udp_clients();
+        assert_eq!((status[0].pings, status[0].bytes_sent), (1, 100));
+
+        sessions.leave(&guard.token, None, endpoint).unwrap();
+        assert!(sessions.endpoints(0).is_empty());
+
+        sessions.subscribe(&guard.token, None, endpoint).unwrap();
+        drop(guard);
+        assert!(sessions.endpoints(0).is_empty());
+    }
+
+    #[test]
+    fn udp_odometry_follows_the_source_filter() {
+        let sessions = Arc::new(Sessions::default());
+        let front = sessions.open(
+            "10.0.0.2:40000".parse().unwrap(),
+            SourceFilter::only(vec![0]),
+        );
+        let all = sessions.open("10.0.0.3:40000".parse().unwrap(), SourceFilter::default());
+        let front_endpoint: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let all_endpoint: SocketAddr = "10.0.0.3:50000".parse().unwrap();
+        sessions
+            .subscribe(&front.token, None, front_endpoint)
+            .unwrap();
+        sessions.subscribe(&all.token, None, all_endpoint).unwrap();
+        let mut endpoints = sessions.endpoints(0);
+        endpoints.sort();
+        assert_eq!(endpoints, vec![front_endpoint, all_endpoint]);
+        assert_eq!(sessions.endpoints(1), vec![all_endpoint]);
+    }
+
+    #[test]
+    fn udp_replays_are_checked_per_session() {
+        let sessions = Arc::new(Sessions::default());
+        let guard = sessions.open("10.0.0.2:40000".parse().unwrap(), SourceFilter::default());
+        let endpoint: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let packet = |sender_id, seq| Some(PacketId { sender_id, seq });
+
+        sessions
+            .subscribe(&guard.token, packet(7, 10), endpoint)
+            .unwrap();
+        assert_eq!(
+            sessions.ping(&guard.token, packet(7, 10), endpoint),
+            Err(RejectReason::Replayed)
+        );
+        sessions
+            .ping(&guard.token, packet(7, 11), endpoint)
+            .unwrap();
+        // Only the client of the session can seal its datagrams.
+        assert_eq!(
+            sessions.ping(&guard.token, packet(8, 12), endpoint),
+            Err(RejectReason::Replayed)
+        );
+
+        // A new session starts over, nothing is kept for the old one.
+        drop(guard);
+        let guard = sessions.open("10.0.0.2:40000".parse().unwrap(), SourceFilter::default());
+        sessions
+            .subscribe(&guard.token, packet(8, 1), endpoint)
+            .unwrap();
+    }
+}
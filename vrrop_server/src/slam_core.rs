@@ -1,17 +1,33 @@
-use std::{ffi::c_void, mem::MaybeUninit, ops::Deref, ptr::NonNull};
+use std::{
+    ffi::{c_void, CStr, CString},
+    mem::MaybeUninit,
+    ops::Deref,
+    path::Path,
+    ptr::NonNull,
+};
 
+use crate::config::{CameraConfig, ImuFilter, SlamConfig};
 use crate::slam_core_sys::*;
+use anyhow::{bail, Result};
 use image::{ImageBuffer, Luma, Primitive, Rgb};
 use nalgebra::{Quaternion, UnitQuaternion, Vector3};
 use vrrop_common::CameraIntrinsics;
 
 pub struct SlamCore<'a> {
     inner: *mut slam_core_t,
-    callback: Option<FfiCallback<'a>>,
+    callback: Option<FfiCallback<'a, OdometryEvent>>,
+    localization_callback: Option<FfiCallback<'a, LocalizationEvent>>,
+    graph_callback: Option<FfiCallback<'a, GraphEvent>>,
     color_intrinsics: CameraIntrinsics,
     depth_intrinsics: CameraIntrinsics,
 }
 
+// The C++ core synchronizes with its own threads and the callbacks are Send,
+// so it can be started and stopped on another thread. Of the methods taking
+// `&self` only `save_map` calls into it, which locks.
+unsafe impl Send for SlamCore<'_> {}
+unsafe impl Sync for SlamCore<'_> {}
+
 pub type ColorImage = ImageBuffer<Rgb<u8>, ImageData<u8>>;
 pub type DepthImage = ImageBuffer<Luma<u16>, ImageData<u16>>;
 
@@ -47,7 +63,7 @@ impl<T: Primitive> From<NonNull<slam_core_image_t>> for ImageData<T> {
                     slam_core_image_get_data(image.as_ptr()),
                 ))
                 .unwrap(),
-                len: slam_core_image_get_size(image.as_ptr()),
+                len: sample_count::<T>(slam_core_image_get_size(image.as_ptr())),
                 inner: image,
                 _phantom: std::marker::PhantomData,
             }
@@ -55,6 +71,11 @@ impl<T: Primitive> From<NonNull<slam_core_image_t>> for ImageData<T> {
     }
 }
 
+/// The core reports image sizes in bytes.
+fn sample_count<T>(bytes: usize) -> usize {
+    bytes / std::mem::size_of::<T>()
+}
+
 impl<T: Primitive> Drop for ImageData<T> {
     fn drop(&mut self) {
         unsafe { slam_core_image_destroy(self.inner.as_ptr()) };
@@ -62,38 +83,65 @@ impl<T: Primitive> Drop for ImageData<T> {
 }
 
 pub struct OdometryEvent {
+    /// Capture time in seconds on the camera clock. Graph events refer to
+    /// frames by it.
+    pub stamp: f64,
     pub translation: Vector3<f32>,
     pub rotation: UnitQuaternion<f32>,
     pub color_image: Option<ColorImage>,
     pub depth_image: Option<DepthImage>,
 }
 
-struct FfiCallback<'a>(Box<Box<dyn Fn(OdometryEvent) + 'a + Send>>);
-struct FfiCallbackRef<'a>(*const Box<dyn Fn(OdometryEvent) + 'a + Send>);
+/// The current frame was matched to `node_id` of the map: a loop closure, or
+/// relocalization in a loaded map.
+pub struct LocalizationEvent {
+    pub node_id: i32,
+}
+
+/// Optimized pose of a map node.
+pub struct NodePose {
+    pub id: i32,
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+}
+
+/// RTAB-Map finished processing a frame.
+pub struct GraphEvent {
+    /// Node added for the frame captured at `stamp`, if it was added to the map.
+    pub node: Option<(i32, f64)>,
+    /// All node poses after the graph was optimized for a loop closure, empty
+    /// otherwise.
+    pub poses: Vec<NodePose>,
+}
+
+type BoxedCallback<'a, E> = Box<dyn Fn(E) + 'a + Send>;
 
-impl<'a> FfiCallback<'a> {
+struct FfiCallback<'a, E>(Box<BoxedCallback<'a, E>>);
+struct FfiCallbackRef<'a, E>(*const BoxedCallback<'a, E>);
+
+impl<'a, E> FfiCallback<'a, E> {
     fn new<F>(cb: F) -> Self
     where
-        F: Fn(OdometryEvent) + 'a + Send,
+        F: Fn(E) + 'a + Send,
     {
         Self(Box::new(Box::new(cb)))
     }
 
-    fn as_ref(&self) -> FfiCallbackRef<'a> {
+    fn as_ref(&self) -> FfiCallbackRef<'a, E> {
         FfiCallbackRef(self.0.as_ref())
     }
 }
 
-impl<'a> FfiCallbackRef<'a> {
+impl<'a, E> FfiCallbackRef<'a, E> {
     fn as_ptr(&self) -> *mut c_void {
-        unsafe { std::mem::transmute(self.0) }
+        self.0 as *mut c_void
     }
 
     unsafe fn from_ptr(ptr: *const c_void) -> Self {
-        std::mem::transmute(ptr)
+        Self(ptr as *const BoxedCallback<'a, E>)
     }
 
-    fn call(&self, ev: OdometryEvent) {
+    fn call(&self, ev: E) {
         unsafe { (*self.0)(ev) }
     }
 }
@@ -103,8 +151,9 @@ unsafe extern "C" fn odometry_event_handler(
     raw_ev: *const slam_core_odometry_event_t,
 ) {
     let raw_ev = raw_ev.as_ref().unwrap();
-    let cb = FfiCallbackRef::from_ptr(userdata);
+    let cb = FfiCallbackRef::<OdometryEvent>::from_ptr(userdata);
     let rust_ev: OdometryEvent = OdometryEvent {
+        stamp: raw_ev.stamp,
         translation: Vector3::new(
             raw_ev.translation[0],
             raw_ev.translation[1],
@@ -148,9 +197,116 @@ unsafe extern "C" fn odometry_event_handler(
     cb.call(rust_ev);
 }
 
+unsafe extern "C" fn localization_event_handler(
+    userdata: *mut std::ffi::c_void,
+    raw_ev: *const slam_core_localization_event_t,
+) {
+    let raw_ev = raw_ev.as_ref().unwrap();
+    FfiCallbackRef::<LocalizationEvent>::from_ptr(userdata).call(LocalizationEvent {
+        node_id: raw_ev.node_id,
+    });
+}
+
+unsafe extern "C" fn graph_event_handler(
+    userdata: *mut std::ffi::c_void,
+    raw_ev: *const slam_core_graph_event_t,
+) {
+    let raw_ev = raw_ev.as_ref().unwrap();
+    let poses = if raw_ev.pose_count == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(raw_ev.poses, raw_ev.pose_count)
+    };
+    FfiCallbackRef::<GraphEvent>::from_ptr(userdata).call(GraphEvent {
+        node: (raw_ev.node_id > 0).then_some((raw_ev.node_id, raw_ev.node_stamp)),
+        poses: poses
+            .iter()
+            .map(|pose| NodePose {
+                id: pose.id,
+                translation: Vector3::from(pose.translation),
+                rotation: UnitQuaternion::new_normalize(Quaternion::new(
+                    pose.rotation[0],
+                    pose.rotation[1],
+                    pose.rotation[2],
+                    pose.rotation[3],
+                )),
+            })
+            .collect(),
+    });
+}
+
+/// RTAB-Map parameters that keep a loaded map as it is and only localize in it.
+const LOCALIZATION_PARAMETERS: [(&str, &str); 2] = [
+    ("Mem/IncrementalMemory", "false"),
+    ("Mem/InitWMWithAllNodes", "true"),
+];
+
 impl<'a> SlamCore<'a> {
-    pub fn new() -> Self {
-        let inner = unsafe { slam_core_create() };
+    /// Starts an empty map, or localizes in the map saved at `map`. Without a
+    /// `serial` the first camera found is used.
+    ///
+    /// Strings are checked for NUL characters by [`crate::config::Config::validate`].
+    pub fn new(
+        camera: &CameraConfig,
+        serial: Option<&str>,
+        slam: &SlamConfig,
+        map: Option<&Path>,
+    ) -> Result<Self> {
+        let cstring = |s: &str| CString::new(s).expect("NUL in SLAM config");
+        let serial = serial.map(cstring);
+        let json_preset = camera
+            .json_preset
+            .as_ref()
+            .map(|path| cstring(&path.to_string_lossy()));
+        let camera_config = slam_core_camera_config_t {
+            width: camera.width,
+            height: camera.height,
+            fps: camera.fps,
+            json_preset: json_preset
+                .as_ref()
+                .map_or(std::ptr::null(), |s| s.as_ptr()),
+            imu_filter: match camera.imu_filter {
+                ImuFilter::None => slam_core_imu_filter_SLAM_CORE_IMU_FILTER_NONE,
+                ImuFilter::Madgwick => slam_core_imu_filter_SLAM_CORE_IMU_FILTER_MADGWICK,
+                ImuFilter::Complementary => slam_core_imu_filter_SLAM_CORE_IMU_FILTER_COMPLEMENTARY,
+            },
+            serial: serial.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+        };
+        let mut parameters = slam.parameters.clone();
+        if map.is_some() {
+            for (key, value) in LOCALIZATION_PARAMETERS {
+                parameters.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        let strings: Vec<_> = parameters
+            .iter()
+            .map(|(key, value)| (cstring(key), cstring(value)))
+            .collect();
+        let database_path = map.map(path_cstring).transpose()?;
+        let parameters: Vec<_> = strings
+            .iter()
+            .map(|(key, value)| slam_core_parameter_t {
+                key: key.as_ptr(),
+                value: value.as_ptr(),
+            })
+            .collect();
+        let mut error = [0; 256];
+        let inner = unsafe {
+            slam_core_create(
+                &camera_config,
+                parameters.as_ptr(),
+                parameters.len(),
+                database_path
+                    .as_ref()
+                    .map_or(std::ptr::null(), |s| s.as_ptr()),
+                error.as_mut_ptr(),
+                error.len(),
+            )
+        };
+        if inner.is_null() {
+            let error = unsafe { CStr::from_ptr(error.as_ptr()) };
+            bail!("Failed to start SLAM core: {}", error.to_string_lossy());
+        }
         let mut color_intrinsics = MaybeUninit::uninit();
         let mut depth_intrinsics = MaybeUninit::uninit();
         unsafe {
@@ -160,12 +316,14 @@ impl<'a> SlamCore<'a> {
                 depth_intrinsics.as_mut_ptr(),
             )
         }
-        Self {
+        Ok(Self {
             inner,
             callback: None,
+            localization_callback: None,
+            graph_callback: None,
             color_intrinsics: convert_intrinsics(unsafe { &color_intrinsics.assume_init() }),
             depth_intrinsics: convert_intrinsics(unsafe { &depth_intrinsics.assume_init() }),
-        }
+        })
     }
 
     pub fn register_odometry_event_handler(&mut self, handler: impl Fn(OdometryEvent) + 'a + Send) {
@@ -179,6 +337,50 @@ impl<'a> SlamCore<'a> {
         };
     }
 
+    pub fn register_localization_event_handler(
+        &mut self,
+        handler: impl Fn(LocalizationEvent) + 'a + Send,
+    ) {
+        self.localization_callback = Some(FfiCallback::new(handler));
+        unsafe {
+            slam_core_register_localization_event_handler(
+                self.inner,
+                self.localization_callback
+                    .as_ref()
+                    .unwrap()
+                    .as_ref()
+                    .as_ptr(),
+                Some(localization_event_handler),
+            )
+        };
+    }
+
+    pub fn register_graph_event_handler(&mut self, handler: impl Fn(GraphEvent) + 'a + Send) {
+        self.graph_callback = Some(FfiCallback::new(handler));
+        unsafe {
+            slam_core_register_graph_event_handler(
+                self.inner,
+                self.graph_callback.as_ref().unwrap().as_ref().as_ptr(),
+                Some(graph_event_handler),
+            )
+        };
+    }
+
+    /// Writes the map to `path`. Mapping continues in a copy, so the file stays
+    /// as saved. Blocks while the database is written.
+    pub fn save_map(&self, path: &Path) -> Result<()> {
+        let path = path_cstring(path)?;
+        let mut error = [0; 256];
+        let res = unsafe {
+            slam_core_save_map(self.inner, path.as_ptr(), error.as_mut_ptr(), error.len())
+        };
+        if res != 0 {
+            let error = unsafe { CStr::from_ptr(error.as_ptr()) };
+            bail!("Failed to save map: {}", error.to_string_lossy());
+        }
+        Ok(())
+    }
+
     pub fn color_intrinsics(&self) -> &CameraIntrinsics {
         &self.color_intrinsics
     }
@@ -194,6 +396,13 @@ impl<'a> Drop for SlamCore<'a> {
     }
 }
 
+fn path_cstring(path: &Path) -> Result<CString> {
+    let Some(path) = path.to_str() else {
+        bail!("Map path {} is not valid UTF-8", path.display());
+    };
+    Ok(CString::new(path)?)
+}
+
 fn convert_intrinsics(intrinsics: &slam_core_camera_intrinsics_t) -> CameraIntrinsics {
     CameraIntrinsics {
         width: intrinsics.width,
@@ -204,3 +413,14 @@ fn convert_intrinsics(intrinsics: &slam_core_camera_intrinsics_t) -> CameraIntri
         cy: intrinsics.cy,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_sizes_are_converted_to_samples() {
+        assert_eq!(sample_count::<u8>(640 * 480 * 3), 640 * 480 * 3);
+        assert_eq!(sample_count::<u16>(640 * 480 * 2), 640 * 480);
+    }
+}
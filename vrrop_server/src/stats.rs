@@ -0,0 +1,330 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use vrrop_common::{
+    stats::{image_stages, Distribution, StageSummary},
+    ImageStages, KeyframePolicy, Stats,
+};
+
+use crate::config::Config;
+use crate::server::ClientInfo;
+
+/// Width of the windows bandwidth is averaged over.
+const BANDWIDTH_WINDOW_SECS: f64 = 1.0;
+
+/// What a recording was made with, saved next to it.
+pub struct Metadata<'a> {
+    pub client: &'a ClientInfo,
+    pub config: &'a Config,
+    /// The policy at the time of saving, clients may have changed it.
+    pub keyframe_policy: &'a KeyframePolicy,
+}
+
+#[derive(Debug, Serialize)]
+struct MetadataFile<'a> {
+    saved_at: f64,
+    client: &'a ClientInfo,
+    image_interval_ms: u64,
+    keyframe_policy: &'a KeyframePolicy,
+    fusion: bool,
+    time_sync: TimeSync,
+}
+
+/// How well the client knew the server clock, which latencies depend on.
+#[derive(Debug, Serialize)]
+struct TimeSync {
+    rtt_ms: Option<Distribution>,
+    /// Spread of the clock offset estimates.
+    offset_ms: Option<Distribution>,
+    uncertainty_ms: Option<Distribution>,
+    drift_ppm: Option<Distribution>,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamSummary {
+    count: usize,
+    bytes: u64,
+    latency_ms: Option<Distribution>,
+}
+
+impl StreamSummary {
+    fn new(sizes: &[usize], latencies: &[i64]) -> Self {
+        Self {
+            count: sizes.len(),
+            bytes: sizes.iter().map(|&size| size as u64).sum(),
+            latency_ms: Distribution::of(latencies.iter().map(|&ns| ns as f64 / 1e6)),
+        }
+    }
+}
+
+/// Megabits per second received in one window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BandwidthSample {
+    /// Start of the window in seconds since the first message.
+    pub time: f64,
+    pub images_mbps: f64,
+    pub odometry_mbps: f64,
+    pub total_mbps: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    /// From the first message to the last.
+    duration_secs: f64,
+    messages_dropped: u64,
+    images: StreamSummary,
+    /// Where the latency of images comes from.
+    image_stages: Vec<StageSummary>,
+    odometry: StreamSummary,
+    /// Of the windows in `bandwidth`.
+    total_mbps: Option<Distribution>,
+    bandwidth_window_secs: f64,
+    bandwidth: Vec<BandwidthSample>,
+}
+
+fn secs(stamp: SystemTime) -> f64 {
+    stamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Seconds of the first and the last message.
+fn time_range(stats: &Stats) -> Option<(f64, f64)> {
+    let stamps = || {
+        stats
+            .images_stamps
+            .iter()
+            .chain(&stats.odometry_stamps)
+            .map(|&stamp| secs(stamp))
+    };
+    Some((
+        stamps().min_by(f64::total_cmp)?,
+        stamps().max_by(f64::total_cmp)?,
+    ))
+}
+
+/// Bandwidth in consecutive windows from the first message to the last.
+pub fn bandwidth(stats: &Stats, window: f64) -> Vec<BandwidthSample> {
+    let images = || stats.images_stamps.iter().zip(&stats.images_original_sizes);
+    let odometry = || {
+        stats
+            .odometry_stamps
+            .iter()
+            .zip(&stats.odometry_original_sizes)
+    };
+    let Some((start, end)) = time_range(stats) else {
+        return Vec::new();
+    };
+    let windows = ((end - start) / window) as usize + 1;
+    let mbps = |messages: &mut dyn Iterator<Item = (&SystemTime, &usize)>| {
+        let mut bytes = vec![0; windows];
+        for (&stamp, &size) in messages {
+            bytes[((secs(stamp) - start) / window) as usize] += size;
+        }
+        bytes
+            .into_iter()
+            .map(|bytes| bytes as f64 * 8.0 / window / 1e6)
+            .collect::<Vec<_>>()
+    };
+    let images = mbps(&mut images());
+    let odometry = mbps(&mut odometry());
+    (0..windows)
+        .map(|i| BandwidthSample {
+            time: i as f64 * window,
+            images_mbps: images[i],
+            odometry_mbps: odometry[i],
+            total_mbps: images[i] + odometry[i],
+        })
+        .collect()
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, time_of_day) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// Times are in seconds. With `stages` each has a column, empty where it
+/// wasn't measured.
+fn write_csv(
+    path: &Path,
+    stamps: &[SystemTime],
+    sizes: &[usize],
+    latencies: &[i64],
+    stages: Option<&[ImageStages]>,
+) -> Result<()> {
+    let mut dest = fs::File::create(path)?;
+    write!(dest, "stamp,size,latency")?;
+    if stages.is_some() {
+        for name in ImageStages::NAMES {
+            write!(dest, ",{name}")?;
+        }
+    }
+    writeln!(dest)?;
+    for (i, ((&stamp, size), &latency)) in stamps.iter().zip(sizes).zip(latencies).enumerate() {
+        write!(dest, "{},{},{}", secs(stamp), size, latency as f64 / 1e9)?;
+        if let Some(stages) = stages {
+            let values = stages.get(i).copied().unwrap_or_default().values();
+            for value in values {
+                match value {
+                    Some(ns) => write!(dest, ",{}", ns as f64 / 1e9)?,
+                    None => write!(dest, ",")?,
+                }
+            }
+        }
+        writeln!(dest)?;
+    }
+    Ok(())
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+/// Creates `dir/name`, or `dir/name_2` and so on if it exists, so a second
+/// recording within the same second doesn't overwrite the first.
+fn create_new_dir(dir: &Path, name: &str) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    for n in 1.. {
+        let path = match n {
+            1 => dir.join(name),
+            n => dir.join(format!("{name}_{n}")),
+        };
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()))
+            }
+        }
+    }
+    unreachable!()
+}
+
+/// Saves a recording to a new directory in `dir`, named after the time and
+/// the client's session, and returns its path.
+pub fn save(stats: &Stats, dir: &Path, metadata: &Metadata) -> Result<PathBuf> {
+    let now = SystemTime::now();
+    let session_dir = create_new_dir(
+        dir,
+        &format!("{}_session{}", utc_timestamp(now), metadata.client.session),
+    )?;
+    write_csv(
+        &session_dir.join("images.csv"),
+        &stats.images_stamps,
+        &stats.images_original_sizes,
+        &stats.images_latencies,
+        Some(&stats.images_stages),
+    )?;
+    write_csv(
+        &session_dir.join("odometry.csv"),
+        &stats.odometry_stamps,
+        &stats.odometry_original_sizes,
+        &stats.odometry_latencies,
+        None,
+    )?;
+    fs::write(
+        session_dir.join("config.toml"),
+        metadata.config.redacted().to_toml()?,
+    )?;
+    let ms = |values: &[i64]| Distribution::of(values.iter().map(|&ns| ns as f64 / 1e6));
+    write_json(
+        &session_dir.join("metadata.json"),
+        &MetadataFile {
+            saved_at: secs(now),
+            client: metadata.client,
+            image_interval_ms: metadata.config.images.interval_ms,
+            keyframe_policy: metadata.keyframe_policy,
+            fusion: metadata.config.fusion.enabled,
+            time_sync: TimeSync {
+                rtt_ms: ms(&stats.time_sync_rtts),
+                offset_ms: ms(&stats.time_sync_offsets),
+                uncertainty_ms: ms(&stats.time_sync_uncertainties),
+                drift_ppm: Distribution::of(stats.time_sync_drifts.iter().copied()),
+            },
+        },
+    )?;
+    let bandwidth = bandwidth(stats, BANDWIDTH_WINDOW_SECS);
+    write_json(
+        &session_dir.join("summary.json"),
+        &Summary {
+            duration_secs: time_range(stats).map_or(0.0, |(start, end)| end - start),
+            messages_dropped: stats.messages_dropped,
+            images: StreamSummary::new(&stats.images_original_sizes, &stats.images_latencies),
+            image_stages: image_stages(&stats.images_stages),
+            odometry: StreamSummary::new(&stats.odometry_original_sizes, &stats.odometry_latencies),
+            total_mbps: Distribution::of(bandwidth.iter().map(|sample| sample.total_mbps)),
+            bandwidth_window_secs: BANDWIDTH_WINDOW_SECS,
+            bandwidth,
+        },
+    )?;
+    Ok(session_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn summaries() {
+        let at = |ms| UNIX_EPOCH + Duration::from_millis(ms);
+        let stats = Stats {
+            images_stamps: vec![at(1000), at(2500)],
+            images_original_sizes: vec![125_000, 250_000],
+            odometry_stamps: vec![at(1200)],
+            odometry_original_sizes: vec![125],
+            ..Default::default()
+        };
+        let samples = bandwidth(&stats, 1.0);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].images_mbps, 1.0);
+        assert_eq!(samples[0].odometry_mbps, 0.001);
+        assert_eq!((samples[1].time, samples[1].total_mbps), (1.0, 2.0));
+        assert_eq!(time_range(&stats), Some((1.0, 2.5)));
+
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(1_709_210_096)),
+            "20240229-123456"
+        );
+    }
+
+    #[test]
+    fn directories_are_not_reused() {
+        let dir = std::env::temp_dir().join(format!("vrrop_stats_test_{}", std::process::id()));
+        let first = create_new_dir(&dir, "session").unwrap();
+        let second = create_new_dir(&dir, "session").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, dir.join("session"));
+        assert_eq!(second, dir.join("session_2"));
+    }
+}
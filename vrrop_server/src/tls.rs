@@ -0,0 +1,36 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio_rustls::{
+    rustls::{crypto::ring, ServerConfig},
+    TlsAcceptor,
+};
+use vrrop_common::cert::Fingerprint;
+
+/// Loads a PEM certificate chain and private key. Returns the acceptor and the
+/// fingerprint clients can pin.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<(TlsAcceptor, Fingerprint)> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path)
+            .with_context(|| format!("Failed to open certificate {}", cert_path.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Invalid certificate {}", cert_path.display()))?;
+    let fingerprint = Fingerprint::of(
+        certs
+            .first()
+            .with_context(|| format!("No certificate in {}", cert_path.display()))?,
+    );
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_path)
+            .with_context(|| format!("Failed to open private key {}", key_path.display()))?,
+    ))
+    .with_context(|| format!("Invalid private key {}", key_path.display()))?
+    .with_context(|| format!("No private key in {}", key_path.display()))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Certificate doesn't match the private key")?;
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}